@@ -14,10 +14,12 @@ fn generate_abi_tests() {
     cfg.skip_field(|s, field| s == "fuse_dirent" && field == "name");
     cfg.skip_roundtrip(|s| s == "fuse_dirent");
 
-    cfg.skip_struct(|s| s == "UnknownOpcode" || s == "InvalidFileLock");
+    // fuse_setxattr_in is bound in its compat layout, since FUSE_SETXATTR_EXT is not supported.
+    cfg.skip_struct(|s| s == "UnknownOpcode" || s == "InvalidFileLock" || s == "fuse_setxattr_in");
 
     // FUSE_FSYNC_FDATASYNC is defined since libfuse 3.7.0.
-    cfg.skip_const(|name| name == "FUSE_FSYNC_FDATASYNC");
+    // FUSE_COMPAT_INIT_IN_SIZE is not defined in the header.
+    cfg.skip_const(|name| name == "FUSE_FSYNC_FDATASYNC" || name == "FUSE_COMPAT_INIT_IN_SIZE");
 
     cfg.generate("../polyfuse-kernel/src/lib.rs", "kernel.rs");
 }
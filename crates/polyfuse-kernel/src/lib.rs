@@ -1,12 +1,13 @@
 //! FUSE application binary interface for `polyfuse`.
 //!
-//! The binding is compatible with ABI 7.31 (in libfuse 3.10.1).
+//! The binding is compatible with ABI 7.42 (in Linux 6.14).
 
 #![allow(nonstandard_style, clippy::identity_op)]
 
 use std::convert::TryFrom;
 use std::error;
 use std::fmt;
+use std::mem;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 
@@ -14,7 +15,10 @@ use zerocopy::FromBytes;
 pub const FUSE_KERNEL_VERSION: u32 = 7;
 
 /// The minor version number of FUSE protocol.
-pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 42;
+
+/// The inode number of the root directory.
+pub const FUSE_ROOT_ID: u64 = 1;
 
 /// The minimum length of read buffer.
 pub const FUSE_MIN_READ_BUFFER: u32 = 8192;
@@ -34,6 +38,7 @@ pub const FATTR_ATIME_NOW: u32 = 1 << 7;
 pub const FATTR_MTIME_NOW: u32 = 1 << 8;
 pub const FATTR_LOCKOWNER: u32 = 1 << 9;
 pub const FATTR_CTIME: u32 = 1 << 10;
+pub const FATTR_KILL_SUIDGID: u32 = 1 << 11;
 
 // Flags returned by the OPEN request.
 pub const FOPEN_DIRECT_IO: u32 = 1 << 0;
@@ -41,6 +46,9 @@ pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
 pub const FOPEN_NONSEEKABLE: u32 = 1 << 2;
 pub const FOPEN_CACHE_DIR: u32 = 1 << 3;
 pub const FOPEN_STREAM: u32 = 1 << 4;
+pub const FOPEN_NOFLUSH: u32 = 1 << 5;
+pub const FOPEN_PARALLEL_DIRECT_WRITES: u32 = 1 << 6;
+pub const FOPEN_PASSTHROUGH: u32 = 1 << 7;
 
 // INIT request/reply flags.
 pub const FUSE_ASYNC_READ: u32 = 1;
@@ -69,6 +77,26 @@ pub const FUSE_MAX_PAGES: u32 = 1 << 22;
 pub const FUSE_CACHE_SYMLINKS: u32 = 1 << 23;
 pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24;
 pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;
+pub const FUSE_MAP_ALIGNMENT: u32 = 1 << 26;
+pub const FUSE_SUBMOUNTS: u32 = 1 << 27;
+pub const FUSE_HANDLE_KILLPRIV_V2: u32 = 1 << 28;
+pub const FUSE_SETXATTR_EXT: u32 = 1 << 29;
+pub const FUSE_INIT_EXT: u32 = 1 << 30;
+pub const FUSE_INIT_RESERVED: u32 = 1 << 31;
+
+// INIT request/reply flags stored in `flags2`.
+//
+// These flags are only available if both sides set FUSE_INIT_EXT.
+pub const FUSE_SECURITY_CTX: u64 = 1 << 32;
+pub const FUSE_HAS_INODE_DAX: u64 = 1 << 33;
+pub const FUSE_CREATE_SUPP_GROUP: u64 = 1 << 34;
+pub const FUSE_HAS_EXPIRE_ONLY: u64 = 1 << 35;
+pub const FUSE_DIRECT_IO_ALLOW_MMAP: u64 = 1 << 36;
+pub const FUSE_PASSTHROUGH: u64 = 1 << 37;
+pub const FUSE_NO_EXPORT_SUPPORT: u64 = 1 << 38;
+pub const FUSE_HAS_RESEND: u64 = 1 << 39;
+pub const FUSE_ALLOW_IDMAP: u64 = 1 << 40;
+pub const FUSE_OVER_IO_URING: u64 = 1 << 41;
 
 // CUSE INIT request/reply flags.
 pub const CUSE_UNRESTRICTED_IOCTL: u32 = 1 << 0;
@@ -86,7 +114,14 @@ pub const FUSE_LK_FLOCK: u32 = 1 << 0;
 // WRITE flags.
 pub const FUSE_WRITE_CACHE: u32 = 1 << 0;
 pub const FUSE_WRITE_LOCKOWNER: u32 = 1 << 1;
-pub const FUSE_WRITE_KILL_PRIV: u32 = 1 << 2;
+pub const FUSE_WRITE_KILL_SUIDGID: u32 = 1 << 2;
+pub const FUSE_WRITE_KILL_PRIV: u32 = FUSE_WRITE_KILL_SUIDGID; // obsolete alias
+
+// Open flags.
+pub const FUSE_OPEN_KILL_SUIDGID: u32 = 1 << 0;
+
+// Setxattr flags.
+pub const FUSE_SETXATTR_ACL_KILL_SGID: u32 = 1 << 0;
 
 // Read flags.
 pub const FUSE_READ_LOCKOWNER: u32 = 1 << 1;
@@ -105,6 +140,17 @@ pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0;
 // Fsync flags.
 pub const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
 
+// Attribute flags.
+pub const FUSE_ATTR_SUBMOUNT: u32 = 1 << 0;
+pub const FUSE_ATTR_DAX: u32 = 1 << 1;
+
+// Notify inval entry flags.
+pub const FUSE_EXPIRE_ONLY: u32 = 1 << 0;
+
+// Setupmapping flags.
+pub const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0;
+pub const FUSE_SETUPMAPPING_FLAG_READ: u64 = 1 << 1;
+
 // misc
 pub const FUSE_COMPAT_ENTRY_OUT_SIZE: usize = 120;
 pub const FUSE_COMPAT_ATTR_OUT_SIZE: usize = 96;
@@ -113,6 +159,9 @@ pub const FUSE_COMPAT_WRITE_IN_SIZE: usize = 24;
 pub const FUSE_COMPAT_STATFS_SIZE: usize = 48;
 pub const FUSE_COMPAT_INIT_OUT_SIZE: usize = 8;
 pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+pub const FUSE_COMPAT_SETXATTR_IN_SIZE: usize = 8;
+/// The size of `fuse_init_in` sent by the kernel before ABI 7.36.
+pub const FUSE_COMPAT_INIT_IN_SIZE: usize = 16;
 pub const CUSE_INIT_INFO_MAX: u32 = 4096;
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -133,7 +182,7 @@ pub struct fuse_attr {
     pub gid: u32,
     pub rdev: u32,
     pub blksize: u32,
-    pub padding: u32,
+    pub flags: u32,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -259,6 +308,11 @@ define_opcode! {
     FUSE_RENAME2 = 45,
     FUSE_LSEEK = 46,
     FUSE_COPY_FILE_RANGE = 47,
+    FUSE_SETUPMAPPING = 48,
+    FUSE_REMOVEMAPPING = 49,
+    FUSE_SYNCFS = 50,
+    FUSE_TMPFILE = 51,
+    FUSE_STATX = 52,
 
     CUSE_INIT = 4096,
 }
@@ -295,6 +349,8 @@ pub struct fuse_init_in {
     pub minor: u32,
     pub max_readahead: u32,
     pub flags: u32,
+    pub flags2: u32,
+    pub unused: [u32; 11],
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -364,7 +420,7 @@ pub struct fuse_link_in {
 #[repr(C)]
 pub struct fuse_open_in {
     pub flags: u32,
-    pub unused: u32,
+    pub open_flags: u32,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -429,6 +485,9 @@ pub struct fuse_getxattr_in {
 pub struct fuse_setxattr_in {
     pub size: u32,
     pub flags: u32,
+    // `setxattr_flags` and `padding` are only sent when FUSE_SETXATTR_EXT is negotiated,
+    // which polyfuse does not. The binding keeps the compat layout
+    // (FUSE_COMPAT_SETXATTR_IN_SIZE).
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -454,7 +513,7 @@ pub struct fuse_create_in {
     pub flags: u32,
     pub mode: u32,
     pub umask: u32,
-    pub padding: u32,
+    pub open_flags: u32,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -506,8 +565,10 @@ pub struct fuse_init_out {
     pub max_write: u32,
     pub time_gran: u32,
     pub max_pages: u16,
-    pub padding: u16,
-    pub unused: [u32; 8],
+    pub map_alignment: u16,
+    pub flags2: u32,
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 
 impl Default for fuse_init_out {
@@ -522,8 +583,10 @@ impl Default for fuse_init_out {
             max_write: 0,
             time_gran: 0,
             max_pages: 0,
-            padding: 0,
-            unused: [0; 8],
+            map_alignment: 0,
+            flags2: 0,
+            max_stack_depth: 0,
+            unused: [0; 6],
         }
     }
 }
@@ -540,7 +603,7 @@ pub struct fuse_getxattr_out {
 pub struct fuse_open_out {
     pub fh: u64,
     pub open_flags: u32,
-    pub padding: u32,
+    pub backing_id: i32,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -676,6 +739,89 @@ pub struct fuse_copy_file_range_in {
     pub flags: u64,
 }
 
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_setupmapping_in {
+    pub fh: u64,
+    pub foffset: u64,
+    pub len: u64,
+    pub flags: u64,
+    pub moffset: u64,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_removemapping_in {
+    pub count: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_removemapping_one {
+    pub moffset: u64,
+    pub len: u64,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_syncfs_in {
+    pub padding: u64,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_sx_time {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub __spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: fuse_sx_time,
+    pub btime: fuse_sx_time,
+    pub ctime: fuse_sx_time,
+    pub mtime: fuse_sx_time,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub __spare2: [u64; 14],
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx_in {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx_out {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: fuse_statx,
+}
+
 macro_rules! define_notify_code {
     ($(
         $(#[$m:meta])*
@@ -704,6 +850,7 @@ define_notify_code! {
     FUSE_NOTIFY_STORE = 4,
     FUSE_NOTIFY_RETRIEVE = 5,
     FUSE_NOTIFY_DELETE = 6,
+    FUSE_NOTIFY_RESEND = 7,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -725,7 +872,7 @@ pub struct fuse_notify_inval_inode_out {
 pub struct fuse_notify_inval_entry_out {
     pub parent: u64,
     pub namelen: u32,
-    pub padding: u32,
+    pub flags: u32,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -789,3 +936,88 @@ pub struct cuse_init_out {
     pub dev_minor: u32,
     pub spare: [u32; 10],
 }
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_backing_map {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
+
+// fuse-over-io_uring.
+
+/// The size of the area in `fuse_uring_req_header` storing `fuse_in_header`/`fuse_out_header`.
+pub const FUSE_URING_IN_OUT_HEADER_SZ: usize = 128;
+
+/// The size of the area in `fuse_uring_req_header` storing the per-operation header.
+pub const FUSE_URING_OP_IN_OUT_SZ: usize = 128;
+
+/// The number of `iovec`s passed with `FUSE_IO_URING_CMD_REGISTER`.
+pub const FUSE_URING_IOV_SEGS: usize = 2;
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_uring_ent_in_out {
+    pub flags: u64,
+    pub commit_id: u64,
+    pub payload_sz: u32,
+    pub padding: u32,
+    pub reserved: u64,
+}
+
+#[derive(Clone, Copy, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_uring_req_header {
+    pub in_out: [u8; FUSE_URING_IN_OUT_HEADER_SZ],
+    pub op_in: [u8; FUSE_URING_OP_IN_OUT_SZ],
+    pub ring_ent_in_out: fuse_uring_ent_in_out,
+}
+
+impl Default for fuse_uring_req_header {
+    fn default() -> Self {
+        Self {
+            in_out: [0; FUSE_URING_IN_OUT_HEADER_SZ],
+            op_in: [0; FUSE_URING_OP_IN_OUT_SZ],
+            ring_ent_in_out: fuse_uring_ent_in_out::default(),
+        }
+    }
+}
+
+// Commands of `IORING_OP_URING_CMD` for the FUSE device.
+pub const FUSE_IO_URING_CMD_INVALID: u32 = 0;
+pub const FUSE_IO_URING_CMD_REGISTER: u32 = 1;
+pub const FUSE_IO_URING_CMD_COMMIT_AND_FETCH: u32 = 2;
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_uring_cmd_req {
+    pub flags: u64,
+    pub commit_id: u64,
+    pub qid: u16,
+    pub padding: [u8; 6],
+}
+
+// Device ioctls.
+//
+// The request codes are encoded in the same way as `_IOR`/`_IOW` in asm-generic/ioctl.h.
+pub const FUSE_DEV_IOC_MAGIC: u32 = 229;
+pub const FUSE_DEV_IOC_CLONE: u32 = _IOR(FUSE_DEV_IOC_MAGIC, 0, mem::size_of::<u32>());
+pub const FUSE_DEV_IOC_BACKING_OPEN: u32 =
+    _IOW(FUSE_DEV_IOC_MAGIC, 1, mem::size_of::<fuse_backing_map>());
+pub const FUSE_DEV_IOC_BACKING_CLOSE: u32 = _IOW(FUSE_DEV_IOC_MAGIC, 2, mem::size_of::<u32>());
+
+const _IOC_WRITE: u32 = 1;
+const _IOC_READ: u32 = 2;
+
+const fn _IOC(dir: u32, typ: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | (typ << 8) | nr
+}
+
+const fn _IOR(typ: u32, nr: u32, size: usize) -> u32 {
+    _IOC(_IOC_READ, typ, nr, size)
+}
+
+const fn _IOW(typ: u32, nr: u32, size: usize) -> u32 {
+    _IOC(_IOC_WRITE, typ, nr, size)
+}
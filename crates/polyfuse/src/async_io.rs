@@ -0,0 +1,42 @@
+//! The reactor shared by `async-std` and `smol`, provided by `async-io`.
+
+use ::async_io::Async;
+use std::task::{ready, Context, Poll};
+
+async_session!(Registration);
+
+pub(crate) struct Registration(Async<rt::Fd>);
+
+impl rt::Registration for Registration {
+    fn register(fd: RawFd) -> io::Result<Self> {
+        Async::new(rt::Fd(fd)).map(Self)
+    }
+
+    fn poll_read_with<R, F>(&self, cx: &mut Context<'_>, f: &mut F) -> Poll<io::Result<R>>
+    where
+        F: FnMut() -> io::Result<R>,
+    {
+        loop {
+            match f() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.0.poll_readable(cx))?;
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    fn poll_write_with<R, F>(&self, cx: &mut Context<'_>, f: &mut F) -> Poll<io::Result<R>>
+    where
+        F: FnMut() -> io::Result<R>,
+    {
+        loop {
+            match f() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.0.poll_writable(cx))?;
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
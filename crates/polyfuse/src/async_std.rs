@@ -0,0 +1,3 @@
+//! Integration with [`async-std`](https://docs.rs/async-std).
+
+pub use crate::async_io::{AsyncNotifier, AsyncRequest, AsyncSession};
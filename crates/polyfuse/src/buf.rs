@@ -0,0 +1,413 @@
+//! Buffers for receiving the request messages.
+//!
+//! The argument part of a request message is placed so that the FUSE argument types
+//! are properly aligned, and the payload of `write` requests starts at a page boundary
+//! so that it can be passed to the files opened with `O_DIRECT` without a bounce buffer.
+
+use bytes::Bytes;
+use polyfuse_kernel::{fuse_write_in, FUSE_WRITE};
+use std::{
+    alloc::{self, Layout},
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+};
+
+/// The default maximum number of idle buffers kept in a pool.
+pub(crate) const DEFAULT_MAX_IDLE_BUFFERS: usize = 8;
+
+/// The arguments that occupy up to `1 / COPY_ARG_RATIO` of the receive buffer are copied
+/// into a right-sized buffer, so that they do not pin a buffer sized for the whole
+/// `max_write` payload.
+const COPY_ARG_RATIO: usize = 2;
+
+/// Return the offset of the argument part in the buffers for receiving the messages.
+///
+/// The offset is chosen such that the payload following `fuse_write_in` starts at a page
+/// boundary. Since `fuse_write_in` is a multiple of 8 bytes, the argument types at the
+/// head of the argument part are also aligned.
+#[inline]
+fn arg_offset() -> usize {
+    pagesize() - mem::size_of::<fuse_write_in>()
+}
+
+// ==== AlignedBuf ====
+
+/// A page-aligned heap buffer.
+pub(crate) struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The buffer is exclusively owned in the same way as `Box<[u8]>`.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::dealloc(self.ptr.as_ptr(), self.layout);
+        }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl AlignedBuf {
+    /// Allocate a zero-filled buffer of `size` bytes.
+    pub(crate) fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, pagesize())
+            .expect("invalid buffer size")
+            .pad_to_align();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    /// Allocate a buffer for receiving a message whose argument part is at most `cap` bytes.
+    pub(crate) fn with_arg_capacity(cap: usize) -> Self {
+        Self::new(arg_offset() + cap)
+    }
+
+    /// Return the maximum length of the argument part.
+    #[inline]
+    pub(crate) fn arg_capacity(&self) -> usize {
+        self.len() - arg_offset()
+    }
+
+    /// Return the region where the argument part is received.
+    #[inline]
+    pub(crate) fn arg_mut(&mut self) -> &mut [u8] {
+        let offset = arg_offset();
+        &mut self[offset..]
+    }
+
+    #[inline]
+    fn arg(&self, len: usize) -> &[u8] {
+        &self[arg_offset()..][..len]
+    }
+}
+
+/// Copy the argument of a request into a newly allocated buffer with the proper alignment.
+pub(crate) fn copy_arg(opcode: u32, chunks: &[&[u8]]) -> Bytes {
+    let len = chunks.iter().map(|chunk| chunk.len()).sum::<usize>();
+
+    if opcode == FUSE_WRITE && len > mem::size_of::<fuse_write_in>() {
+        let mut buf = AlignedBuf::with_arg_capacity(len);
+        let mut offset = 0;
+        for chunk in chunks {
+            buf.arg_mut()[offset..offset + chunk.len()].copy_from_slice(chunk);
+            offset += chunk.len();
+        }
+        return Bytes::from_owner(Arg {
+            buf: Box::new(buf),
+            len,
+        });
+    }
+
+    let mut words = vec![0u64; (len + mem::size_of::<u64>() - 1) / mem::size_of::<u64>()];
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(
+            words.as_mut_ptr().cast::<u8>(),
+            words.len() * mem::size_of::<u64>(),
+        )
+    };
+    let mut offset = 0;
+    for chunk in chunks {
+        bytes[offset..offset + chunk.len()].copy_from_slice(chunk);
+        offset += chunk.len();
+    }
+    Bytes::from_owner(SmallArg { words, len })
+}
+
+/// Take the received argument out of a buffer.
+///
+/// The arguments that are small relative to the capacity of the buffer are copied into
+/// a new buffer, and the large ones refer to the buffer directly.
+fn take_arg<B, F>(buf: B, len: usize, opcode: u32, wrap: F) -> Bytes
+where
+    B: Deref<Target = AlignedBuf>,
+    F: FnOnce(B) -> Bytes,
+{
+    if len <= buf.arg_capacity() / COPY_ARG_RATIO {
+        copy_arg(opcode, &[buf.arg(len)])
+    } else {
+        wrap(buf)
+    }
+}
+
+struct Arg<B> {
+    buf: B,
+    len: usize,
+}
+
+impl<B> AsRef<[u8]> for Arg<B>
+where
+    B: Deref<Target = AlignedBuf>,
+{
+    fn as_ref(&self) -> &[u8] {
+        self.buf.arg(self.len)
+    }
+}
+
+struct SmallArg {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl AsRef<[u8]> for SmallArg {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr().cast::<u8>(), self.len) }
+    }
+}
+
+// ==== BufferPool ====
+
+/// A pool of the buffers for receiving request messages.
+///
+/// The argument of a large request (e.g. the payload of `write`) refers to the pooled
+/// buffer directly, and the buffer is returned into the pool when it is dropped.
+pub(crate) struct BufferPool {
+    capacity: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<AlignedBuf>>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(capacity: usize, max_idle: usize) -> Self {
+        Self {
+            capacity,
+            max_idle,
+            idle: Mutex::new(Vec::with_capacity(max_idle)),
+        }
+    }
+
+    /// Return the capacity of the argument part in the buffers of this pool.
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Take an idle buffer from the pool, or allocate a new one.
+    pub(crate) fn get(&self) -> AlignedBuf {
+        match self.idle.lock().unwrap().pop() {
+            Some(buf) => buf,
+            None => AlignedBuf::with_arg_capacity(self.capacity),
+        }
+    }
+
+    /// Return a buffer into the pool.
+    ///
+    /// The buffer is released if the pool already has enough idle buffers.
+    pub(crate) fn put(&self, buf: AlignedBuf) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+
+    /// Take the received argument of `len` bytes out of a buffer obtained from this pool.
+    pub(crate) fn take_arg(self: &Arc<Self>, buf: AlignedBuf, len: usize, opcode: u32) -> Bytes {
+        let pooled = PooledBuf {
+            buf: Some(buf),
+            pool: self.clone(),
+        };
+        take_arg(pooled, len, opcode, |buf| {
+            Bytes::from_owner(Arg { buf, len })
+        })
+    }
+}
+
+struct PooledBuf {
+    buf: Option<AlignedBuf>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = AlignedBuf;
+
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_ref().expect("the buffer has been released")
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+// ==== RequestBuffer ====
+
+/// A buffer for receiving the request messages, owned by the caller of
+/// `Session::next_request_into`.
+///
+/// The argument of a large request shares the allocation with this buffer until
+/// the request is dropped, and the allocation is reused by the subsequent calls
+/// after that. Otherwise, a new allocation is made for the next request.
+#[derive(Default)]
+pub struct RequestBuffer {
+    buf: Option<Arc<AlignedBuf>>,
+}
+
+impl fmt::Debug for RequestBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestBuffer")
+            .field(
+                "capacity",
+                &self.buf.as_ref().map_or(0, |buf| buf.arg_capacity()),
+            )
+            .finish()
+    }
+}
+
+impl RequestBuffer {
+    /// Create an empty buffer.
+    ///
+    /// The memory is allocated when the buffer is used at first.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the exclusive reference to the allocation capable of `cap` bytes argument.
+    pub(crate) fn get_mut(&mut self, cap: usize) -> &mut AlignedBuf {
+        let reusable = match self.buf {
+            Some(ref mut buf) => Arc::get_mut(buf).map_or(false, |buf| buf.arg_capacity() >= cap),
+            None => false,
+        };
+        if !reusable {
+            self.buf = Some(Arc::new(AlignedBuf::with_arg_capacity(cap)));
+        }
+        Arc::get_mut(self.buf.as_mut().unwrap()).unwrap()
+    }
+
+    /// Take the received argument of `len` bytes out of this buffer.
+    pub(crate) fn take_arg(&self, len: usize, opcode: u32) -> Bytes {
+        let buf = self.buf.clone().expect("the buffer is not allocated");
+        take_arg(buf, len, opcode, |buf| Bytes::from_owner(Arg { buf, len }))
+    }
+}
+
+#[inline]
+pub(crate) fn pagesize() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse_kernel::FUSE_LOOKUP;
+
+    fn receive(buf: &mut AlignedBuf, len: usize) {
+        for (i, b) in buf.arg_mut()[..len].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+    }
+
+    fn is_aligned(arg: &Bytes, align: usize) -> bool {
+        arg.as_ptr() as usize % align == 0
+    }
+
+    #[test]
+    fn aligned_arg() {
+        let mut buf = AlignedBuf::with_arg_capacity(0x10000);
+        assert!(buf.arg_capacity() >= 0x10000);
+        assert_eq!(buf.as_ptr() as usize % pagesize(), 0);
+
+        // The payload of write requests starts at a page boundary.
+        let data = &buf.arg_mut()[mem::size_of::<fuse_write_in>()..];
+        assert_eq!(data.as_ptr() as usize % pagesize(), 0);
+
+        let arg = copy_arg(FUSE_LOOKUP, &[b"foo", b"bar\0"]);
+        assert_eq!(arg, b"foobar\0"[..]);
+        assert!(is_aligned(&arg, mem::align_of::<u64>()));
+
+        let arg = copy_arg(FUSE_WRITE, &[&[0u8; 40][..], b"data"]);
+        assert_eq!(arg.len(), 44);
+        assert!(is_aligned(&arg.slice(40..), pagesize()));
+
+        let arg = copy_arg(FUSE_WRITE, &[&[0u8; 40][..]]);
+        assert!(is_aligned(&arg, mem::align_of::<u64>()));
+    }
+
+    #[test]
+    fn pool_recycle() {
+        let pool = Arc::new(BufferPool::new(0x10000, 2));
+
+        // small arguments are copied out and the buffer is returned immediately.
+        let mut buf = pool.get();
+        receive(&mut buf, 16);
+        let arg = pool.take_arg(buf, 16, FUSE_LOOKUP);
+        assert_eq!(arg, (0..16).collect::<Vec<u8>>());
+        assert!(is_aligned(&arg, mem::align_of::<u64>()));
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        // the payload of small write requests is copied into a page-aligned buffer.
+        let arg = pool.take_arg(pool.get(), 100, FUSE_WRITE);
+        assert!(is_aligned(&arg.slice(40..), pagesize()));
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        // the arguments up to half of the capacity are also copied out.
+        let arg = pool.take_arg(pool.get(), 0x8000, FUSE_WRITE);
+        assert_eq!(arg.len(), 0x8000);
+        assert!(is_aligned(&arg.slice(40..), pagesize()));
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        // large arguments keep the buffer until they are dropped.
+        let arg1 = pool.take_arg(pool.get(), 0x9000, FUSE_LOOKUP);
+        let arg2 = pool.take_arg(pool.get(), 0x9000, FUSE_LOOKUP);
+        let arg3 = pool.take_arg(pool.get(), 0x9000, FUSE_WRITE);
+        assert!(pool.idle.lock().unwrap().is_empty());
+        assert_eq!(arg1.len(), 0x9000);
+        assert!(is_aligned(&arg3.slice(40..), pagesize()));
+
+        drop(arg1);
+        drop(arg2.clone());
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+        drop(arg2);
+        drop(arg3);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn request_buffer_reuse() {
+        let mut buf = RequestBuffer::new();
+
+        receive(buf.get_mut(0x10000), 16);
+        let ptr = buf.buf.as_ref().unwrap().as_ptr();
+        let small = buf.take_arg(16, FUSE_LOOKUP);
+        assert_eq!(small.len(), 16);
+
+        // the allocation is reused since the small argument has been copied.
+        receive(buf.get_mut(0x10000), 0x9000);
+        assert_eq!(buf.buf.as_ref().unwrap().as_ptr(), ptr);
+        let large = buf.take_arg(0x9000, FUSE_LOOKUP);
+
+        // a new allocation is made while the large argument is alive.
+        buf.get_mut(0x10000);
+        assert_ne!(buf.buf.as_ref().unwrap().as_ptr(), ptr);
+        drop(large);
+    }
+}
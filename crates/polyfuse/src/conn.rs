@@ -1,7 +1,10 @@
 use libc::{c_int, c_void, iovec};
+use polyfuse_kernel::{
+    fuse_backing_map, FUSE_DEV_IOC_BACKING_CLOSE, FUSE_DEV_IOC_BACKING_OPEN, FUSE_DEV_IOC_CLONE,
+};
 use std::{
     cmp,
-    ffi::{OsStr, OsString},
+    ffi::{CString, OsStr, OsString},
     io,
     mem::{self, MaybeUninit},
     os::unix::{net::UnixStream, prelude::*},
@@ -11,6 +14,7 @@ use std::{
 };
 
 const FUSERMOUNT_PROG: &str = "/usr/bin/fusermount";
+const FUSE_DEVICE_PATH: &str = "/dev/fuse";
 const FUSE_COMMFD_ENV: &str = "_FUSE_COMMFD";
 
 macro_rules! syscall {
@@ -29,7 +33,7 @@ macro_rules! syscall {
 pub struct Connection {
     fd: RawFd,
     child: Option<Fusermount>,
-    mountpoint: PathBuf,
+    mountpoint: Option<PathBuf>,
     mountopts: MountOptions,
 }
 
@@ -42,15 +46,70 @@ impl Drop for Connection {
 impl Connection {
     /// Establish a connection with the FUSE kernel driver.
     pub(crate) fn open(mountpoint: PathBuf, mountopts: MountOptions) -> io::Result<Self> {
-        let (fd, child) = mount(&mountpoint, &mountopts)?;
+        let mut mount = PendingMount::spawn(mountpoint, mountopts)?;
+        mount.receive_fd()?;
+        mount.finish()
+    }
+
+    /// Open a device file of the FUSE kernel driver directly, without mounting.
+    ///
+    /// This is used for the devices that are not associated with any mountpoint,
+    /// such as `/dev/cuse`.
+    pub(crate) fn open_device(path: &Path) -> io::Result<Self> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = syscall! { open(c_path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
         Ok(Self {
             fd,
-            child,
-            mountpoint,
-            mountopts,
+            child: None,
+            mountpoint: None,
+            mountopts: MountOptions::default(),
         })
     }
 
+    /// Create a connection with a socket that plays the role of the kernel, for testing.
+    #[cfg(test)]
+    pub(crate) fn socketpair() -> io::Result<(Self, UnixStream)> {
+        let mut fds = [0; 2];
+        syscall! {
+            socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        let conn = Self {
+            fd: fds[0],
+            child: None,
+            mountpoint: None,
+            mountopts: MountOptions::default(),
+        };
+        Ok((conn, unsafe { UnixStream::from_raw_fd(fds[1]) }))
+    }
+
+    /// Create a new connection that is attached to the same FUSE session as this one.
+    ///
+    /// The requests are dequeued from the kernel independently of the other connections,
+    /// and the replies must be sent to the connection from which the request was received.
+    pub(crate) fn clone_device(&self) -> io::Result<Self> {
+        let conn = Self::open_device(Path::new(FUSE_DEVICE_PATH))?;
+        let master_fd = self.fd as u32;
+        syscall! {
+            ioctl(
+                conn.fd, //
+                FUSE_DEV_IOC_CLONE as _,
+                &master_fd as *const u32,
+            )
+        };
+        Ok(conn)
+    }
+
+    #[cfg(any(test, feature = "tokio", feature = "async-std", feature = "smol"))]
+    /// Switch the device file to the non-blocking mode.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        set_nonblocking(self.fd)
+    }
+
     fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
         let len = syscall! {
             read(
@@ -95,6 +154,36 @@ impl Connection {
         Ok(res as usize)
     }
 
+    /// Register a file descriptor as a backing file for passthrough I/O.
+    pub(crate) fn backing_open(&self, fd: RawFd) -> io::Result<i32> {
+        let map = fuse_backing_map {
+            fd,
+            flags: 0,
+            padding: 0,
+        };
+        let backing_id = syscall! {
+            ioctl(
+                self.fd, //
+                FUSE_DEV_IOC_BACKING_OPEN as _,
+                &map as *const fuse_backing_map,
+            )
+        };
+        Ok(backing_id)
+    }
+
+    /// Unregister a backing file associated with the specified ID.
+    pub(crate) fn backing_close(&self, backing_id: i32) -> io::Result<()> {
+        let backing_id = backing_id as u32;
+        syscall! {
+            ioctl(
+                self.fd, //
+                FUSE_DEV_IOC_BACKING_CLOSE as _,
+                &backing_id as *const u32,
+            )
+        };
+        Ok(())
+    }
+
     fn unmount(&mut self) {
         unsafe {
             libc::close(self.fd);
@@ -104,7 +193,9 @@ impl Connection {
             let _ = child.wait();
         }
 
-        unmount(&self.mountpoint);
+        if let Some(mountpoint) = self.mountpoint.take() {
+            unmount(&mountpoint);
+        }
     }
 }
 
@@ -172,6 +263,135 @@ impl io::Write for &Connection {
     }
 }
 
+// ==== pipe ====
+
+/// A pipe used as the intermediate buffer of `splice(2)`.
+#[derive(Debug)]
+pub(crate) struct Pipe {
+    reader: RawFd,
+    writer: RawFd,
+    capacity: usize,
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.reader);
+            libc::close(self.writer);
+        }
+    }
+}
+
+impl Pipe {
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        syscall! { pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        let capacity = unsafe { libc::fcntl(fds[1], libc::F_GETPIPE_SZ) };
+        Ok(Self {
+            reader: fds[0],
+            writer: fds[1],
+            capacity: cmp::max(capacity, 0) as usize,
+        })
+    }
+
+    /// Grow the capacity of this pipe so that it can hold at least `size` bytes.
+    pub(crate) fn reserve(&mut self, size: usize) -> io::Result<()> {
+        if self.capacity < size {
+            let size = cmp::min(size, c_int::MAX as usize) as c_int;
+            let capacity = syscall! { fcntl(self.writer, libc::F_SETPIPE_SZ, size) };
+            self.capacity = capacity as usize;
+        }
+        Ok(())
+    }
+
+    /// Move at most `len` bytes from `fd` into this pipe.
+    pub(crate) fn splice_from(
+        &self,
+        fd: RawFd,
+        offset: Option<&mut i64>,
+        len: usize,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let offset = offset.map_or_else(ptr::null_mut, |off| off as *mut i64);
+        let len = syscall! {
+            splice(
+                fd, //
+                offset,
+                self.writer,
+                ptr::null_mut(),
+                len,
+                flags,
+            )
+        };
+        Ok(len as usize)
+    }
+
+    /// Move at most `len` bytes in this pipe into `fd`.
+    pub(crate) fn splice_to(
+        &self,
+        fd: RawFd,
+        offset: Option<&mut i64>,
+        len: usize,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let offset = offset.map_or_else(ptr::null_mut, |off| off as *mut i64);
+        let len = syscall! {
+            splice(
+                self.reader, //
+                ptr::null_mut(),
+                fd,
+                offset,
+                len,
+                flags,
+            )
+        };
+        Ok(len as usize)
+    }
+
+    /// Move exactly `len` bytes from the another pipe into this pipe.
+    pub(crate) fn splice_all_from(&self, other: &Pipe, len: usize, flags: u32) -> io::Result<()> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = other.splice_to(self.writer, None, remaining, flags)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            remaining -= n;
+        }
+        Ok(())
+    }
+}
+
+impl io::Read for &Pipe {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let len = syscall! {
+            read(
+                self.reader, //
+                dst.as_mut_ptr() as *mut c_void,
+                dst.len(),
+            )
+        };
+        Ok(len as usize)
+    }
+}
+
+impl io::Write for &Pipe {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let len = syscall! {
+            write(
+                self.writer, //
+                src.as_ptr() as *const c_void,
+                src.len(),
+            )
+        };
+        Ok(len as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 // ==== mount ====
 
 #[derive(Debug)]
@@ -208,88 +428,179 @@ impl Fusermount {
     }
 }
 
-fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Option<Fusermount>)> {
-    let (input, output) = UnixStream::pair()?;
-
-    let mut fusermount = Command::new(
-        mountopts
-            .fusermount_path
-            .as_deref()
-            .unwrap_or_else(|| Path::new(FUSERMOUNT_PROG)),
-    );
-
-    let opts = mountopts
-        .options
-        .iter()
-        .map(|opt| opt.as_str())
-        .chain(if mountopts.auto_unmount {
-            Some("auto_unmount")
-        } else {
-            None
-        })
-        .fold(String::new(), |mut opts, opt| {
-            if !opts.is_empty() {
-                opts.push(',');
+/// A mount in progress, waiting for `fusermount` to send the file descriptor of the device.
+///
+/// The methods that communicate with `fusermount` return `WouldBlock` instead of
+/// blocking the thread once the socket is switched to the non-blocking mode.
+#[derive(Debug)]
+pub(crate) struct PendingMount {
+    mountpoint: PathBuf,
+    mountopts: MountOptions,
+    child: Option<Fusermount>,
+    fd: Option<RawFd>,
+}
+
+impl Drop for PendingMount {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            unsafe {
+                libc::close(fd);
+            }
+            if let Some(child) = self.child.take() {
+                let _ = child.wait();
             }
-            opts.push_str(opt);
-            opts
-        });
-    if !opts.is_empty() {
-        fusermount.arg("-o").arg(opts);
+            unmount(&self.mountpoint);
+        }
     }
+}
 
-    fusermount.arg("--").arg(mountpoint);
+impl AsRawFd for PendingMount {
+    /// Return the socket on which the file descriptor is sent.
+    fn as_raw_fd(&self) -> RawFd {
+        self.child
+            .as_ref()
+            .map_or(-1, |child| child.input.as_raw_fd())
+    }
+}
 
-    fusermount.env(
-        mountopts
-            .fuse_comm_fd
-            .as_deref()
-            .unwrap_or_else(|| OsStr::new(FUSE_COMMFD_ENV)),
-        output.as_raw_fd().to_string(),
-    );
+impl PendingMount {
+    /// Spawn a `fusermount` process to mount the filesystem on the specified path.
+    pub(crate) fn spawn(mountpoint: PathBuf, mountopts: MountOptions) -> io::Result<Self> {
+        let (input, output) = UnixStream::pair()?;
+
+        let mut fusermount = Command::new(
+            mountopts
+                .fusermount_path
+                .as_deref()
+                .unwrap_or_else(|| Path::new(FUSERMOUNT_PROG)),
+        );
+
+        let opts = mountopts
+            .options
+            .iter()
+            .map(|opt| opt.as_str())
+            .chain(if mountopts.auto_unmount {
+                Some("auto_unmount")
+            } else {
+                None
+            })
+            .fold(String::new(), |mut opts, opt| {
+                if !opts.is_empty() {
+                    opts.push(',');
+                }
+                opts.push_str(opt);
+                opts
+            });
+        if !opts.is_empty() {
+            fusermount.arg("-o").arg(opts);
+        }
 
-    tracing::debug!("spawn {:?}", fusermount);
+        fusermount.arg("--").arg(&mountpoint);
 
-    match unsafe { fork()? } {
-        ForkResult::Child => {
-            // Only async-signal-safe functions are allowed to call here.
-            // in a multi threaded situation.
+        fusermount.env(
+            mountopts
+                .fuse_comm_fd
+                .as_deref()
+                .unwrap_or_else(|| OsStr::new(FUSE_COMMFD_ENV)),
+            output.as_raw_fd().to_string(),
+        );
 
-            let output = output.into_raw_fd();
-            unsafe { libc::fcntl(output, libc::F_SETFD, 0) };
+        tracing::debug!("spawn {:?}", fusermount);
 
-            // Assumes that the UnixStream destructor only calls close(2).
-            drop(input);
+        match unsafe { fork()? } {
+            ForkResult::Child => {
+                // Only async-signal-safe functions are allowed to call here.
+                // in a multi threaded situation.
 
-            let _err = fusermount.exec();
+                let output = output.into_raw_fd();
+                unsafe { libc::fcntl(output, libc::F_SETFD, 0) };
 
-            // Exit immediately since the process may be in a "broken state".
-            // https://doc.rust-lang.org/stable/std/os/unix/process/trait.CommandExt.html#notes
-            unsafe {
-                libc::_exit(1);
+                // Assumes that the UnixStream destructor only calls close(2).
+                drop(input);
+
+                let _err = fusermount.exec();
+
+                // Exit immediately since the process may be in a "broken state".
+                // https://doc.rust-lang.org/stable/std/os/unix/process/trait.CommandExt.html#notes
+                unsafe {
+                    libc::_exit(1);
+                }
+            }
+
+            ForkResult::Parent { child_pid, .. } => {
+                drop(output);
+                Ok(Self {
+                    mountpoint,
+                    mountopts,
+                    child: Some(Fusermount {
+                        pid: child_pid,
+                        input,
+                    }),
+                    fd: None,
+                })
             }
         }
+    }
 
-        ForkResult::Parent { child_pid, .. } => {
-            drop(output);
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+    /// Switch the socket connected to `fusermount` to the non-blocking mode.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        set_nonblocking(self.as_raw_fd())
+    }
 
-            let fd = receive_fd(&input)?;
+    /// Receive the file descriptor of the device from `fusermount`.
+    pub(crate) fn receive_fd(&mut self) -> io::Result<()> {
+        if self.fd.is_none() {
+            let child = self.child.as_ref().expect("fusermount has already exited");
+            self.fd = Some(receive_fd(&child.input)?);
+        }
+        Ok(())
+    }
 
-            let mut child = Some(Fusermount {
-                pid: child_pid,
-                input,
-            });
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+    /// Return whether `finish` waits for `fusermount` to exit.
+    ///
+    /// When `auto_unmount` is not specified, `fusermount` exits immediately
+    /// after sending the file descriptor, and `check_exited` tells when it has
+    /// closed the socket.
+    pub(crate) fn waits_exit(&self) -> bool {
+        !self.mountopts.auto_unmount
+    }
 
-            if !mountopts.auto_unmount {
-                // When auto_unmount is not specified, `fusermount` exits immediately
-                // after sending the file descriptor and thus we need to wait until
-                // the command is exited.
-                let child = child.take().unwrap();
-                let _st = child.wait()?;
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+    /// Check whether `fusermount` has closed its end of the socket.
+    pub(crate) fn check_exited(&self) -> io::Result<()> {
+        let child = self.child.as_ref().expect("fusermount has already exited");
+        let mut buf = [0u8; 1];
+        loop {
+            let len = syscall! {
+                recv(child.input.as_raw_fd(), buf.as_mut_ptr().cast(), 1, 0)
+            };
+            if len == 0 {
+                return Ok(());
             }
+        }
+    }
 
-            Ok((fd, child))
+    /// Complete the mount and establish the connection with the received file descriptor.
+    pub(crate) fn finish(mut self) -> io::Result<Connection> {
+        let fd = self.fd.take().expect("the file descriptor is not received");
+        let mut conn = Connection {
+            fd,
+            child: self.child.take(),
+            mountpoint: Some(mem::take(&mut self.mountpoint)),
+            mountopts: mem::take(&mut self.mountopts),
+        };
+
+        if !conn.mountopts.auto_unmount {
+            // When auto_unmount is not specified, `fusermount` exits immediately
+            // after sending the file descriptor and thus we need to wait until
+            // the command is exited.
+            let child = conn.child.take().unwrap();
+            let _st = child.wait()?;
         }
+
+        Ok(conn)
     }
 }
 
@@ -349,6 +660,13 @@ fn receive_fd(reader: &UnixStream) -> io::Result<RawFd> {
 
 // ==== util ====
 
+#[cfg(any(test, feature = "tokio", feature = "async-std", feature = "smol"))]
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = syscall! { fcntl(fd, libc::F_GETFL) };
+    syscall! { fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    Ok(())
+}
+
 enum ForkResult {
     Parent { child_pid: c_int },
     Child,
@@ -362,3 +680,24 @@ unsafe fn fork() -> io::Result<ForkResult> {
         pid => Ok(ForkResult::Parent { child_pid: pid }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+
+    #[test]
+    fn pipe_splice() {
+        let mut src = Pipe::new().unwrap();
+        src.reserve(1024 * 1024).unwrap();
+        assert!(src.capacity >= 1024 * 1024);
+
+        let dst = Pipe::new().unwrap();
+        (&src).write_all(b"hello, splice").unwrap();
+        dst.splice_all_from(&src, 13, 0).unwrap();
+
+        let mut buf = vec![0u8; 13];
+        (&dst).read_exact(&mut buf[..]).unwrap();
+        assert_eq!(buf, b"hello, splice");
+    }
+}
@@ -0,0 +1,417 @@
+use crate::{
+    buf::{AlignedBuf, DEFAULT_MAX_IDLE_BUFFERS},
+    conn::Connection,
+    decoder::Decoder,
+    session::{
+        self, write_bytes, Notifier, Reply, Request, SessionInner, MINIMUM_SUPPORTED_MINOR_VERSION,
+    },
+};
+use polyfuse_kernel::*;
+use std::{
+    cmp, fmt, io, mem,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use zerocopy::AsBytes as _;
+
+const CUSE_DEVICE_PATH: &str = "/dev/cuse";
+
+const DEFAULT_MAX_READ: u32 = 0x20000;
+const DEFAULT_MAX_WRITE: u32 = 0x20000;
+const MIN_MAX_WRITE: u32 = 0x1000;
+
+// The size of request buffer, except for the data of write requests.
+const BUFFER_HEADER_SIZE: usize = 0x1000;
+
+// ==== CuseConfig ====
+
+/// Parameters for setting up a CUSE (character device in userspace) session.
+pub struct CuseConfig {
+    device_path: Option<PathBuf>,
+    init_out: cuse_init_out,
+}
+
+impl Default for CuseConfig {
+    fn default() -> Self {
+        Self {
+            device_path: None,
+            init_out: default_init_out(),
+        }
+    }
+}
+
+impl CuseConfig {
+    /// Specify the path to the CUSE device file.
+    ///
+    /// The default value is `/dev/cuse`.
+    pub fn device_path(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.device_path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set the major number of the created device.
+    ///
+    /// If the value is zero, the number is dynamically allocated by the kernel.
+    pub fn dev_major(&mut self, major: u32) -> &mut Self {
+        self.init_out.dev_major = major;
+        self
+    }
+
+    /// Set the minor number of the created device.
+    pub fn dev_minor(&mut self, minor: u32) -> &mut Self {
+        self.init_out.dev_minor = minor;
+        self
+    }
+
+    /// Specify that the device handles `ioctl` requests in the unrestricted mode.
+    ///
+    /// See the documentation of `op::Ioctl` for details.
+    pub fn unrestricted_ioctl(&mut self, enabled: bool) -> &mut Self {
+        if enabled {
+            self.init_out.flags |= CUSE_UNRESTRICTED_IOCTL;
+        } else {
+            self.init_out.flags &= !CUSE_UNRESTRICTED_IOCTL;
+        }
+        self
+    }
+
+    /// Set the maximum size of the data of read requests.
+    pub fn max_read(&mut self, value: u32) -> &mut Self {
+        self.init_out.max_read = value;
+        self
+    }
+
+    /// Set the maximum size of the data of write requests.
+    ///
+    /// # Panic
+    /// It causes an assertion panic if the setting value is less than the absolute minimum.
+    pub fn max_write(&mut self, value: u32) -> &mut Self {
+        assert!(
+            value >= MIN_MAX_WRITE,
+            "max_write must be greater or equal to {}",
+            MIN_MAX_WRITE,
+        );
+        self.init_out.max_write = value;
+        self
+    }
+}
+
+// ==== CuseSession ====
+
+/// The object containing the contextual information about a CUSE session.
+///
+/// Unlike `Session`, the session does not mount any filesystem and instead
+/// creates a character device named `/dev/<devname>`.  The requests to the
+/// device (such as `open`, `read`, `write`, `ioctl` and `poll`) are received
+/// as the ordinary `Request`s.
+pub struct CuseSession {
+    inner: Arc<SessionInner>,
+    init_out: cuse_init_out,
+}
+
+impl fmt::Debug for CuseSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CuseSession").finish()
+    }
+}
+
+impl Drop for CuseSession {
+    fn drop(&mut self) {
+        self.inner.exit();
+    }
+}
+
+impl AsRawFd for CuseSession {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.conn().as_raw_fd()
+    }
+}
+
+impl CuseSession {
+    /// Create a character device with the specified name.
+    pub fn open(devname: &str, config: CuseConfig) -> io::Result<Self> {
+        let CuseConfig {
+            device_path,
+            mut init_out,
+        } = config;
+
+        let dev_info = dev_info(devname)?;
+
+        let conn = Connection::open_device(
+            device_path
+                .as_deref()
+                .unwrap_or_else(|| Path::new(CUSE_DEVICE_PATH)),
+        )?;
+
+        init_cuse_session(&mut init_out, &dev_info, &conn, &conn)?;
+        let bufsize = BUFFER_HEADER_SIZE + init_out.max_write as usize;
+
+        Ok(Self {
+            inner: Arc::new(SessionInner::new(conn, bufsize, DEFAULT_MAX_IDLE_BUFFERS)),
+            init_out,
+        })
+    }
+
+    /// Return the major number of the created device.
+    pub fn dev_major(&self) -> u32 {
+        self.init_out.dev_major
+    }
+
+    /// Return the minor number of the created device.
+    pub fn dev_minor(&self) -> u32 {
+        self.init_out.dev_minor
+    }
+
+    /// Receive an incoming request from the kernel.
+    ///
+    /// This method returns `None` when the device is released by the kernel.
+    pub fn next_request(&self) -> io::Result<Option<Request>> {
+        session::next_request(&self.inner, self.inner.conn(), None)
+    }
+
+    /// Create an instance of `Notifier` corresponding to this session.
+    ///
+    /// Only `Notifier::poll_wakeup` is meaningful for CUSE devices.
+    pub fn notifier(&self) -> Notifier {
+        Notifier::new(self.inner.clone())
+    }
+}
+
+fn dev_info(devname: &str) -> io::Result<Vec<u8>> {
+    if devname.is_empty() || devname.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid device name",
+        ));
+    }
+
+    let mut dev_info = Vec::with_capacity("DEVNAME=".len() + devname.len() + 1);
+    dev_info.extend_from_slice(b"DEVNAME=");
+    dev_info.extend_from_slice(devname.as_bytes());
+    dev_info.push(b'\0');
+
+    if dev_info.len() > CUSE_INIT_INFO_MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "device name is too long",
+        ));
+    }
+
+    Ok(dev_info)
+}
+
+fn init_cuse_session<R, W>(
+    init_out: &mut cuse_init_out,
+    dev_info: &[u8],
+    mut reader: R,
+    mut writer: W,
+) -> io::Result<()>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    let mut header = fuse_in_header::default();
+    let mut buf = AlignedBuf::with_arg_capacity(BUFFER_HEADER_SIZE + init_out.max_write as usize);
+    let arg = buf.arg_mut();
+
+    let len = reader.read_vectored(&mut [
+        io::IoSliceMut::new(header.as_bytes_mut()),
+        io::IoSliceMut::new(&mut arg[..]),
+    ])?;
+    if len < mem::size_of::<fuse_in_header>() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request message is too short",
+        ));
+    }
+
+    if header.opcode != CUSE_INIT {
+        tracing::warn!(
+            "unexpected operation before CUSE_INIT (opcode={:?})",
+            header.opcode
+        );
+        write_bytes(&mut writer, Reply::new(header.unique, libc::EIO, ()))?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the first request is not CUSE_INIT",
+        ));
+    }
+
+    let mut decoder = Decoder::new(&arg[..len - mem::size_of::<fuse_in_header>()]);
+    let init_in = decoder
+        .fetch::<cuse_init_in>() //
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decode cuse_init_in"))?;
+
+    tracing::debug!("CUSE_INIT request:");
+    tracing::debug!("  proto = {}.{}:", init_in.major, init_in.minor);
+    tracing::debug!("  flags = 0x{:08x}", init_in.flags);
+
+    if init_in.major != FUSE_KERNEL_VERSION || init_in.minor < MINIMUM_SUPPORTED_MINOR_VERSION {
+        tracing::warn!(
+            "polyfuse supports only ABI 7.{} or later. {}.{} is not supported",
+            MINIMUM_SUPPORTED_MINOR_VERSION,
+            init_in.major,
+            init_in.minor
+        );
+        write_bytes(&mut writer, Reply::new(header.unique, libc::EPROTO, ()))?;
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "unsupported protocol version",
+        ));
+    }
+
+    init_out.minor = cmp::min(init_out.minor, init_in.minor);
+    init_out.flags &= init_in.flags;
+
+    tracing::debug!("Reply to CUSE_INIT:");
+    tracing::debug!("  proto = {}.{}:", init_out.major, init_out.minor);
+    tracing::debug!("  flags = 0x{:08x}", init_out.flags);
+    tracing::debug!("  max_read = 0x{:08X}", init_out.max_read);
+    tracing::debug!("  max_write = 0x{:08X}", init_out.max_write);
+    tracing::debug!("  dev = {}:{}", init_out.dev_major, init_out.dev_minor);
+    write_bytes(
+        writer,
+        Reply::new(header.unique, 0, (init_out.as_bytes(), dev_info)),
+    )?;
+
+    Ok(())
+}
+
+#[inline]
+const fn default_init_out() -> cuse_init_out {
+    cuse_init_out {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        unused: 0,
+        flags: 0,
+        max_read: DEFAULT_MAX_READ,
+        max_write: DEFAULT_MAX_WRITE,
+        dev_major: 0,
+        dev_minor: 0,
+        spare: [0; 10],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::prelude::*, os::unix::net::UnixStream, thread};
+
+    fn seqpacket_pair() -> (UnixStream, UnixStream) {
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0, "socketpair failed");
+        unsafe {
+            (
+                UnixStream::from_raw_fd(fds[0]),
+                UnixStream::from_raw_fd(fds[1]),
+            )
+        }
+    }
+
+    #[test]
+    fn init_cuse() {
+        let (device, mut kernel) = seqpacket_pair();
+
+        let kernel = thread::spawn(move || {
+            let in_header = fuse_in_header {
+                len: (mem::size_of::<fuse_in_header>() + mem::size_of::<cuse_init_in>()) as u32,
+                opcode: CUSE_INIT,
+                unique: 2,
+                ..Default::default()
+            };
+            let init_in = cuse_init_in {
+                major: 7,
+                minor: 31,
+                unused: 0,
+                flags: CUSE_UNRESTRICTED_IOCTL,
+            };
+            let mut input = Vec::new();
+            input.extend_from_slice(in_header.as_bytes());
+            input.extend_from_slice(init_in.as_bytes());
+            kernel.write_all(&input).unwrap();
+
+            let mut output = vec![0u8; 8192];
+            let len = kernel.read(&mut output).unwrap();
+            output.truncate(len);
+            output
+        });
+
+        let mut init_out = default_init_out();
+        init_out.dev_major = 240;
+        init_out.dev_minor = 1;
+        init_out.flags |= CUSE_UNRESTRICTED_IOCTL;
+        let dev_info = dev_info("polyfuse").unwrap();
+        init_cuse_session(&mut init_out, &dev_info, &device, &device)
+            .expect("initialization failed");
+
+        let output = kernel.join().unwrap();
+
+        let header_len = mem::size_of::<fuse_out_header>();
+        let init_out_len = mem::size_of::<cuse_init_out>();
+        assert_eq!(output.len(), header_len + init_out_len + dev_info.len());
+
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&output[..header_len]);
+        assert_eq!(out_header.len as usize, output.len());
+        assert_eq!(out_header.error, 0);
+        assert_eq!(out_header.unique, 2);
+
+        let mut reply = cuse_init_out::default();
+        reply
+            .as_bytes_mut()
+            .copy_from_slice(&output[header_len..header_len + init_out_len]);
+        assert_eq!(reply.major, 7);
+        assert_eq!(reply.minor, 31);
+        assert_eq!(reply.flags, CUSE_UNRESTRICTED_IOCTL);
+        assert_eq!(reply.max_read, DEFAULT_MAX_READ);
+        assert_eq!(reply.max_write, DEFAULT_MAX_WRITE);
+        assert_eq!(reply.dev_major, 240);
+        assert_eq!(reply.dev_minor, 1);
+
+        assert_eq!(&output[header_len + init_out_len..], b"DEVNAME=polyfuse\0");
+    }
+
+    #[test]
+    fn init_cuse_unsupported_version() {
+        let (device, mut kernel) = seqpacket_pair();
+
+        let in_header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<cuse_init_in>()) as u32,
+            opcode: CUSE_INIT,
+            unique: 2,
+            ..Default::default()
+        };
+        let init_in = cuse_init_in {
+            major: 7,
+            minor: 10,
+            unused: 0,
+            flags: 0,
+        };
+        let mut input = Vec::new();
+        input.extend_from_slice(in_header.as_bytes());
+        input.extend_from_slice(init_in.as_bytes());
+        kernel.write_all(&input).unwrap();
+
+        let mut init_out = default_init_out();
+        let dev_info = dev_info("polyfuse").unwrap();
+        assert!(init_cuse_session(&mut init_out, &dev_info, &device, &device).is_err());
+
+        let mut output = vec![0u8; 8192];
+        let len = kernel.read(&mut output).unwrap();
+        assert_eq!(len, mem::size_of::<fuse_out_header>());
+        let mut out_header = fuse_out_header::default();
+        out_header.as_bytes_mut().copy_from_slice(&output[..len]);
+        assert_eq!(out_header.error, -libc::EPROTO);
+    }
+}
@@ -5,16 +5,23 @@ use zerocopy::{FromBytes, LayoutVerified};
 pub(crate) enum DecodeError {
     UnexpectedEof,
     MissingNulCharacter,
+    BadLength,
     Unaligned,
 }
 
 pub(crate) struct Decoder<'a> {
     bytes: &'a [u8],
+    offset: usize,
 }
 
 impl<'a> Decoder<'a> {
     pub(crate) fn new(bytes: &'a [u8]) -> Self {
-        Self { bytes }
+        Self { bytes, offset: 0 }
+    }
+
+    /// Return the number of bytes fetched so far.
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
     }
 
     pub(crate) fn fetch_bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
@@ -24,12 +31,24 @@ impl<'a> Decoder<'a> {
 
         let (bytes, remaining) = self.bytes.split_at(count);
         self.bytes = remaining;
+        self.offset += count;
 
         debug_assert!(bytes.len() >= count);
 
         Ok(bytes)
     }
 
+    /// Fetch the bytes whose length is specified by a field in the message.
+    ///
+    /// Unlike `fetch_bytes`, the lack of bytes is reported as `BadLength`
+    /// since the length field is inconsistent with the message.
+    pub(crate) fn fetch_bytes_by_len(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::BadLength);
+        }
+        self.fetch_bytes(len)
+    }
+
     /// Fetch a value of Plain-Old-Data (POD) type by reference.
     pub(crate) fn fetch<T>(&mut self) -> Result<&'a T, DecodeError>
     where
@@ -46,7 +65,10 @@ impl<'a> Decoder<'a> {
     where
         T: FromBytes,
     {
-        let bytes = self.fetch_bytes(mem::size_of::<T>() * count)?;
+        let len = mem::size_of::<T>()
+            .checked_mul(count)
+            .ok_or(DecodeError::BadLength)?;
+        let bytes = self.fetch_bytes_by_len(len)?;
         let verified = LayoutVerified::<_, [T]>::new_slice(bytes) //
             .ok_or(DecodeError::Unaligned)?;
         Ok(verified.into_slice())
@@ -96,6 +118,7 @@ mod tests {
         assert!(decoder.fetch_bytes(1).is_ok());
         assert!(decoder.fetch_bytes(0).is_ok());
         assert!(decoder.fetch_bytes(1).is_err());
+        assert_eq!(decoder.offset(), 9);
 
         assert!(Decoder::new(INPUT).fetch::<[u8; 10]>().is_err());
     }
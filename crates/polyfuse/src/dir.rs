@@ -0,0 +1,344 @@
+//! Directory streams for the `opendir`, `readdir` and `releasedir` requests.
+
+use crate::{
+    inode::InodeTable,
+    op::{self, ReaddirMode},
+    reply::{EntryOut, OpenOut, ReaddirOut, ReaddirPlusOut},
+    session::Request,
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto as _,
+    ffi::{OsStr, OsString},
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+/// An entry in a directory stream.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    name: OsString,
+    ino: u64,
+    typ: u32,
+}
+
+impl DirEntry {
+    /// Create a directory entry.
+    ///
+    /// `typ` is the file type of the entry in the form of `DT_*`.
+    pub fn new<T>(name: T, ino: u64, typ: u32) -> Self
+    where
+        T: Into<OsString>,
+    {
+        Self {
+            name: name.into(),
+            ino,
+            typ,
+        }
+    }
+
+    /// Return the name of this entry.
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Return the inode number of this entry.
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Return the file type of this entry.
+    pub fn typ(&self) -> u32 {
+        self.typ
+    }
+}
+
+/// A snapshot of the entries in a directory, taken when the directory is opened.
+///
+/// The offset of each entry is the position of the next entry in the snapshot.
+/// Since the snapshot is not affected by the later modifications of the directory,
+/// the offsets remain valid for the lifetime of the stream, and the entries are
+/// neither duplicated nor skipped while the directory is read in multiple requests.
+#[derive(Debug)]
+pub struct DirStream {
+    entries: Vec<DirEntry>,
+}
+
+impl DirStream {
+    /// Create a stream with the entries of a directory.
+    ///
+    /// The entries of `.` and `..` are inserted at the beginning of the stream,
+    /// so they should not be included in `entries`.
+    pub fn new<I>(ino: u64, parent: u64, entries: I) -> Self
+    where
+        I: IntoIterator<Item = DirEntry>,
+    {
+        let dot = DirEntry::new(".", ino, libc::DT_DIR as u32);
+        let dotdot = DirEntry::new("..", parent, libc::DT_DIR as u32);
+        let mut stream = vec![dot, dotdot];
+        stream.extend(entries);
+        Self { entries: stream }
+    }
+
+    /// Return the number of the entries, including `.` and `..`.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return whether the stream has no entries.
+    ///
+    /// This always returns `false`, since the stream contains `.` and `..`.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the entries following the specified offset, along with their offsets.
+    ///
+    /// The offset is the one passed by the `readdir` request, which is zero at the
+    /// beginning of the stream and the offset of the last entry received by the kernel
+    /// otherwise.
+    pub fn entries(&self, offset: u64) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
+        let start = offset.try_into().unwrap_or(usize::MAX);
+        self.entries
+            .iter()
+            .enumerate()
+            .skip(start)
+            .map(|(i, entry)| (i as u64 + 1, entry))
+    }
+
+    /// Append the entries following the specified offset to the reply until it is full.
+    pub fn fill(&self, offset: u64, out: &mut ReaddirOut) {
+        for (off, entry) in self.entries(offset) {
+            if out.entry(&entry.name, entry.ino, entry.typ, off) {
+                break;
+            }
+        }
+    }
+}
+
+/// A table of the directory streams, keyed by the file handles.
+#[derive(Default)]
+pub struct DirHandles {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    streams: HashMap<u64, Arc<DirStream>>,
+    next_fh: u64,
+}
+
+impl fmt::Debug for DirHandles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirHandles")
+            .field("len", &self.inner.lock().unwrap().streams.len())
+            .finish()
+    }
+}
+
+impl DirHandles {
+    /// Create an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a directory stream and return its file handle.
+    pub fn insert(&self, stream: DirStream) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let fh = inner.next_fh;
+        inner.next_fh += 1;
+        inner.streams.insert(fh, Arc::new(stream));
+        fh
+    }
+
+    /// Return the directory stream associated with the file handle.
+    pub fn get(&self, fh: u64) -> Option<Arc<DirStream>> {
+        self.inner.lock().unwrap().streams.get(&fh).cloned()
+    }
+
+    /// Remove the directory stream associated with the file handle.
+    pub fn remove(&self, fh: u64) -> Option<Arc<DirStream>> {
+        self.inner.lock().unwrap().streams.remove(&fh)
+    }
+
+    /// Reply to an `opendir` request with a new directory stream.
+    ///
+    /// The stream is removed if the reply fails.
+    pub fn reply_opendir(&self, req: &Request, stream: DirStream) -> io::Result<()> {
+        let fh = self.insert(stream);
+        let mut out = OpenOut::default();
+        out.fh(fh);
+        let res = req.reply(out);
+        if res.is_err() {
+            self.remove(fh);
+        }
+        res
+    }
+
+    /// Reply to a `readdir` request with the entries in the stream.
+    ///
+    /// The request is replied with `EBADF` if the file handle is unknown. The replies
+    /// to `readdirplus` requests need the attributes of the entries, so they are replied
+    /// with `ENOSYS`. Use `reply_readdirplus` instead if `readdirplus` is enabled.
+    pub fn reply_readdir(&self, req: &Request, op: &op::Readdir<'_>) -> io::Result<()> {
+        if op.mode() == ReaddirMode::Plus {
+            return req.reply_error(libc::ENOSYS);
+        }
+        let stream = match self.get(op.fh()) {
+            Some(stream) => stream,
+            None => return req.reply_error(libc::EBADF),
+        };
+        let mut out = ReaddirOut::new(op.size() as usize);
+        stream.fill(op.offset(), &mut out);
+        req.reply(out)
+    }
+
+    /// Reply to a `readdir` request with the entries in the stream, including the
+    /// ones in the `readdirplus` mode.
+    ///
+    /// In the `readdirplus` mode, `f` returns the entry with the attributes of each
+    /// directory entry, or `None` to omit it (e.g. if the inode has been removed after
+    /// the directory was opened). The lookup counts of the replied entries are incremented
+    /// through `inodes`, as with `InodeTable::reply_readdirplus`. The requests in the
+    /// normal mode are replied in the same way as `reply_readdir`, without calling `f`.
+    pub fn reply_readdirplus<T, F>(
+        &self,
+        req: &Request,
+        op: &op::Readdir<'_>,
+        inodes: &InodeTable<T>,
+        mut f: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&DirEntry) -> Option<EntryOut>,
+    {
+        if op.mode() == ReaddirMode::Normal {
+            return self.reply_readdir(req, op);
+        }
+        let stream = match self.get(op.fh()) {
+            Some(stream) => stream,
+            None => return req.reply_error(libc::EBADF),
+        };
+        let mut out = ReaddirPlusOut::new(op.size() as usize);
+        for (off, entry) in stream.entries(op.offset()) {
+            let entry_out = match f(entry) {
+                Some(entry_out) => entry_out,
+                None => continue,
+            };
+            if out.entry(&entry.name, entry.typ, off, entry_out) {
+                break;
+            }
+        }
+        inodes.reply_readdirplus(req, out)
+    }
+
+    /// Reply to a `releasedir` request and remove the stream.
+    pub fn reply_releasedir(&self, req: &Request, op: &op::Releasedir<'_>) -> io::Result<()> {
+        self.remove(op.fh());
+        req.reply(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::testing, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use std::mem;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn resume_from_offset() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let dirs = DirHandles::new();
+
+        let children =
+            (0..3).map(|i| DirEntry::new(format!("file{}", i), 10 + i, libc::DT_REG as u32));
+        let fh = dirs.insert(DirStream::new(FUSE_ROOT_ID, FUSE_ROOT_ID, children));
+
+        // The buffer has the room for three entries.
+        let size = (mem::size_of::<fuse_dirent>() + 8) * 3;
+        let mut names = vec![];
+        let mut offset = 0;
+        for unique in [2, 4, 6] {
+            let arg = fuse_read_in {
+                fh,
+                offset,
+                size: size as u32,
+                ..Default::default()
+            };
+            kernel.send(FUSE_READDIR, unique, FUSE_ROOT_ID, &[arg.as_bytes()]);
+
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation().unwrap() {
+                Operation::Readdir(op) => dirs.reply_readdir(&req, &op).unwrap(),
+                _ => unreachable!(),
+            }
+
+            let (_, out) = kernel.recv().unwrap();
+            let mut buf = &out[..];
+            while !buf.is_empty() {
+                let mut dirent = fuse_dirent::default();
+                let len = mem::size_of::<fuse_dirent>();
+                dirent.as_bytes_mut().copy_from_slice(&buf[..len]);
+                let name = &buf[len..len + dirent.namelen as usize];
+                names.push(String::from_utf8(name.to_vec()).unwrap());
+                offset = dirent.off;
+                buf = &buf[(len + dirent.namelen as usize + 7) & !7..];
+            }
+        }
+        assert_eq!(names, vec![".", "..", "file0", "file1", "file2"]);
+        assert_eq!(offset, 5);
+
+        assert!(dirs.remove(fh).is_some());
+        assert!(dirs.get(fh).is_none());
+    }
+
+    #[test]
+    fn readdirplus_entries() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let dirs = DirHandles::new();
+        let inodes = InodeTable::new(());
+        let ino = inodes.insert(());
+
+        // The entry of `stale` refers to an inode that does not exist.
+        let children = vec![
+            DirEntry::new("file", ino, libc::DT_REG as u32),
+            DirEntry::new("stale", ino + 1, libc::DT_REG as u32),
+        ];
+        let fh = dirs.insert(DirStream::new(FUSE_ROOT_ID, FUSE_ROOT_ID, children));
+
+        let arg = fuse_read_in {
+            fh,
+            size: 4096,
+            ..Default::default()
+        };
+        kernel.send(FUSE_READDIRPLUS, 2, FUSE_ROOT_ID, &[arg.as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Readdir(op) => dirs
+                .reply_readdirplus(&req, &op, &inodes, |entry| {
+                    inodes.get(entry.ino())?;
+                    let mut out = EntryOut::default();
+                    out.ino(entry.ino());
+                    Some(out)
+                })
+                .unwrap(),
+            _ => unreachable!(),
+        }
+
+        let (header, out) = kernel.recv().unwrap();
+        assert_eq!(header.error, 0);
+        let mut buf = &out[..];
+        let mut names = vec![];
+        while !buf.is_empty() {
+            let mut entry = fuse_direntplus::default();
+            let len = mem::size_of::<fuse_direntplus>();
+            entry.as_bytes_mut().copy_from_slice(&buf[..len]);
+            let namelen = entry.dirent.namelen as usize;
+            names.push(String::from_utf8(buf[len..len + namelen].to_vec()).unwrap());
+            buf = &buf[(len + namelen + 7) & !7..];
+        }
+        assert_eq!(names, vec![".", "..", "file"]);
+        assert_eq!(inodes.nlookup(ino), Some(1));
+    }
+}
@@ -0,0 +1,167 @@
+//! Error numbers replied to the kernel.
+
+use std::{error, fmt, io};
+
+/// An error number replied to the kernel, such as `ENOENT`.
+///
+/// The value is always in the range accepted by the kernel, that is, positive and
+/// less than 512. The handlers returning `Result<T, Errno>` can reply to the request
+/// with `Request::reply_result`, and `io::Error` is converted with `?`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Errno(i32);
+
+macro_rules! errno_consts {
+    ($($name:ident,)*) => {
+        impl Errno {
+            $(
+                pub const $name: Self = Self(libc::$name);
+            )*
+        }
+    };
+}
+
+errno_consts! {
+    EPERM,
+    ENOENT,
+    EINTR,
+    EIO,
+    ENXIO,
+    E2BIG,
+    EBADF,
+    EAGAIN,
+    ENOMEM,
+    EACCES,
+    EBUSY,
+    EEXIST,
+    EXDEV,
+    ENODEV,
+    ENOTDIR,
+    EISDIR,
+    EINVAL,
+    ENFILE,
+    EMFILE,
+    ENOTTY,
+    EFBIG,
+    ENOSPC,
+    ESPIPE,
+    EROFS,
+    EMLINK,
+    ERANGE,
+    EDEADLK,
+    ENAMETOOLONG,
+    ENOLCK,
+    ENOSYS,
+    ENOTEMPTY,
+    ELOOP,
+    ENODATA,
+    EOVERFLOW,
+    EOPNOTSUPP,
+    ENOTSUP,
+    ETIMEDOUT,
+    ESTALE,
+    EDQUOT,
+}
+
+impl Errno {
+    /// The upper bound of the error numbers accepted by the kernel (`ERESTARTSYS`).
+    const LIMIT: i32 = 512;
+
+    /// Create an error number from the raw value.
+    ///
+    /// Return `None` if the value is not a positive number less than 512.
+    pub const fn new(code: i32) -> Option<Self> {
+        if code > 0 && code < Self::LIMIT {
+            Some(Self(code))
+        } else {
+            None
+        }
+    }
+
+    /// Return the raw value of this error number.
+    pub const fn raw(self) -> i32 {
+        self.0
+    }
+
+    /// Convert an I/O error into the error number.
+    ///
+    /// `fallback` is used if the error does not carry a valid OS error code,
+    /// e.g. the errors created by `io::Error::new`.
+    pub fn from_io_error(err: &io::Error, fallback: Self) -> Self {
+        err.raw_os_error().and_then(Self::new).unwrap_or(fallback)
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Errno").field(&self.0).finish()
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&io::Error::from_raw_os_error(self.0), f)
+    }
+}
+
+impl error::Error for Errno {}
+
+/// The conversion with `EIO` as the fallback.
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Self {
+        Self::from_io_error(&err, Self::EIO)
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(errno: Errno) -> Self {
+        io::Error::from_raw_os_error(errno.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn convert_io_error() {
+        assert_eq!(Errno::new(0), None);
+        assert_eq!(Errno::new(-libc::ENOENT), None);
+        assert_eq!(Errno::new(512), None);
+
+        let err = io::Error::from_raw_os_error(libc::ENOENT);
+        assert_eq!(Errno::from(err), Errno::ENOENT);
+        let err = io::Error::new(io::ErrorKind::InvalidData, "no OS code");
+        assert_eq!(Errno::from_io_error(&err, Errno::ENOSYS), Errno::ENOSYS);
+        assert_eq!(Errno::from(err), Errno::EIO);
+
+        let err = io::Error::from(Errno::EACCES);
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    }
+
+    #[test]
+    fn reply_result() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        for unique in [2, 4] {
+            kernel.send(
+                FUSE_GETATTR,
+                unique,
+                1,
+                &[fuse_getattr_in::default().as_bytes()],
+            );
+        }
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_result::<()>(Err(Errno::ENOENT)).unwrap();
+        // The invalid error numbers are replaced with EIO.
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(-libc::ENOENT).unwrap();
+
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, -libc::ENOENT));
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (4, -libc::EIO));
+    }
+}
@@ -0,0 +1,1059 @@
+//! High-level interface for filesystems that handle each operation in its own method.
+
+use crate::{
+    op,
+    session::{Request, Session},
+    Operation,
+};
+use bytes::Bytes;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// A filesystem that handles the requests dispatched by operation.
+///
+/// Each method corresponds to a variant of `Operation`. The default implementations
+/// reply `ENOSYS` to the kernel, which leads the kernel to use the fallback behavior
+/// or to return the error to the caller, except for the operations described below.
+///
+/// * `destroy` replies with an empty payload.
+/// * `forget`, `interrupt` and `notify_reply` do nothing, since the kernel does not
+///   expect any replies to these requests.
+#[allow(unused_variables)]
+pub trait Filesystem {
+    /// Lookup a directory entry by name.
+    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Get file attributes.
+    fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Set file attributes.
+    fn setattr(&self, req: &Request, op: op::Setattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Read a symbolic link.
+    fn readlink(&self, req: &Request, op: op::Readlink<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Create a symbolic link.
+    fn symlink(&self, req: &Request, op: op::Symlink<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Create a file node.
+    fn mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Create a directory node.
+    fn mkdir(&self, req: &Request, op: op::Mkdir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Remove a file.
+    fn unlink(&self, req: &Request, op: op::Unlink<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Remove a directory.
+    fn rmdir(&self, req: &Request, op: op::Rmdir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Rename a file.
+    fn rename(&self, req: &Request, op: op::Rename<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Create a hard link.
+    fn link(&self, req: &Request, op: op::Link<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Open a file.
+    fn open(&self, req: &Request, op: op::Open<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Read data from a file.
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Write data to a file.
+    ///
+    /// The data is empty if the payload is left in the kernel pipe by `splice_read`,
+    /// in which case it is taken with `Request::write_data_to`.
+    fn write(&self, req: &Request, op: op::Write<'_>, data: Bytes) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Release an opened file.
+    fn release(&self, req: &Request, op: op::Release<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Get the filesystem statistics.
+    fn statfs(&self, req: &Request, op: op::Statfs<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Synchronize the file contents.
+    fn fsync(&self, req: &Request, op: op::Fsync<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(&self, req: &Request, op: op::Setxattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Get an extended attribute.
+    fn getxattr(&self, req: &Request, op: op::Getxattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// List extended attribute names.
+    fn listxattr(&self, req: &Request, op: op::Listxattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&self, req: &Request, op: op::Removexattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Close a file descriptor.
+    fn flush(&self, req: &Request, op: op::Flush<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Open a directory.
+    fn opendir(&self, req: &Request, op: op::Opendir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Read contents from an opened directory.
+    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Release an opened directory.
+    fn releasedir(&self, req: &Request, op: op::Releasedir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Synchronize the directory contents.
+    fn fsyncdir(&self, req: &Request, op: op::Fsyncdir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(&self, req: &Request, op: op::Getlk<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk(&self, req: &Request, op: op::Setlk<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Acquire, modify or release a BSD file lock.
+    fn flock(&self, req: &Request, op: op::Flock<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    fn access(&self, req: &Request, op: op::Access<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Create and open a file.
+    fn create(&self, req: &Request, op: op::Create<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Map block index within a file to block index within device.
+    fn bmap(&self, req: &Request, op: op::Bmap<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Allocate requested space.
+    fn fallocate(&self, req: &Request, op: op::Fallocate<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Copy a range of data from an opened file to another.
+    fn copy_file_range(&self, req: &Request, op: op::CopyFileRange<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Poll for readiness.
+    fn poll(&self, req: &Request, op: op::Poll<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Control a device.
+    fn ioctl(&self, req: &Request, op: op::Ioctl<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Reposition the offset of an opened file.
+    fn lseek(&self, req: &Request, op: op::Lseek<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Clean up the filesystem.
+    fn destroy(&self, req: &Request, op: op::Destroy<'_>) -> io::Result<()> {
+        req.reply(())
+    }
+
+    /// Forget the inodes removed from the kernel's internal caches.
+    ///
+    /// The kernel expects no reply to this request.
+    fn forget(&self, req: &Request, forgets: op::Forgets<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Interrupt a previous FUSE request.
+    ///
+    /// The session notifies the interrupt to the `CancelToken` of the target request,
+    /// so this method is usually not needed to be implemented.
+    /// The kernel expects no reply to this request.
+    fn interrupt(&self, req: &Request, op: op::Interrupt<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Receive the data retrieved by `Notifier::retrieve`.
+    ///
+    /// The kernel expects no reply to this request.
+    fn notify_reply(&self, req: &Request, op: op::NotifyReply<'_>, data: Bytes) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Handle a request with an opcode not supported by polyfuse.
+    ///
+    /// This method also receives all the requests after the session has exited,
+    /// for which `op::Unknown::is_exited` returns `true`.
+    fn unknown(&self, req: &Request, op: op::Unknown<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+}
+
+/// Dispatch a request to the corresponding method of the filesystem.
+///
+/// If the request cannot be decoded, the error is logged and the request is replied
+/// with the error number of `DecodeErrorPolicy::Reply`, or `EIO` by default. The
+/// requests that take no reply, such as `forget`, are not replied.
+pub fn dispatch<F>(fs: &F, req: &Request) -> io::Result<()>
+where
+    F: Filesystem + ?Sized,
+{
+    let op = match req.operation() {
+        Ok(op) => op,
+        Err(err) => return req.reply_undecodable(&err),
+    };
+
+    match op {
+        Operation::Lookup(op) => fs.lookup(req, op),
+        Operation::Getattr(op) => fs.getattr(req, op),
+        Operation::Setattr(op) => fs.setattr(req, op),
+        Operation::Readlink(op) => fs.readlink(req, op),
+        Operation::Symlink(op) => fs.symlink(req, op),
+        Operation::Mknod(op) => fs.mknod(req, op),
+        Operation::Mkdir(op) => fs.mkdir(req, op),
+        Operation::Unlink(op) => fs.unlink(req, op),
+        Operation::Rmdir(op) => fs.rmdir(req, op),
+        Operation::Rename(op) => fs.rename(req, op),
+        Operation::Link(op) => fs.link(req, op),
+        Operation::Open(op) => fs.open(req, op),
+        Operation::Read(op) => fs.read(req, op),
+        Operation::Write(op, data) => fs.write(req, op, data),
+        Operation::Release(op) => fs.release(req, op),
+        Operation::Statfs(op) => fs.statfs(req, op),
+        Operation::Fsync(op) => fs.fsync(req, op),
+        Operation::Setxattr(op) => fs.setxattr(req, op),
+        Operation::Getxattr(op) => fs.getxattr(req, op),
+        Operation::Listxattr(op) => fs.listxattr(req, op),
+        Operation::Removexattr(op) => fs.removexattr(req, op),
+        Operation::Flush(op) => fs.flush(req, op),
+        Operation::Opendir(op) => fs.opendir(req, op),
+        Operation::Readdir(op) => fs.readdir(req, op),
+        Operation::Releasedir(op) => fs.releasedir(req, op),
+        Operation::Fsyncdir(op) => fs.fsyncdir(req, op),
+        Operation::Getlk(op) => fs.getlk(req, op),
+        Operation::Setlk(op) => fs.setlk(req, op),
+        Operation::Flock(op) => fs.flock(req, op),
+        Operation::Access(op) => fs.access(req, op),
+        Operation::Create(op) => fs.create(req, op),
+        Operation::Bmap(op) => fs.bmap(req, op),
+        Operation::Fallocate(op) => fs.fallocate(req, op),
+        Operation::CopyFileRange(op) => fs.copy_file_range(req, op),
+        Operation::Poll(op) => fs.poll(req, op),
+        Operation::Ioctl(op) => fs.ioctl(req, op),
+        Operation::Lseek(op) => fs.lseek(req, op),
+        Operation::Destroy(op) => fs.destroy(req, op),
+        Operation::Forget(forgets) => fs.forget(req, forgets),
+        Operation::Interrupt(op) => fs.interrupt(req, op),
+        Operation::NotifyReply(op, data) => fs.notify_reply(req, op, data),
+        Operation::Unknown(op) => fs.unknown(req, op),
+    }
+}
+
+/// Receive the requests from the session and dispatch them to the filesystem,
+/// until the session is closed.
+///
+/// The errors returned from the handlers are logged with `tracing`, and the requests
+/// left unreplied by the failed handlers are replied with `EIO`. This function returns
+/// an error only if receiving the requests fails.
+pub fn serve<F>(session: &Session, fs: &F) -> io::Result<()>
+where
+    F: Filesystem + ?Sized,
+{
+    while let Some(req) = session.next_request()? {
+        if let Err(err) = dispatch(fs, &req) {
+            log_error(&req, &err);
+            reply_failed(&req);
+        }
+    }
+    Ok(())
+}
+
+/// Log the error returned from the handler of a request.
+///
+/// `ENOENT` means that the request has been interrupted and the kernel no longer
+/// waits for the reply, so it is not reported as a failure.
+pub(crate) fn log_error(req: &Request, err: &io::Error) {
+    if err.raw_os_error() == Some(libc::ENOENT) {
+        tracing::debug!("the request has been aborted (unique={})", req.unique());
+    } else {
+        tracing::error!(
+            "failed to handle the request (unique={}): {}",
+            req.unique(),
+            err
+        );
+    }
+}
+
+/// Reply `EIO` to the request whose handler has failed, if it is not replied yet.
+pub(crate) fn reply_failed(req: &Request) {
+    if let Err(err) = req.reply_error_if_unreplied(libc::EIO) {
+        log_error(req, &err);
+    }
+}
+
+/// The future returned from the methods of `AsyncFilesystem`.
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// The asynchronous version of `Filesystem`.
+///
+/// Each method returns a future that completes after the request is replied,
+/// and the default implementations behave the same as the ones of `Filesystem`.
+/// The replies are written to the device file without blocking, so the methods
+/// of `Request` can be called directly from the futures.
+#[allow(unused_variables)]
+pub trait AsyncFilesystem: Sync {
+    /// Lookup a directory entry by name.
+    fn lookup<'a>(&'a self, req: &'a Request, op: op::Lookup<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Get file attributes.
+    fn getattr<'a>(&'a self, req: &'a Request, op: op::Getattr<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Set file attributes.
+    fn setattr<'a>(&'a self, req: &'a Request, op: op::Setattr<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Read a symbolic link.
+    fn readlink<'a>(&'a self, req: &'a Request, op: op::Readlink<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Create a symbolic link.
+    fn symlink<'a>(&'a self, req: &'a Request, op: op::Symlink<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Create a file node.
+    fn mknod<'a>(&'a self, req: &'a Request, op: op::Mknod<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Create a directory node.
+    fn mkdir<'a>(&'a self, req: &'a Request, op: op::Mkdir<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Remove a file.
+    fn unlink<'a>(&'a self, req: &'a Request, op: op::Unlink<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Remove a directory.
+    fn rmdir<'a>(&'a self, req: &'a Request, op: op::Rmdir<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Rename a file.
+    fn rename<'a>(&'a self, req: &'a Request, op: op::Rename<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Create a hard link.
+    fn link<'a>(&'a self, req: &'a Request, op: op::Link<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Open a file.
+    fn open<'a>(&'a self, req: &'a Request, op: op::Open<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Read data from a file.
+    fn read<'a>(&'a self, req: &'a Request, op: op::Read<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Write data to a file.
+    ///
+    /// The data is empty if the payload is left in the kernel pipe by `splice_read`,
+    /// in which case it is taken with `Request::write_data_to`.
+    fn write<'a>(&'a self, req: &'a Request, op: op::Write<'a>, data: Bytes) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Release an opened file.
+    fn release<'a>(&'a self, req: &'a Request, op: op::Release<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Get the filesystem statistics.
+    fn statfs<'a>(&'a self, req: &'a Request, op: op::Statfs<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Synchronize the file contents.
+    fn fsync<'a>(&'a self, req: &'a Request, op: op::Fsync<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Set an extended attribute.
+    fn setxattr<'a>(&'a self, req: &'a Request, op: op::Setxattr<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Get an extended attribute.
+    fn getxattr<'a>(&'a self, req: &'a Request, op: op::Getxattr<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// List extended attribute names.
+    fn listxattr<'a>(&'a self, req: &'a Request, op: op::Listxattr<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr<'a>(&'a self, req: &'a Request, op: op::Removexattr<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Close a file descriptor.
+    fn flush<'a>(&'a self, req: &'a Request, op: op::Flush<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Open a directory.
+    fn opendir<'a>(&'a self, req: &'a Request, op: op::Opendir<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Read contents from an opened directory.
+    fn readdir<'a>(&'a self, req: &'a Request, op: op::Readdir<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Release an opened directory.
+    fn releasedir<'a>(&'a self, req: &'a Request, op: op::Releasedir<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Synchronize the directory contents.
+    fn fsyncdir<'a>(&'a self, req: &'a Request, op: op::Fsyncdir<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk<'a>(&'a self, req: &'a Request, op: op::Getlk<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk<'a>(&'a self, req: &'a Request, op: op::Setlk<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Acquire, modify or release a BSD file lock.
+    fn flock<'a>(&'a self, req: &'a Request, op: op::Flock<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Check file access permissions.
+    fn access<'a>(&'a self, req: &'a Request, op: op::Access<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Create and open a file.
+    fn create<'a>(&'a self, req: &'a Request, op: op::Create<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Map block index within a file to block index within device.
+    fn bmap<'a>(&'a self, req: &'a Request, op: op::Bmap<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Allocate requested space.
+    fn fallocate<'a>(&'a self, req: &'a Request, op: op::Fallocate<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Copy a range of data from an opened file to another.
+    fn copy_file_range<'a>(
+        &'a self,
+        req: &'a Request,
+        op: op::CopyFileRange<'a>,
+    ) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Poll for readiness.
+    fn poll<'a>(&'a self, req: &'a Request, op: op::Poll<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Control a device.
+    fn ioctl<'a>(&'a self, req: &'a Request, op: op::Ioctl<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Reposition the offset of an opened file.
+    fn lseek<'a>(&'a self, req: &'a Request, op: op::Lseek<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+
+    /// Clean up the filesystem.
+    fn destroy<'a>(&'a self, req: &'a Request, op: op::Destroy<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move { req.reply(()) })
+    }
+
+    /// Forget the inodes removed from the kernel's internal caches.
+    ///
+    /// The kernel expects no reply to this request.
+    fn forget<'a>(&'a self, req: &'a Request, forgets: op::Forgets<'a>) -> HandlerFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Interrupt a previous FUSE request.
+    ///
+    /// The session notifies the interrupt to the `CancelToken` of the target request,
+    /// so this method is usually not needed to be implemented.
+    /// The kernel expects no reply to this request.
+    fn interrupt<'a>(&'a self, req: &'a Request, op: op::Interrupt<'a>) -> HandlerFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Receive the data retrieved by `Notifier::retrieve`.
+    ///
+    /// The kernel expects no reply to this request.
+    fn notify_reply<'a>(
+        &'a self,
+        req: &'a Request,
+        op: op::NotifyReply<'a>,
+        data: Bytes,
+    ) -> HandlerFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Handle a request with an opcode not supported by polyfuse.
+    ///
+    /// See the documentation of `Filesystem::unknown` for details.
+    fn unknown<'a>(&'a self, req: &'a Request, op: op::Unknown<'a>) -> HandlerFuture<'a> {
+        enosys(req)
+    }
+}
+
+fn enosys(req: &Request) -> HandlerFuture<'_> {
+    Box::pin(async move { req.reply_error(libc::ENOSYS) })
+}
+
+/// Dispatch a request to the corresponding method of the asynchronous filesystem.
+///
+/// See the documentation of `dispatch` for details.
+pub async fn dispatch_async<F>(fs: &F, req: &Request) -> io::Result<()>
+where
+    F: AsyncFilesystem + ?Sized,
+{
+    let op = match req.operation() {
+        Ok(op) => op,
+        Err(err) => return req.reply_undecodable(&err),
+    };
+
+    match op {
+        Operation::Lookup(op) => fs.lookup(req, op).await,
+        Operation::Getattr(op) => fs.getattr(req, op).await,
+        Operation::Setattr(op) => fs.setattr(req, op).await,
+        Operation::Readlink(op) => fs.readlink(req, op).await,
+        Operation::Symlink(op) => fs.symlink(req, op).await,
+        Operation::Mknod(op) => fs.mknod(req, op).await,
+        Operation::Mkdir(op) => fs.mkdir(req, op).await,
+        Operation::Unlink(op) => fs.unlink(req, op).await,
+        Operation::Rmdir(op) => fs.rmdir(req, op).await,
+        Operation::Rename(op) => fs.rename(req, op).await,
+        Operation::Link(op) => fs.link(req, op).await,
+        Operation::Open(op) => fs.open(req, op).await,
+        Operation::Read(op) => fs.read(req, op).await,
+        Operation::Write(op, data) => fs.write(req, op, data).await,
+        Operation::Release(op) => fs.release(req, op).await,
+        Operation::Statfs(op) => fs.statfs(req, op).await,
+        Operation::Fsync(op) => fs.fsync(req, op).await,
+        Operation::Setxattr(op) => fs.setxattr(req, op).await,
+        Operation::Getxattr(op) => fs.getxattr(req, op).await,
+        Operation::Listxattr(op) => fs.listxattr(req, op).await,
+        Operation::Removexattr(op) => fs.removexattr(req, op).await,
+        Operation::Flush(op) => fs.flush(req, op).await,
+        Operation::Opendir(op) => fs.opendir(req, op).await,
+        Operation::Readdir(op) => fs.readdir(req, op).await,
+        Operation::Releasedir(op) => fs.releasedir(req, op).await,
+        Operation::Fsyncdir(op) => fs.fsyncdir(req, op).await,
+        Operation::Getlk(op) => fs.getlk(req, op).await,
+        Operation::Setlk(op) => fs.setlk(req, op).await,
+        Operation::Flock(op) => fs.flock(req, op).await,
+        Operation::Access(op) => fs.access(req, op).await,
+        Operation::Create(op) => fs.create(req, op).await,
+        Operation::Bmap(op) => fs.bmap(req, op).await,
+        Operation::Fallocate(op) => fs.fallocate(req, op).await,
+        Operation::CopyFileRange(op) => fs.copy_file_range(req, op).await,
+        Operation::Poll(op) => fs.poll(req, op).await,
+        Operation::Ioctl(op) => fs.ioctl(req, op).await,
+        Operation::Lseek(op) => fs.lseek(req, op).await,
+        Operation::Destroy(op) => fs.destroy(req, op).await,
+        Operation::Forget(forgets) => fs.forget(req, forgets).await,
+        Operation::Interrupt(op) => fs.interrupt(req, op).await,
+        Operation::NotifyReply(op, data) => fs.notify_reply(req, op, data).await,
+        Operation::Unknown(op) => fs.unknown(req, op).await,
+    }
+}
+
+/// A task that handles a request, passed to `Spawn`.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// An executor that runs the tasks spawned by `AsyncServer`.
+///
+/// This trait is implemented for closures, so the spawn function of any runtime can
+/// be used as an executor, e.g. `|task| { tokio::spawn(task); }`.
+pub trait Spawn {
+    /// Run the task in the background.
+    fn spawn(&self, task: Task);
+}
+
+impl<F> Spawn for F
+where
+    F: Fn(Task),
+{
+    fn spawn(&self, task: Task) {
+        (self)(task)
+    }
+}
+
+type ErrorHandler = dyn Fn(&Request, io::Error) + Send + Sync;
+
+/// A driver that handles each request with an `AsyncFilesystem` in its own task.
+///
+/// The requests are received by blocking the current thread, and the handlers are
+/// spawned on the specified executor. The number of tasks in flight is limited by
+/// `max_concurrency`; once the limit is reached, the driver stops receiving requests
+/// until any of the tasks completes, and the remaining requests wait in the kernel queue.
+pub struct AsyncServer<S> {
+    spawner: S,
+    max_concurrency: usize,
+    on_error: Arc<ErrorHandler>,
+}
+
+impl<S> fmt::Debug for AsyncServer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncServer")
+            .field("max_concurrency", &self.max_concurrency)
+            .finish()
+    }
+}
+
+impl<S> AsyncServer<S>
+where
+    S: Spawn,
+{
+    /// The default value of `max_concurrency`.
+    pub const DEFAULT_MAX_CONCURRENCY: usize = 256;
+
+    /// Create a driver that spawns the tasks on the specified executor.
+    pub fn new(spawner: S) -> Self {
+        Self {
+            spawner,
+            max_concurrency: Self::DEFAULT_MAX_CONCURRENCY,
+            on_error: Arc::new(|req, err| log_error(req, &err)),
+        }
+    }
+
+    /// Specify the maximum number of the requests handled at the same time.
+    ///
+    /// # Panics
+    /// Panics if `n` is zero.
+    pub fn max_concurrency(&mut self, n: usize) -> &mut Self {
+        assert!(n > 0, "max_concurrency must be greater than zero");
+        self.max_concurrency = n;
+        self
+    }
+
+    /// Specify the callback invoked when a handler returns an error.
+    ///
+    /// By default, the errors are logged with `tracing`, except `ENOENT` that means
+    /// the request has been interrupted. After the callback returns, the request is
+    /// replied with `EIO` if it has not been replied yet.
+    pub fn on_error<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Request, io::Error) + Send + Sync + 'static,
+    {
+        self.on_error = Arc::new(f);
+        self
+    }
+
+    /// Receive the requests from the session and spawn the handlers,
+    /// until the session is closed.
+    ///
+    /// This method returns after all the spawned tasks have been completed.
+    /// The session must not be the one underlying the async sessions, whose
+    /// device file is switched to the non-blocking mode.
+    pub fn serve<F>(&self, session: &Session, fs: Arc<F>) -> io::Result<()>
+    where
+        F: AsyncFilesystem + Send + 'static,
+    {
+        let limit = Arc::new(Limit {
+            in_flight: Mutex::new(0),
+            cond: Condvar::new(),
+        });
+
+        let res = loop {
+            let permit = limit.acquire(self.max_concurrency);
+
+            let req = match session.next_request() {
+                Ok(Some(req)) => req,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+
+            let fs = fs.clone();
+            let on_error = self.on_error.clone();
+            self.spawner.spawn(Box::pin(async move {
+                let _permit = permit;
+                if let Err(err) = dispatch_async(&*fs, &req).await {
+                    on_error(&req, err);
+                    reply_failed(&req);
+                }
+            }));
+        };
+
+        // Wait for the completion of the tasks in flight.
+        limit.wait_idle();
+
+        res
+    }
+}
+
+/// The counter of the tasks in flight.
+struct Limit {
+    in_flight: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl Limit {
+    /// Wait until the number of the tasks in flight falls below `max`, and then count up.
+    fn acquire(self: &Arc<Self>, max: usize) -> Permit {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= max {
+            in_flight = self.cond.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+        Permit(self.clone())
+    }
+
+    /// Wait until all the tasks are completed.
+    fn wait_idle(&self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight > 0 {
+            in_flight = self.cond.wait(in_flight).unwrap();
+        }
+    }
+}
+
+/// A slot of the tasks in flight, released on drop.
+struct Permit(Arc<Limit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.in_flight.lock().unwrap() -= 1;
+        self.0.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reply::AttrOut, session::testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use zerocopy::AsBytes as _;
+
+    struct Getattr;
+
+    impl Filesystem for Getattr {
+        fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+            let mut out = AttrOut::default();
+            out.attr().ino(op.ino());
+            req.reply(out)
+        }
+    }
+
+    #[test]
+    fn dispatch_default() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let fs = Getattr;
+
+        kernel.send(
+            FUSE_GETATTR,
+            2,
+            42,
+            &[fuse_getattr_in::default().as_bytes()],
+        );
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+        assert_eq!(arg.len(), std::mem::size_of::<fuse_attr_out>());
+
+        kernel.send(FUSE_STATFS, 4, 1, &[]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (4, -libc::ENOSYS));
+
+        // forget and interrupt must not be replied.
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        kernel.send(FUSE_FORGET, 6, 42, &[forget_in.as_bytes()]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        let interrupt_in = fuse_interrupt_in { unique: 4 };
+        kernel.send(FUSE_INTERRUPT, 8, 0, &[interrupt_in.as_bytes()]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        assert!(kernel.recv().is_none());
+
+        kernel.send(0xdead, 10, 0, &[]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        // The interrupt for the completed request is requeued by the session.
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (8, -libc::EAGAIN));
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (10, -libc::ENOSYS));
+    }
+
+    #[test]
+    fn dispatch_undecodable() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let fs = Getattr;
+
+        // The requests that take no reply are not replied even if they are broken.
+        kernel.send(FUSE_FORGET, 2, 42, &[]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        kernel.send(FUSE_GETATTR, 4, 42, &[]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (4, -libc::EIO));
+        assert!(kernel.recv().is_none());
+    }
+
+    struct Failing;
+
+    impl Filesystem for Failing {
+        fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+            if op.ino() == 1 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            let mut out = AttrOut::default();
+            out.attr().ino(op.ino());
+            req.reply(out)
+        }
+    }
+
+    #[test]
+    fn serve_after_handler_error() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        let getattr_in = fuse_getattr_in::default();
+        kernel.send(FUSE_GETATTR, 2, 1, &[getattr_in.as_bytes()]);
+        kernel.send(FUSE_GETATTR, 4, 42, &[getattr_in.as_bytes()]);
+        kernel.send(FUSE_DESTROY, 6, 0, &[]);
+        serve(&session, &Failing).unwrap();
+
+        // The failed request is replied with EIO, and the next one is still handled.
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, -libc::EIO));
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (4, 0));
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (6, 0));
+    }
+
+    struct Syncfs;
+
+    impl Filesystem for Syncfs {
+        fn unknown(&self, req: &Request, op: op::Unknown<'_>) -> io::Result<()> {
+            match op.opcode() {
+                FUSE_SYNCFS if op.arg() == [0u8; 8] => req.reply(()),
+                _ => req.reply_error(libc::ENOSYS),
+            }
+        }
+    }
+
+    #[test]
+    fn dispatch_unknown() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let fs = Syncfs;
+
+        // The requests with unsupported opcodes are passed with the raw argument.
+        kernel.send(FUSE_SYNCFS, 2, 1, &[&[0u8; 8]]);
+        dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+
+        kernel.send(FUSE_STATX, 4, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Unknown(op) => {
+                assert_eq!(op.header().unique, 4);
+                assert!(op.arg().is_empty());
+                assert!(!op.is_exited());
+            }
+            op => panic!("unexpected operation: {:?}", op),
+        }
+        dispatch(&fs, &req).unwrap();
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (4, -libc::ENOSYS));
+
+        // The supported opcodes are not decoded after the session exits.
+        kernel.send(FUSE_STATFS, 6, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        drop(session);
+        match req.operation().unwrap() {
+            Operation::Unknown(op) => {
+                assert_eq!(op.opcode(), FUSE_STATFS);
+                assert!(op.is_exited());
+            }
+            op => panic!("unexpected operation: {:?}", op),
+        }
+    }
+
+    #[derive(Default)]
+    struct Concurrent {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl AsyncFilesystem for Concurrent {
+        fn getattr<'a>(&'a self, req: &'a Request, op: op::Getattr<'a>) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                let n = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(n, Ordering::SeqCst);
+                for _ in 0..4 {
+                    tokio::task::yield_now().await;
+                }
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                if op.ino() == 0 {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                let mut out = AttrOut::default();
+                out.attr().ino(op.ino());
+                req.reply(out)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn async_server_limits_concurrency() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let fs = Arc::new(Concurrent::default());
+        let errors = Arc::new(Mutex::new(vec![]));
+
+        for i in 0..6 {
+            kernel.send(
+                FUSE_GETATTR,
+                2 * i + 2,
+                i,
+                &[fuse_getattr_in::default().as_bytes()],
+            );
+        }
+        kernel.send(FUSE_DESTROY, 100, 0, &[]);
+
+        let mut server = AsyncServer::new(|task| {
+            tokio::spawn(task);
+        });
+        server.max_concurrency(2).on_error({
+            let errors = errors.clone();
+            move |req, err| {
+                errors
+                    .lock()
+                    .unwrap()
+                    .push((req.unique(), err.raw_os_error()))
+            }
+        });
+        tokio::task::spawn_blocking({
+            let fs = fs.clone();
+            move || server.serve(&session, fs)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(fs.in_flight.load(Ordering::SeqCst), 0);
+        assert!(fs.max_in_flight.load(Ordering::SeqCst) <= 2);
+        assert_eq!(*errors.lock().unwrap(), vec![(2, Some(libc::EINVAL))]);
+
+        let mut replies = vec![];
+        while let Some((header, _)) = kernel.recv() {
+            replies.push((header.unique, header.error));
+        }
+        replies.sort_unstable();
+        assert_eq!(
+            replies,
+            vec![
+                (2, -libc::EIO),
+                (4, 0),
+                (6, 0),
+                (8, 0),
+                (10, 0),
+                (12, 0),
+                (100, 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+//! Allocation of inode numbers and tracking of the lookup counts.
+
+use crate::{
+    op::Forget,
+    reply::{EntryOut, OpenOut, ReaddirPlusOut},
+    session::Request,
+};
+use polyfuse_kernel::FUSE_ROOT_ID;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+/// A table of the inodes exposed to the kernel, keyed by inode number.
+///
+/// Each entry has two kinds of reference counts:
+///
+/// * The *lookup count* is the number of the references held by the kernel.
+///   It is incremented when the entry is replied to the kernel by `reply_entry`
+///   or `reply_create`, and decremented by `forget`.
+/// * The *link count* is the number of the references held by the filesystem,
+///   such as the directory entries pointing to the inode. It is controlled
+///   explicitly with `link` and `unlink`.
+///
+/// An entry is removed from the table when both of the counts reach zero,
+/// and its inode number is recycled with an incremented generation number,
+/// so that the pairs of inode number and generation are never reused.
+///
+/// The root inode is registered with `FUSE_ROOT_ID` and never removed.
+pub struct InodeTable<T> {
+    inner: Mutex<Inner<T>>,
+}
+
+struct Inner<T> {
+    map: HashMap<u64, Slot<T>>,
+    free: VecDeque<(u64, u64)>,
+    next_ino: u64,
+}
+
+struct Slot<T> {
+    value: Arc<T>,
+    generation: u64,
+    nlookup: u64,
+    nlink: u64,
+}
+
+impl<T> fmt::Debug for InodeTable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeTable")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<T> InodeTable<T> {
+    /// Create a table that contains the root inode.
+    pub fn new(root: T) -> Self {
+        let mut map = HashMap::new();
+        map.insert(
+            FUSE_ROOT_ID,
+            Slot {
+                value: Arc::new(root),
+                generation: 0,
+                nlookup: 0,
+                nlink: 0,
+            },
+        );
+        Self {
+            inner: Mutex::new(Inner {
+                map,
+                free: VecDeque::new(),
+                next_ino: FUSE_ROOT_ID + 1,
+            }),
+        }
+    }
+
+    /// Return the number of the entries in this table, including the root.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().map.len()
+    }
+
+    /// Return whether the table has no entries except for the root.
+    pub fn is_empty(&self) -> bool {
+        self.len() <= 1
+    }
+
+    /// Register a new inode and return the allocated inode number.
+    ///
+    /// Both of the reference counts of the new entry are zero. The entry should be
+    /// passed to `reply_entry` or `link` soon, since it is not removed until
+    /// one of the counts is decremented to zero.
+    pub fn insert(&self, value: T) -> u64 {
+        self.insert_with(|_| value)
+    }
+
+    /// Register a new inode whose value is built from the allocated inode number.
+    ///
+    /// The closure is called while the table is locked.
+    pub fn insert_with<F>(&self, f: F) -> u64
+    where
+        F: FnOnce(u64) -> T,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let (ino, generation) = match inner.free.pop_front() {
+            Some((ino, generation)) => (ino, generation + 1),
+            None => {
+                let ino = inner.next_ino;
+                inner.next_ino += 1;
+                (ino, 0)
+            }
+        };
+        inner.map.insert(
+            ino,
+            Slot {
+                value: Arc::new(f(ino)),
+                generation,
+                nlookup: 0,
+                nlink: 0,
+            },
+        );
+        ino
+    }
+
+    /// Return the value of an inode.
+    pub fn get(&self, ino: u64) -> Option<Arc<T>> {
+        let inner = self.inner.lock().unwrap();
+        inner.map.get(&ino).map(|slot| slot.value.clone())
+    }
+
+    /// Return the generation number of an inode.
+    pub fn generation(&self, ino: u64) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.map.get(&ino).map(|slot| slot.generation)
+    }
+
+    /// Return the lookup count of an inode.
+    pub fn nlookup(&self, ino: u64) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.map.get(&ino).map(|slot| slot.nlookup)
+    }
+
+    /// Increment the link count of an inode.
+    ///
+    /// Return `false` if the inode is not found.
+    pub fn link(&self, ino: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.map.get_mut(&ino) {
+            Some(slot) => {
+                slot.nlink += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Decrement the link count of an inode.
+    ///
+    /// The value is returned if the entry is removed from the table.
+    pub fn unlink(&self, ino: u64) -> Option<Arc<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        let slot = inner.map.get_mut(&ino)?;
+        if slot.nlink == 0 {
+            tracing::warn!("unbalanced unlink (ino={})", ino);
+        }
+        slot.nlink = slot.nlink.saturating_sub(1);
+        inner.remove_unreferenced(ino)
+    }
+
+    /// Increment the lookup count of an inode, and return its generation number.
+    ///
+    /// This is used for the replies that return the entries to the kernel other than
+    /// `reply_entry` and `reply_create`. The count must be released with `release`
+    /// if the reply fails.
+    pub fn acquire(&self, ino: u64) -> Option<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let slot = inner.map.get_mut(&ino)?;
+        slot.nlookup += 1;
+        Some(slot.generation)
+    }
+
+    /// Decrement the lookup count of an inode.
+    ///
+    /// The value is returned if the entry is removed from the table.
+    pub fn release(&self, ino: u64, nlookup: u64) -> Option<Arc<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        let slot = inner.map.get_mut(&ino)?;
+        if slot.nlookup < nlookup && ino != FUSE_ROOT_ID {
+            tracing::warn!(
+                "lookup count underflow (ino={}, nlookup={}, forget={})",
+                ino,
+                slot.nlookup,
+                nlookup
+            );
+        }
+        slot.nlookup = slot.nlookup.saturating_sub(nlookup);
+        inner.remove_unreferenced(ino)
+    }
+
+    /// Decrement the lookup counts by the `forget` requests.
+    ///
+    /// The values of the entries removed from the table are returned.
+    pub fn forget(&self, forgets: &[Forget]) -> Vec<Arc<T>> {
+        forgets
+            .iter()
+            .filter_map(|forget| self.release(forget.ino(), forget.nlookup()))
+            .collect()
+    }
+
+    /// Reply to a request with an entry of the inode, and increment its lookup count.
+    ///
+    /// The inode number and generation of `out` are overwritten with the ones of the entry.
+    /// If the inode is not found, the request is replied with `ENOENT`.
+    pub fn reply_entry(&self, req: &Request, ino: u64, mut out: EntryOut) -> io::Result<()> {
+        let generation = match self.acquire(ino) {
+            Some(generation) => generation,
+            None => return req.reply_error(libc::ENOENT),
+        };
+        out.ino(ino);
+        out.generation(generation);
+        self.reply_acquired(ino, req.reply(out))
+    }
+
+    /// Reply to a `create` request with an entry of the inode and the opened file,
+    /// and increment its lookup count.
+    ///
+    /// See the documentation of `reply_entry` for details.
+    pub fn reply_create(
+        &self,
+        req: &Request,
+        ino: u64,
+        mut entry: EntryOut,
+        open: OpenOut,
+    ) -> io::Result<()> {
+        let generation = match self.acquire(ino) {
+            Some(generation) => generation,
+            None => return req.reply_error(libc::ENOENT),
+        };
+        entry.ino(ino);
+        entry.generation(generation);
+        self.reply_acquired(ino, req.reply((entry, open)))
+    }
+
+    /// Reply to a `readdirplus` request, and increment the lookup counts of the entries.
+    ///
+    /// The generations of the entries are overwritten with the ones in the table.
+    /// If one of the inodes is not found, the request is replied with `ENOENT` and
+    /// none of the counts are changed.
+    pub fn reply_readdirplus(&self, req: &Request, mut out: ReaddirPlusOut) -> io::Result<()> {
+        let inos: Vec<u64> = out.lookups().collect();
+        for (i, &ino) in inos.iter().enumerate() {
+            match self.acquire(ino) {
+                Some(generation) => out.set_generation(i, generation),
+                None => {
+                    self.release_all(&inos[..i]);
+                    return req.reply_error(libc::ENOENT);
+                }
+            }
+        }
+
+        let res = req.reply(out);
+        if res.is_err() {
+            self.release_all(&inos);
+        }
+        res
+    }
+
+    fn release_all(&self, inos: &[u64]) {
+        for &ino in inos {
+            self.release(ino, 1);
+        }
+    }
+
+    fn reply_acquired(&self, ino: u64, res: io::Result<()>) -> io::Result<()> {
+        if res.is_err() {
+            // The kernel has not received the entry, e.g. the request has been interrupted.
+            self.release(ino, 1);
+        }
+        res
+    }
+}
+
+impl<T> Inner<T> {
+    fn remove_unreferenced(&mut self, ino: u64) -> Option<Arc<T>> {
+        match self.map.get(&ino) {
+            Some(slot) if ino != FUSE_ROOT_ID && slot.nlookup == 0 && slot.nlink == 0 => {
+                tracing::debug!("remove inode (ino={})", ino);
+                let slot = self.map.remove(&ino)?;
+                self.free.push_back((ino, slot.generation));
+                Some(slot.value)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::{ffi::OsStr, mem};
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn recycle_with_generation() {
+        let table = InodeTable::new("root");
+
+        let ino = table.insert("a");
+        assert_eq!(ino, 2);
+        assert_eq!(table.acquire(ino), Some(0));
+        assert_eq!(table.acquire(ino), Some(0));
+        assert!(table.release(ino, 1).is_none());
+        assert_eq!(table.release(ino, 1).as_deref(), Some(&"a"));
+        assert!(table.get(ino).is_none());
+
+        let ino2 = table.insert("b");
+        assert_eq!(ino2, ino);
+        assert_eq!(table.generation(ino2), Some(1));
+        assert_eq!(table.insert("c"), 3);
+    }
+
+    #[test]
+    fn keep_linked_inodes() {
+        let table = InodeTable::new(());
+
+        let ino = table.insert(());
+        assert!(table.link(ino));
+        table.acquire(ino);
+        assert!(table.release(ino, 1).is_none());
+        assert!(table.get(ino).is_some());
+        assert!(table.unlink(ino).is_some());
+        assert!(table.is_empty());
+
+        // The root inode is never removed.
+        assert!(table.release(FUSE_ROOT_ID, 1).is_none());
+        assert!(table.get(FUSE_ROOT_ID).is_some());
+    }
+
+    #[test]
+    fn count_replied_entries() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let table = InodeTable::new(());
+        let ino = table.insert(());
+
+        kernel.send(FUSE_LOOKUP, 2, FUSE_ROOT_ID, &[b"a\0"]);
+        let req = session.next_request().unwrap().unwrap();
+        table.reply_entry(&req, ino, EntryOut::default()).unwrap();
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+        let mut out = fuse_entry_out::default();
+        out.as_bytes_mut().copy_from_slice(&arg);
+        assert_eq!((out.nodeid, out.generation), (ino, 0));
+        assert_eq!(table.nlookup(ino), Some(1));
+
+        // The count is not incremented if the reply fails.
+        kernel.send(FUSE_LOOKUP, 4, FUSE_ROOT_ID, &[b"a\0"]);
+        let req = session.next_request().unwrap().unwrap();
+        drop(kernel);
+        assert!(table.reply_entry(&req, ino, EntryOut::default()).is_err());
+        assert_eq!(table.nlookup(ino), Some(1));
+    }
+
+    #[test]
+    fn count_readdirplus_entries() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let table = InodeTable::new(());
+        let ino = table.insert(());
+        table.acquire(ino);
+        table.release(ino, 1);
+        let ino = table.insert(());
+        assert_eq!(table.generation(ino), Some(1));
+
+        let mut out = ReaddirPlusOut::new(4096);
+        for (i, name) in [".", "..", "a", "b"].iter().enumerate() {
+            let mut entry = EntryOut::default();
+            entry.ino(if i < 2 { FUSE_ROOT_ID } else { ino });
+            assert!(!out.entry(OsStr::new(name), libc::DT_REG as u32, i as u64 + 1, entry));
+        }
+        assert_eq!(out.lookups().collect::<Vec<_>>(), vec![ino, ino]);
+
+        kernel.send(
+            FUSE_READDIRPLUS,
+            2,
+            FUSE_ROOT_ID,
+            &[fuse_read_in::default().as_bytes()],
+        );
+        let req = session.next_request().unwrap().unwrap();
+        table.reply_readdirplus(&req, out).unwrap();
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+        assert_eq!(table.nlookup(ino), Some(2));
+        assert_eq!(table.nlookup(FUSE_ROOT_ID), Some(0));
+
+        // The entry of `a` starts after the ones of `.` and `..`, each of which is
+        // padded to 8 bytes.
+        let len = mem::size_of::<fuse_direntplus>();
+        let pos = ((len + 1 + 7) & !7) + ((len + 2 + 7) & !7);
+        let mut entry = fuse_direntplus::default();
+        entry.as_bytes_mut().copy_from_slice(&arg[pos..pos + len]);
+        assert_eq!(entry.entry_out.nodeid, ino);
+        assert_eq!(entry.entry_out.generation, 1);
+        assert_eq!(entry.dirent.namelen, 1);
+        assert_eq!(arg[pos + len], b'a');
+    }
+}
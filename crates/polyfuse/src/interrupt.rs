@@ -0,0 +1,293 @@
+//! Cancellation of the requests interrupted by the kernel.
+//!
+//! The kernel sends a `FUSE_INTERRUPT` request when the process waiting for a request
+//! receives a signal, e.g. by Ctrl-C. The session keeps track of the requests being
+//! handled, and notifies the interrupts through the `CancelToken` of each request.
+
+use crate::{
+    conn::Connection,
+    session::{write_bytes, Reply},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+/// A handle to observe whether a request has been interrupted.
+///
+/// The token is obtained by `Request::cancel_token`. The long-running handlers should
+/// check it periodically, or race the work against `cancelled`, and reply with `EINTR`
+/// once the request is interrupted.
+#[derive(Clone)]
+pub struct CancelToken {
+    state: Arc<State>,
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    inner: Mutex<StateInner>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct StateInner {
+    cancelled: bool,
+    wakers: Vec<Waker>,
+}
+
+impl State {
+    fn cancel(&self) {
+        let wakers = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.cancelled = true;
+            std::mem::take(&mut inner.wakers)
+        };
+        self.cond.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl CancelToken {
+    /// Create a token that is never cancelled.
+    pub(crate) fn never() -> Self {
+        Self {
+            state: Arc::default(),
+        }
+    }
+
+    /// Return whether the request has been interrupted.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.inner.lock().unwrap().cancelled
+    }
+
+    /// Block the current thread until the request is interrupted.
+    pub fn wait(&self) {
+        let mut inner = self.state.inner.lock().unwrap();
+        while !inner.cancelled {
+            inner = self.state.cond.wait(inner).unwrap();
+        }
+    }
+
+    /// Block the current thread until the request is interrupted or the timeout expires.
+    ///
+    /// Return whether the request has been interrupted.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let inner = self.state.inner.lock().unwrap();
+        let (inner, _) = self
+            .state
+            .cond
+            .wait_timeout_while(inner, timeout, |inner| !inner.cancelled)
+            .unwrap();
+        inner.cancelled
+    }
+
+    /// Return a future that completes when the request is interrupted.
+    ///
+    /// The future does not depend on any specific async runtime.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// The future returned by `CancelToken::cancelled`.
+pub struct Cancelled {
+    state: Arc<State>,
+}
+
+impl fmt::Debug for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cancelled").finish()
+    }
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.state.inner.lock().unwrap();
+        if inner.cancelled {
+            return Poll::Ready(());
+        }
+        if !inner.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            inner.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// The requests being handled and the interrupts whose target has not been received yet.
+#[derive(Default)]
+pub(crate) struct Registry {
+    inner: Mutex<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    in_flight: HashMap<u64, Arc<State>>,
+    pending: VecDeque<Pending>,
+}
+
+/// An interrupt that arrived before the request to be interrupted.
+struct Pending {
+    unique: u64,
+    target: u64,
+    conn: Arc<Connection>,
+}
+
+impl Registry {
+    /// Start tracking a received request, and return the guard to stop tracking it.
+    ///
+    /// If an interrupt for the request has arrived in advance, the request is
+    /// cancelled immediately. Otherwise, one of the pending interrupts is replied
+    /// with `EAGAIN` so that the kernel queues it again, in the same way as libfuse.
+    pub(crate) fn track(self: &Arc<Self>, unique: u64) -> Tracked {
+        let state = Arc::new(State::default());
+        let requeue = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.in_flight.insert(unique, state.clone());
+            match inner.pending.iter().position(|p| p.target == unique) {
+                Some(i) => {
+                    inner.pending.remove(i);
+                    state.cancel();
+                    None
+                }
+                None => inner.pending.pop_front(),
+            }
+        };
+
+        if let Some(pending) = requeue {
+            tracing::debug!(
+                "requeue the interrupt (unique={}, target={})",
+                pending.unique,
+                pending.target
+            );
+            // The write fails with ENOENT if the target has already been completed.
+            if let Err(err) =
+                write_bytes(&*pending.conn, Reply::new(pending.unique, libc::EAGAIN, ()))
+            {
+                tracing::debug!("failed to requeue the interrupt: {}", err);
+            }
+        }
+
+        Tracked {
+            registry: self.clone(),
+            unique,
+            state,
+        }
+    }
+
+    /// Notify an interrupt to the target request.
+    pub(crate) fn interrupt(&self, unique: u64, target: u64, conn: &Arc<Connection>) {
+        let state = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.in_flight.get(&target) {
+                Some(state) => state.clone(),
+                None => {
+                    inner.pending.push_back(Pending {
+                        unique,
+                        target,
+                        conn: conn.clone(),
+                    });
+                    return;
+                }
+            }
+        };
+        tracing::debug!("interrupt the request (target={})", target);
+        state.cancel();
+    }
+}
+
+/// A request tracked by `Registry`, which is untracked when dropped.
+pub(crate) struct Tracked {
+    registry: Arc<Registry>,
+    unique: u64,
+    state: Arc<State>,
+}
+
+impl Tracked {
+    pub(crate) fn token(&self) -> CancelToken {
+        CancelToken {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut inner = self.registry.inner.lock().unwrap();
+        inner.in_flight.remove(&self.unique);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{session::testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::thread;
+    use zerocopy::AsBytes as _;
+
+    fn interrupt_in(target: u64) -> fuse_interrupt_in {
+        fuse_interrupt_in { unique: target }
+    }
+
+    #[test]
+    fn cancel_interrupted_requests() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        kernel.send(FUSE_GETATTR, 2, 1, &[fuse_getattr_in::default().as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        let token = req.cancel_token();
+        assert!(!token.is_cancelled());
+
+        let waiter = thread::spawn(move || token.wait());
+        kernel.send(FUSE_INTERRUPT, 3, 0, &[interrupt_in(2).as_bytes()]);
+        let _ = session.next_request().unwrap().unwrap();
+        waiter.join().unwrap();
+        assert!(req.is_interrupted());
+
+        // The interrupt is not replied.
+        req.reply_error(libc::EINTR).unwrap();
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, -libc::EINTR));
+        assert!(kernel.recv().is_none());
+    }
+
+    #[test]
+    fn interrupt_before_request() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        // The interrupt for a request not yet received is kept until the request arrives.
+        kernel.send(FUSE_INTERRUPT, 5, 0, &[interrupt_in(4).as_bytes()]);
+        let _ = session.next_request().unwrap().unwrap();
+        kernel.send(FUSE_GETATTR, 4, 1, &[fuse_getattr_in::default().as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.is_interrupted());
+        assert!(kernel.recv().is_none());
+
+        // Otherwise, the interrupt is requeued with EAGAIN on the next request.
+        kernel.send(FUSE_INTERRUPT, 7, 0, &[interrupt_in(100).as_bytes()]);
+        let _ = session.next_request().unwrap().unwrap();
+        kernel.send(FUSE_GETATTR, 8, 1, &[fuse_getattr_in::default().as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        assert!(!req.is_interrupted());
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (7, -libc::EAGAIN));
+    }
+}
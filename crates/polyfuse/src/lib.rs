@@ -3,15 +3,43 @@
 #![doc(html_root_url = "https://docs.rs/polyfuse/0.4.0")]
 #![forbid(clippy::todo, clippy::unimplemented)]
 
+#[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+#[macro_use]
+mod rt;
+
+mod buf;
 mod conn;
+mod cuse;
 mod decoder;
 mod session;
+mod uring;
+
+#[cfg(any(feature = "async-std", feature = "smol"))]
+mod async_io;
 
 pub mod atomic_bytes;
+pub mod dir;
+pub mod errno;
+pub mod fs;
+pub mod inode;
+pub mod interrupt;
 pub mod op;
+pub mod path;
+pub mod pool;
 pub mod reply;
+pub mod retrieve;
+
+#[cfg(feature = "async-std")]
+pub mod async_std;
+#[cfg(feature = "smol")]
+pub mod smol;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
 pub use crate::{
+    buf::RequestBuffer,
+    cuse::{CuseConfig, CuseSession},
+    errno::Errno,
     op::Operation,
-    session::{KernelConfig, Notifier, Request, Session},
+    session::{Channel, KernelConfig, Notifier, Request, Session},
 };
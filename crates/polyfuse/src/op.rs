@@ -1,27 +1,121 @@
-use crate::decoder::Decoder;
+use crate::{
+    decoder::Decoder,
+    errno::Errno,
+    reply::{
+        AttrReply, BmapReply, CreateReply, DataReply, EmptyReply, EntryReply, IoctlReply, LkReply,
+        LseekReply, OpenReply, PollReply, ReaddirReply, ReplyHandle, StatfsReply, WriteReply,
+        XattrReply,
+    },
+    session::Request,
+};
 use polyfuse_kernel::*;
-use std::{ffi::OsStr, fmt, time::Duration, u32, u64};
+use std::{ffi::OsStr, fmt, mem, time::Duration, u32, u64};
 
-#[derive(Debug)]
+/// The error occurred while decoding a request message.
+#[derive(Debug, Clone)]
 pub struct DecodeError {
-    inner: crate::decoder::DecodeError,
+    opcode: u32,
+    unique: u64,
+    offset: usize,
+    kind: DecodeErrorKind,
 }
 
 impl DecodeError {
-    #[inline]
-    const fn new(inner: crate::decoder::DecodeError) -> Self {
-        Self { inner }
+    /// Return the opcode of the request.
+    pub fn opcode(&self) -> u32 {
+        self.opcode
+    }
+
+    /// Return the unique ID of the request.
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Return the byte offset in the message at which the decoding failed.
+    ///
+    /// The offset is counted from the beginning of the message, including
+    /// `fuse_in_header`.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Return the reason of the failure.
+    pub fn kind(&self) -> DecodeErrorKind {
+        self.kind
     }
 }
 
 impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "failed to decode request message")
+        write!(
+            f,
+            "failed to decode request message (opcode={}, unique={}, offset={}): {}",
+            self.opcode, self.unique, self.offset, self.kind
+        )
     }
 }
 
 impl std::error::Error for DecodeError {}
 
+/// The reason of a failure in decoding a request message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecodeErrorKind {
+    /// The message ends in the middle of an argument.
+    UnexpectedEof,
+
+    /// A string argument is not terminated with NUL.
+    MissingNul,
+
+    /// A length field in the argument is inconsistent with the message.
+    BadLength,
+
+    /// An argument is not properly aligned in the buffer.
+    Unaligned,
+}
+
+impl From<crate::decoder::DecodeError> for DecodeErrorKind {
+    fn from(err: crate::decoder::DecodeError) -> Self {
+        use crate::decoder::DecodeError;
+        match err {
+            DecodeError::UnexpectedEof => Self::UnexpectedEof,
+            DecodeError::MissingNulCharacter => Self::MissingNul,
+            DecodeError::BadLength => Self::BadLength,
+            DecodeError::Unaligned => Self::Unaligned,
+        }
+    }
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => f.write_str("unexpected end of message"),
+            Self::MissingNul => f.write_str("missing NUL terminator"),
+            Self::BadLength => f.write_str("bad length"),
+            Self::Unaligned => f.write_str("unaligned argument"),
+        }
+    }
+}
+
+/// How the session handles the requests that fail to be decoded.
+///
+/// The policy is specified by `KernelConfig::decode_error_policy`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum DecodeErrorPolicy {
+    /// Return the requests from `Session::next_request` as they are, and let
+    /// `Request::operation` report the error. `fs::dispatch` replies to such requests
+    /// with `EIO`. This is the default policy.
+    #[default]
+    Return,
+
+    /// Reply to the requests with the error number, and skip them without
+    /// returning from `Session::next_request`.
+    ///
+    /// The requests that take no reply, such as `forget`, are skipped silently.
+    Reply(Errno),
+}
+
 /// The kind of filesystem operation requested by the kernel.
 #[non_exhaustive]
 pub enum Operation<'op, T> {
@@ -60,13 +154,16 @@ pub enum Operation<'op, T> {
     Fallocate(Fallocate<'op>),
     CopyFileRange(CopyFileRange<'op>),
     Poll(Poll<'op>),
+    Ioctl(Ioctl<'op>),
+    Lseek(Lseek<'op>),
 
+    Destroy(Destroy<'op>),
     Forget(Forgets<'op>),
     Interrupt(Interrupt<'op>),
     NotifyReply(NotifyReply<'op>, T),
 
-    #[doc(hidden)]
-    Unknown,
+    /// A request with an opcode not supported by this library.
+    Unknown(Unknown<'op>),
 }
 
 impl<T> fmt::Debug for Operation<'_, T>
@@ -109,8 +206,12 @@ where
             Operation::Fallocate(op) => op.fmt(f),
             Operation::CopyFileRange(op) => op.fmt(f),
             Operation::Poll(op) => op.fmt(f),
+            Operation::Ioctl(op) => op.fmt(f),
+            Operation::Lseek(op) => op.fmt(f),
+            Operation::Destroy(op) => op.fmt(f),
             Operation::Forget(op) => op.fmt(f),
             Operation::Interrupt(op) => op.fmt(f),
+            Operation::Unknown(op) => op.fmt(f),
 
             Operation::Write(op, data) => f
                 .debug_struct("Write")
@@ -122,16 +223,28 @@ where
                 .field("op", op)
                 .field("data", data)
                 .finish(),
-
-            _ => f.debug_struct("Unknown").finish(),
         }
     }
 }
 
 impl<'op, T> Operation<'op, T> {
     #[inline]
-    pub(crate) fn unknown() -> Self {
-        Self::Unknown
+    pub(crate) fn unknown(header: &'op fuse_in_header, arg: &'op [u8]) -> Self {
+        Self::Unknown(Unknown {
+            header,
+            arg,
+            exited: false,
+        })
+    }
+
+    /// Create an operation for a request received after the session has exited.
+    #[inline]
+    pub(crate) fn exited(header: &'op fuse_in_header, arg: &'op [u8]) -> Self {
+        Self::Unknown(Unknown {
+            header,
+            arg,
+            exited: true,
+        })
     }
 
     pub(crate) fn decode(
@@ -140,10 +253,25 @@ impl<'op, T> Operation<'op, T> {
         data: T,
     ) -> Result<Self, DecodeError> {
         let mut decoder = Decoder::new(arg);
+        Self::decode_arg(header, arg, &mut decoder, data).map_err(|err| DecodeError {
+            opcode: header.opcode,
+            unique: header.unique,
+            offset: mem::size_of::<fuse_in_header>() + decoder.offset(),
+            kind: err.into(),
+        })
+    }
 
+    fn decode_arg(
+        header: &'op fuse_in_header,
+        arg: &'op [u8],
+        decoder: &mut Decoder<'op>,
+        data: T,
+    ) -> Result<Self, crate::decoder::DecodeError> {
         match header.opcode {
+            FUSE_DESTROY => Ok(Operation::Destroy(Destroy { header })),
+
             FUSE_FORGET => {
-                let arg: &fuse_forget_in = decoder.fetch().map_err(DecodeError::new)?;
+                let arg: &fuse_forget_in = decoder.fetch()?;
                 let forget = fuse_forget_one {
                     nodeid: header.nodeid,
                     nlookup: arg.nlookup,
@@ -153,74 +281,72 @@ impl<'op, T> Operation<'op, T> {
                 }))
             }
             FUSE_BATCH_FORGET => {
-                let arg: &fuse_batch_forget_in = decoder.fetch().map_err(DecodeError::new)?;
-                let forgets = decoder
-                    .fetch_array::<fuse_forget_one>(arg.count as usize)
-                    .map_err(DecodeError::new)?;
+                let arg: &fuse_batch_forget_in = decoder.fetch()?;
+                let forgets = decoder.fetch_array::<fuse_forget_one>(arg.count as usize)?;
                 Ok(Operation::Forget(Forgets {
                     inner: ForgetsInner::Batch(forgets),
                 }))
             }
 
             FUSE_INTERRUPT => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Interrupt(Interrupt { header, arg }))
             }
 
             FUSE_NOTIFY_REPLY => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::NotifyReply(NotifyReply { header, arg }, data))
             }
 
             FUSE_LOOKUP => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Lookup(Lookup { header, name }))
             }
 
             FUSE_GETATTR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Getattr(Getattr { header, arg }))
             }
 
             FUSE_SETATTR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Setattr(Setattr { header, arg }))
             }
 
             FUSE_READLINK => Ok(Operation::Readlink(Readlink { header })),
 
             FUSE_SYMLINK => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                let link = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_str()?;
+                let link = decoder.fetch_str()?;
                 Ok(Operation::Symlink(Symlink { header, name, link }))
             }
 
             FUSE_MKNOD => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Mknod(Mknod { header, arg, name }))
             }
 
             FUSE_MKDIR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Mkdir(Mkdir { header, arg, name }))
             }
 
             FUSE_UNLINK => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Unlink(Unlink { header, name }))
             }
 
             FUSE_RMDIR => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Rmdir(Rmdir { header, name }))
             }
 
             FUSE_RENAME => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                let newname = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
+                let name = decoder.fetch_str()?;
+                let newname = decoder.fetch_str()?;
                 Ok(Operation::Rename(Rename {
                     header,
                     arg: RenameArg::V1(arg),
@@ -229,9 +355,9 @@ impl<'op, T> Operation<'op, T> {
                 }))
             }
             FUSE_RENAME2 => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                let newname = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
+                let name = decoder.fetch_str()?;
+                let newname = decoder.fetch_str()?;
                 Ok(Operation::Rename(Rename {
                     header,
                     arg: RenameArg::V2(arg),
@@ -241,8 +367,8 @@ impl<'op, T> Operation<'op, T> {
             }
 
             FUSE_LINK => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let newname = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
+                let newname = decoder.fetch_str()?;
                 Ok(Operation::Link(Link {
                     header,
                     arg,
@@ -251,40 +377,36 @@ impl<'op, T> Operation<'op, T> {
             }
 
             FUSE_OPEN => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Open(Open { header, arg }))
             }
 
             FUSE_READ => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Read(Read { header, arg }))
             }
 
             FUSE_WRITE => {
-                let arg: &fuse_write_in = decoder.fetch().map_err(DecodeError::new)?;
+                let arg: &fuse_write_in = decoder.fetch()?;
                 Ok(Operation::Write(Write { header, arg }, data))
             }
 
             FUSE_RELEASE => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Release(Release { header, arg }))
             }
 
             FUSE_STATFS => Ok(Operation::Statfs(Statfs { header })),
 
             FUSE_FSYNC => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Fsync(Fsync { header, arg }))
             }
 
             FUSE_SETXATTR => {
-                let arg = decoder
-                    .fetch::<fuse_setxattr_in>()
-                    .map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                let value = decoder
-                    .fetch_bytes(arg.size as usize)
-                    .map_err(DecodeError::new)?;
+                let arg = decoder.fetch::<fuse_setxattr_in>()?;
+                let name = decoder.fetch_str()?;
+                let value = decoder.fetch_bytes_by_len(arg.size as usize)?;
                 Ok(Operation::Setxattr(Setxattr {
                     header,
                     arg,
@@ -294,33 +416,33 @@ impl<'op, T> Operation<'op, T> {
             }
 
             FUSE_GETXATTR => {
-                let arg: &fuse_getxattr_in = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg: &fuse_getxattr_in = decoder.fetch()?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Getxattr(Getxattr { header, arg, name }))
             }
 
             FUSE_LISTXATTR => {
-                let arg: &fuse_getxattr_in = decoder.fetch().map_err(DecodeError::new)?;
+                let arg: &fuse_getxattr_in = decoder.fetch()?;
                 Ok(Operation::Listxattr(Listxattr { header, arg }))
             }
 
             FUSE_REMOVEXATTR => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Removexattr(Removexattr { header, name }))
             }
 
             FUSE_FLUSH => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Flush(Flush { header, arg }))
             }
 
             FUSE_OPENDIR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Opendir(Opendir { header, arg }))
             }
 
             FUSE_READDIR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Readdir(Readdir {
                     header,
                     arg,
@@ -328,7 +450,7 @@ impl<'op, T> Operation<'op, T> {
                 }))
             }
             FUSE_READDIRPLUS => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Readdir(Readdir {
                     header,
                     arg,
@@ -337,22 +459,22 @@ impl<'op, T> Operation<'op, T> {
             }
 
             FUSE_RELEASEDIR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Releasedir(Releasedir { header, arg }))
             }
 
             FUSE_FSYNCDIR => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Fsyncdir(Fsyncdir { header, arg }))
             }
 
             FUSE_GETLK => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Getlk(Getlk { header, arg }))
             }
 
             FUSE_SETLK | FUSE_SETLKW => {
-                let arg: &fuse_lk_in = decoder.fetch().map_err(DecodeError::new)?;
+                let arg: &fuse_lk_in = decoder.fetch()?;
                 let sleep = match header.opcode {
                     FUSE_SETLK => false,
                     FUSE_SETLKW => true,
@@ -368,39 +490,54 @@ impl<'op, T> Operation<'op, T> {
             }
 
             FUSE_ACCESS => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Access(Access { header, arg }))
             }
 
             FUSE_CREATE => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
+                let name = decoder.fetch_str()?;
                 Ok(Operation::Create(Create { header, arg, name }))
             }
 
             FUSE_BMAP => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Bmap(Bmap { header, arg }))
             }
 
             FUSE_FALLOCATE => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Fallocate(Fallocate { header, arg }))
             }
 
             FUSE_COPY_FILE_RANGE => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::CopyFileRange(CopyFileRange { header, arg }))
             }
 
             FUSE_POLL => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg = decoder.fetch()?;
                 Ok(Operation::Poll(Poll { header, arg }))
             }
 
+            FUSE_IOCTL => {
+                let arg: &fuse_ioctl_in = decoder.fetch()?;
+                let in_data = decoder.fetch_bytes_by_len(arg.in_size as usize)?;
+                Ok(Operation::Ioctl(Ioctl {
+                    header,
+                    arg,
+                    in_data,
+                }))
+            }
+
+            FUSE_LSEEK => {
+                let arg = decoder.fetch()?;
+                Ok(Operation::Lseek(Lseek { header, arg }))
+            }
+
             _ => {
-                tracing::warn!("unsupported opcode: {}", header.opcode);
-                Ok(Operation::Unknown)
+                tracing::debug!("unsupported opcode: {}", header.opcode);
+                Ok(Operation::unknown(header, arg))
             }
         }
     }
@@ -425,6 +562,59 @@ fn convert_to_flock_op(lk_type: u32, sleep: bool) -> Option<u32> {
     Some(op)
 }
 
+/// A request with an opcode not supported by this library.
+///
+/// The raw header and argument are exposed so that the newer opcodes of the kernel,
+/// e.g. `FUSE_SYNCFS` or `FUSE_STATX`, can be handled before they are supported.
+/// The argument of `write`-like requests includes the payload, unless it is moved
+/// into a pipe by `KernelConfig::splice_read`.
+///
+/// After the session has exited, i.e. the `Session` has been dropped, the remaining
+/// requests are no longer decoded and are all returned as `Unknown`, even if their
+/// opcodes are supported. Such requests are distinguished by `is_exited`.
+pub struct Unknown<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op [u8],
+    exited: bool,
+}
+
+impl fmt::Debug for Unknown<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Unknown")
+            .field("opcode", &self.header.opcode)
+            .field("arg_len", &self.arg.len())
+            .field("exited", &self.exited)
+            .finish()
+    }
+}
+
+impl<'op> Unknown<'op> {
+    /// Return the opcode of the request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Return the header of the request.
+    #[inline]
+    pub fn header(&self) -> &'op fuse_in_header {
+        self.header
+    }
+
+    /// Return the raw bytes of the argument following the header.
+    #[inline]
+    pub fn arg(&self) -> &'op [u8] {
+        self.arg
+    }
+
+    /// Return whether the request has been received after the session exited,
+    /// rather than having an unsupported opcode.
+    #[inline]
+    pub fn is_exited(&self) -> bool {
+        self.exited
+    }
+}
+
 /// The identifier for locking operations.
 #[repr(transparent)]
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
@@ -450,6 +640,27 @@ impl LockOwner {
     }
 }
 
+/// Clean up the filesystem.
+///
+/// This is the last request sent by the kernel before the connection is
+/// closed, and the filesystem should write back all dirty state (such as
+/// buffered data in the write-back caches) before replying to it.
+/// The reply is done with an empty payload, e.g. `req.reply(())`, and any
+/// requests are not received from the session after this request.
+///
+/// Note that the kernel sends this request only for the specific mounts
+/// (e.g. `fuseblk`).  In other cases, the end of the session is notified
+/// when `Session::next_request` returns `None`.
+pub struct Destroy<'op> {
+    header: &'op fuse_in_header,
+}
+
+impl fmt::Debug for Destroy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Destroy").finish()
+    }
+}
+
 /// A set of forget information removed from the kernel's internal caches.
 pub struct Forgets<'op> {
     inner: ForgetsInner<'op>,
@@ -1897,6 +2108,7 @@ impl<'op> Create<'op> {
     /// Return the open flags.
     ///
     /// This is the same as `Open::flags`.
+    #[allow(clippy::misnamed_getters)]
     #[inline]
     pub fn open_flags(&self) -> u32 {
         self.arg.flags
@@ -2117,3 +2329,295 @@ impl<'op> Poll<'op> {
         }
     }
 }
+
+/// Control a device.
+///
+/// The result of `ioctl(2)` and the output data are replied using `IoctlOut`.
+///
+/// By default, the kernel issues the ioctl requests in the *restricted* mode,
+/// where the sizes of input/output data are determined from the encoded command
+/// number and the input data is already copied in `in_data`.  When the request
+/// is *unrestricted* (e.g. on CUSE devices), the filesystem is responsible for
+/// resolving the memory regions from `arg` and asking the kernel to retry the
+/// request with the appropriate iovecs through `IoctlOut::in_iov` and
+/// `IoctlOut::out_iov`.
+pub struct Ioctl<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_ioctl_in,
+    in_data: &'op [u8],
+}
+
+impl fmt::Debug for Ioctl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ioctl")
+            .field("ino", &self.ino())
+            .field("fh", &self.fh())
+            .field("flags", &self.flags())
+            .field("cmd", &self.cmd())
+            .field("arg", &self.arg())
+            .field("in_size", &self.in_size())
+            .field("out_size", &self.out_size())
+            .finish()
+    }
+}
+
+impl<'op> Ioctl<'op> {
+    /// Return the target inode number.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of opened file.
+    #[inline]
+    pub fn fh(&self) -> u64 {
+        self.arg.fh
+    }
+
+    /// Return the flags of this request.
+    ///
+    /// The value is a combination of `FUSE_IOCTL_*` flags.
+    #[inline]
+    pub fn flags(&self) -> u32 {
+        self.arg.flags
+    }
+
+    /// Return whether the request was issued by a 32-bit compat syscall.
+    #[inline]
+    pub fn compat(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_COMPAT != 0
+    }
+
+    /// Return whether the request is in the unrestricted mode.
+    ///
+    /// The retry with `IoctlOut::in_iov` and `IoctlOut::out_iov` is
+    /// allowed only if this method returns `true`.
+    #[inline]
+    pub fn unrestricted(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_UNRESTRICTED != 0
+    }
+
+    /// Return whether the request is a retried one.
+    #[inline]
+    pub fn retried(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_RETRY != 0
+    }
+
+    /// Return whether the target inode is a directory.
+    ///
+    /// This flag is set only if `KernelConfig::ioctl_dir` is enabled.
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_DIR != 0
+    }
+
+    /// Return the command number.
+    #[inline]
+    pub fn cmd(&self) -> u32 {
+        self.arg.cmd
+    }
+
+    /// Return the raw argument value passed to `ioctl(2)`.
+    ///
+    /// In the unrestricted mode, this value is typically the address
+    /// of the caller's memory.
+    #[inline]
+    pub fn arg(&self) -> u64 {
+        self.arg.arg
+    }
+
+    /// Return the length of input data.
+    #[inline]
+    pub fn in_size(&self) -> u32 {
+        self.arg.in_size
+    }
+
+    /// Return the maximum length of output data to be replied.
+    #[inline]
+    pub fn out_size(&self) -> u32 {
+        self.arg.out_size
+    }
+
+    /// Return the input data.
+    ///
+    /// When the request is retried, the data of all regions specified
+    /// by `IoctlOut::in_iov` are concatenated in order.
+    #[inline]
+    pub fn in_data(&self) -> &'op [u8] {
+        self.in_data
+    }
+}
+
+/// Reposition the offset of an opened file.
+///
+/// The kernel issues this request only for `SEEK_DATA` and `SEEK_HOLE`,
+/// which are required to handle sparse files efficiently.  The resulting
+/// offset must be replied using `LseekOut`.
+///
+/// If the filesystem returns an `ENOSYS` error, the kernel falls back to
+/// the generic implementation and does not send subsequent `lseek` requests.
+pub struct Lseek<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_lseek_in,
+}
+
+impl fmt::Debug for Lseek<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lseek")
+            .field("ino", &self.ino())
+            .field("fh", &self.fh())
+            .field("offset", &self.offset())
+            .field("whence", &self.whence())
+            .finish()
+    }
+}
+
+impl<'op> Lseek<'op> {
+    /// Return the inode number to be repositioned.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of opened file.
+    #[inline]
+    pub fn fh(&self) -> u64 {
+        self.arg.fh
+    }
+
+    /// Return the offset to start seeking.
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.arg.offset
+    }
+
+    /// Return the seeking mode.
+    ///
+    /// If the kernel sends an unknown value, this method returns `None`.
+    #[inline]
+    pub fn whence(&self) -> Option<Whence> {
+        Whence::from_raw(self.arg.whence)
+    }
+}
+
+/// The seeking mode of `Lseek`.
+///
+/// See [`lseek(2)`][lseek] for details.
+///
+/// [lseek]: http://man7.org/linux/man-pages/man2/lseek.2.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Whence {
+    /// The offset is an absolute position (`SEEK_SET`).
+    Set,
+
+    /// The offset is relative to the current position (`SEEK_CUR`).
+    Current,
+
+    /// The offset is relative to the end of file (`SEEK_END`).
+    End,
+
+    /// Seek to the next region containing data at or after the offset (`SEEK_DATA`).
+    Data,
+
+    /// Seek to the next hole at or after the offset (`SEEK_HOLE`).
+    Hole,
+}
+
+impl Whence {
+    #[inline]
+    fn from_raw(whence: u32) -> Option<Self> {
+        match whence as i32 {
+            libc::SEEK_SET => Some(Whence::Set),
+            libc::SEEK_CUR => Some(Whence::Current),
+            libc::SEEK_END => Some(Whence::End),
+            libc::SEEK_DATA => Some(Whence::Data),
+            libc::SEEK_HOLE => Some(Whence::Hole),
+            _ => None,
+        }
+    }
+}
+
+/// Ensure that the reply handle is created for the request of the operation, since
+/// a handle for another request would reply to it with a mismatched type.
+fn check_request(req: &Request, header: &fuse_in_header) {
+    assert_eq!(
+        req.unique(),
+        header.unique,
+        "the request does not match the operation"
+    );
+}
+
+macro_rules! impl_reply_handle {
+    ($($Op:ident => $Reply:ident,)*) => {$(
+        impl<'op> $Op<'op> {
+            /// Create a handle to reply to the request of this operation.
+            ///
+            /// The handle accepts only the type of reply expected by the kernel.
+            ///
+            /// # Panics
+            /// Panics if `req` is not the request of this operation.
+            #[inline]
+            pub fn reply_handle(&self, req: &Request) -> $Reply {
+                check_request(req, self.header);
+                ReplyHandle::new(req)
+            }
+        }
+    )*};
+}
+
+impl_reply_handle! {
+    Destroy => EmptyReply,
+    Lookup => EntryReply,
+    Getattr => AttrReply,
+    Setattr => AttrReply,
+    Readlink => DataReply,
+    Symlink => EntryReply,
+    Mknod => EntryReply,
+    Mkdir => EntryReply,
+    Unlink => EmptyReply,
+    Rmdir => EmptyReply,
+    Rename => EmptyReply,
+    Link => EntryReply,
+    Open => OpenReply,
+    Read => DataReply,
+    Write => WriteReply,
+    Release => EmptyReply,
+    Statfs => StatfsReply,
+    Fsync => EmptyReply,
+    Setxattr => EmptyReply,
+    Getxattr => XattrReply,
+    Listxattr => XattrReply,
+    Removexattr => EmptyReply,
+    Flush => EmptyReply,
+    Opendir => OpenReply,
+    Releasedir => EmptyReply,
+    Fsyncdir => EmptyReply,
+    Getlk => LkReply,
+    Setlk => EmptyReply,
+    Flock => EmptyReply,
+    Access => EmptyReply,
+    Create => CreateReply,
+    Bmap => BmapReply,
+    Fallocate => EmptyReply,
+    CopyFileRange => WriteReply,
+    Poll => PollReply,
+    Ioctl => IoctlReply,
+    Lseek => LseekReply,
+}
+
+impl<'op> Readdir<'op> {
+    /// Create a handle to reply to the request of this operation.
+    ///
+    /// The type of the handle depends on the mode of this operation.
+    ///
+    /// # Panics
+    /// Panics if `req` is not the request of this operation.
+    pub fn reply_handle(&self, req: &Request) -> ReaddirReply {
+        check_request(req, self.header);
+        match self.mode {
+            ReaddirMode::Normal => ReaddirReply::Normal(ReplyHandle::new(req)),
+            ReaddirMode::Plus => ReaddirReply::Plus(ReplyHandle::new(req)),
+        }
+    }
+}
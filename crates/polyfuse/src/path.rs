@@ -0,0 +1,824 @@
+//! Path-based high-level interface on top of the inode-based protocol.
+//!
+//! `PathFs` resolves the inode numbers in the requests into paths and calls the
+//! corresponding methods of `PathFilesystem`, in a similar way to the high-level
+//! API of libfuse. The paths are relative to the root of the filesystem, and the
+//! root itself is represented by an empty path.
+
+use crate::{
+    errno::Errno,
+    fs::Filesystem,
+    inode::InodeTable,
+    op,
+    reply::{AttrOut, EntryOut, FileAttr, OpenOut},
+    session::Request,
+};
+use bytes::Bytes;
+use polyfuse_kernel::FUSE_ROOT_ID;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// A filesystem that handles the requests by path.
+///
+/// The methods that take `&mut FileAttr` or affect the directory entries return the
+/// results, which are replied by `PathFs` along with the updates of the inode table.
+/// The attributes are filled by the filesystem except for the inode number, which
+/// is assigned by `PathFs`. If an `Err` is returned, the request is replied with the
+/// OS error code of the error, or `EIO` if it has none.
+///
+/// The other methods reply to the requests by themselves. The default implementations
+/// reply `ENOSYS` or return the error of `ENOSYS`.
+///
+/// The path of an inode is the one at the last time observed by `PathFs`. Once all the
+/// entries of an inode have been unlinked or replaced by `rename`, its old path may
+/// refer to another file, so the requests for that inode are replied with `ESTALE`
+/// without calling the methods, except for `flush`, `release` and `releasedir`, which
+/// receive `None` as the path so that the opened handles can be closed.
+#[allow(unused_variables)]
+pub trait PathFilesystem {
+    /// Get file attributes.
+    ///
+    /// This method is also called for looking up the directory entries, in which case
+    /// `fh` is `None`. The error is replied as is, so a lookup failed with `ENOENT` is
+    /// not cached by the kernel as a negative entry.
+    fn getattr(
+        &self,
+        req: &Request,
+        path: &Path,
+        fh: Option<u64>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Set file attributes.
+    fn setattr(
+        &self,
+        req: &Request,
+        path: &Path,
+        op: op::Setattr<'_>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a file node.
+    fn mknod(
+        &self,
+        req: &Request,
+        path: &Path,
+        op: op::Mknod<'_>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a directory node.
+    fn mkdir(
+        &self,
+        req: &Request,
+        path: &Path,
+        op: op::Mkdir<'_>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a symbolic link.
+    fn symlink(
+        &self,
+        req: &Request,
+        path: &Path,
+        op: op::Symlink<'_>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a hard link to `path` at `newpath`.
+    fn link(
+        &self,
+        req: &Request,
+        path: &Path,
+        newpath: &Path,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Remove a file.
+    fn unlink(&self, req: &Request, path: &Path) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Remove a directory.
+    fn rmdir(&self, req: &Request, path: &Path) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Rename a file.
+    ///
+    /// `flags` may contain `RENAME_NOREPLACE` or `RENAME_EXCHANGE`.
+    fn rename(&self, req: &Request, path: &Path, newpath: &Path, flags: u32) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create and open a file.
+    fn create(
+        &self,
+        req: &Request,
+        path: &Path,
+        op: op::Create<'_>,
+        attr: &mut FileAttr,
+        open: &mut OpenOut,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Read a symbolic link.
+    fn readlink(&self, req: &Request, path: &Path, op: op::Readlink<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Open a file.
+    fn open(&self, req: &Request, path: &Path, op: op::Open<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Read data from a file.
+    fn read(&self, req: &Request, path: &Path, op: op::Read<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Write data to a file.
+    fn write(&self, req: &Request, path: &Path, op: op::Write<'_>, data: Bytes) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Close a file descriptor.
+    ///
+    /// `path` is `None` if the file has been removed.
+    fn flush(&self, req: &Request, path: Option<&Path>, op: op::Flush<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Release an opened file.
+    ///
+    /// `path` is `None` if the file has been removed.
+    fn release(&self, req: &Request, path: Option<&Path>, op: op::Release<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Synchronize the file contents.
+    fn fsync(&self, req: &Request, path: &Path, op: op::Fsync<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Open a directory.
+    fn opendir(&self, req: &Request, path: &Path, op: op::Opendir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Read contents from an opened directory.
+    fn readdir(&self, req: &Request, path: &Path, op: op::Readdir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Release an opened directory.
+    ///
+    /// `path` is `None` if the directory has been removed.
+    fn releasedir(
+        &self,
+        req: &Request,
+        path: Option<&Path>,
+        op: op::Releasedir<'_>,
+    ) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Synchronize the directory contents.
+    fn fsyncdir(&self, req: &Request, path: &Path, op: op::Fsyncdir<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Get the filesystem statistics.
+    fn statfs(&self, req: &Request, path: &Path, op: op::Statfs<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(&self, req: &Request, path: &Path, op: op::Setxattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Get an extended attribute.
+    fn getxattr(&self, req: &Request, path: &Path, op: op::Getxattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// List extended attribute names.
+    fn listxattr(&self, req: &Request, path: &Path, op: op::Listxattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&self, req: &Request, path: &Path, op: op::Removexattr<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    fn access(&self, req: &Request, path: &Path, op: op::Access<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Allocate requested space.
+    fn fallocate(&self, req: &Request, path: &Path, op: op::Fallocate<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+
+    /// Reposition the offset of an opened file.
+    fn lseek(&self, req: &Request, path: &Path, op: op::Lseek<'_>) -> io::Result<()> {
+        req.reply_error(libc::ENOSYS)
+    }
+}
+
+/// An adapter that translates the inode-based requests into the calls of `PathFilesystem`.
+///
+/// The inodes are assigned to the paths looked up by the kernel, and the paths are
+/// updated by the successful `rename` requests. The hard links created by `link` share
+/// the same inode, while the existing hard links in the underlying storage are looked up
+/// as distinct inodes.
+pub struct PathFs<F> {
+    fs: F,
+    inodes: InodeTable<()>,
+    names: Mutex<Names>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+}
+
+impl<F> fmt::Debug for PathFs<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathFs")
+            .field("inodes", &self.inodes)
+            .field("entry_ttl", &self.entry_ttl)
+            .field("attr_ttl", &self.attr_ttl)
+            .finish()
+    }
+}
+
+/// The directory entries pointing to the inodes.
+#[derive(Default)]
+struct Names {
+    /// The pairs of parent inode and name for each inode, the first of which is used as
+    /// the path. The inodes whose links have all been removed are kept with no links
+    /// until they are forgotten.
+    links: HashMap<u64, Vec<(u64, OsString)>>,
+    children: HashMap<(u64, OsString), u64>,
+}
+
+impl Names {
+    /// Resolve the path of an inode.
+    ///
+    /// Return `ENOENT` for the unknown inodes, and `ESTALE` for the inodes that are no
+    /// longer reachable from the root.
+    fn resolve(&self, ino: u64) -> Result<PathBuf, Errno> {
+        let mut names = vec![];
+        let mut ino = ino;
+        while ino != FUSE_ROOT_ID {
+            let links = self.links.get(&ino).ok_or(Errno::ENOENT)?;
+            let (parent, name) = links.first().ok_or(Errno::ESTALE)?;
+            names.push(name.as_os_str());
+            ino = *parent;
+        }
+        Ok(names.into_iter().rev().collect())
+    }
+
+    fn add_link(&mut self, ino: u64, parent: u64, name: &OsStr) {
+        self.links
+            .entry(ino)
+            .or_default()
+            .push((parent, name.to_owned()));
+        self.children.insert((parent, name.to_owned()), ino);
+    }
+
+    fn remove_link(&mut self, parent: u64, name: &OsStr) -> Option<u64> {
+        let key = (parent, name.to_owned());
+        let ino = self.children.remove(&key)?;
+        if let Some(links) = self.links.get_mut(&ino) {
+            links.retain(|link| *link != key);
+        }
+        Some(ino)
+    }
+
+    fn remove_inode(&mut self, ino: u64) {
+        for link in self.links.remove(&ino).into_iter().flatten() {
+            if self.children.get(&link) == Some(&ino) {
+                self.children.remove(&link);
+            }
+        }
+    }
+}
+
+macro_rules! try_path {
+    ($self:expr, $req:expr, $ino:expr) => {
+        match $self.resolve($ino) {
+            Ok(path) => path,
+            Err(errno) => return $req.reply_error(errno.raw()),
+        }
+    };
+}
+
+/// Resolve the path of an opened file, or `None` if the file has been removed.
+macro_rules! try_opened_path {
+    ($self:expr, $req:expr, $ino:expr) => {
+        match $self.resolve($ino) {
+            Ok(path) => Some(path),
+            Err(Errno::ESTALE) => None,
+            Err(errno) => return $req.reply_error(errno.raw()),
+        }
+    };
+}
+
+impl<F> PathFs<F>
+where
+    F: PathFilesystem,
+{
+    /// Create an adapter for the path-based filesystem.
+    pub fn new(fs: F) -> Self {
+        Self {
+            fs,
+            inodes: InodeTable::new(()),
+            names: Mutex::default(),
+            entry_ttl: Duration::from_secs(1),
+            attr_ttl: Duration::from_secs(1),
+        }
+    }
+
+    /// Specify the validity timeout for the names of the entries.
+    ///
+    /// The default value is 1 second.
+    pub fn entry_timeout(&mut self, ttl: Duration) -> &mut Self {
+        self.entry_ttl = ttl;
+        self
+    }
+
+    /// Specify the validity timeout for the attributes of the inodes.
+    ///
+    /// The default value is 1 second.
+    pub fn attr_timeout(&mut self, ttl: Duration) -> &mut Self {
+        self.attr_ttl = ttl;
+        self
+    }
+
+    /// Return the reference to the underlying filesystem.
+    pub fn get_ref(&self) -> &F {
+        &self.fs
+    }
+
+    /// Return the path of an inode.
+    ///
+    /// `None` is returned if the inode is unknown or has been removed.
+    pub fn path(&self, ino: u64) -> Option<PathBuf> {
+        self.resolve(ino).ok()
+    }
+
+    fn resolve(&self, ino: u64) -> Result<PathBuf, Errno> {
+        self.names.lock().unwrap().resolve(ino)
+    }
+
+    /// Register the entry and increment its lookup count.
+    fn acquire_entry(&self, parent: u64, name: &OsStr, ino: Option<u64>) -> Option<(u64, u64)> {
+        let mut names = self.names.lock().unwrap();
+        let key = (parent, name.to_owned());
+        let ino = match ino.or_else(|| names.children.get(&key).copied()) {
+            Some(ino) => ino,
+            None => self.inodes.insert(()),
+        };
+        let generation = self.inodes.acquire(ino)?;
+        if names.children.get(&key) != Some(&ino) {
+            names.add_link(ino, parent, name);
+        }
+        Some((ino, generation))
+    }
+
+    fn release(&self, ino: u64, nlookup: u64) {
+        let mut names = self.names.lock().unwrap();
+        if self.inodes.release(ino, nlookup).is_some() {
+            names.remove_inode(ino);
+        }
+    }
+
+    /// Reply to a request with the entry filled by `f`.
+    fn reply_entry<G>(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        ino: Option<u64>,
+        f: G,
+    ) -> io::Result<()>
+    where
+        G: FnOnce(&Path, &mut EntryOut) -> io::Result<()>,
+    {
+        let path = try_path!(self, req, parent).join(name);
+        let mut out = EntryOut::default();
+        if let Err(err) = f(&path, &mut out) {
+            return reply_io_error(req, err);
+        }
+        out.ttl_entry(self.entry_ttl);
+        out.ttl_attr(self.attr_ttl);
+
+        let (ino, generation) = match self.acquire_entry(parent, name, ino) {
+            Some(entry) => entry,
+            None => return req.reply_error(libc::ENOENT),
+        };
+        out.ino(ino);
+        out.generation(generation);
+        out.attr().ino(ino);
+        self.reply_acquired(ino, req.reply(out))
+    }
+
+    fn reply_acquired(&self, ino: u64, res: io::Result<()>) -> io::Result<()> {
+        if res.is_err() {
+            self.release(ino, 1);
+        }
+        res
+    }
+
+    fn reply_attr<G>(&self, req: &Request, ino: u64, f: G) -> io::Result<()>
+    where
+        G: FnOnce(&mut FileAttr) -> io::Result<()>,
+    {
+        let mut out = AttrOut::default();
+        if let Err(err) = f(out.attr()) {
+            return reply_io_error(req, err);
+        }
+        out.attr().ino(ino);
+        out.ttl(self.attr_ttl);
+        req.reply(out)
+    }
+}
+
+fn reply_io_error(req: &Request, err: io::Error) -> io::Result<()> {
+    req.reply_error(Errno::from(err).raw())
+}
+
+impl<F> Filesystem for PathFs<F>
+where
+    F: PathFilesystem,
+{
+    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+        self.reply_entry(req, op.parent(), op.name(), None, |path, out| {
+            self.fs.getattr(req, path, None, out.attr())
+        })
+    }
+
+    fn forget(&self, _: &Request, forgets: op::Forgets<'_>) -> io::Result<()> {
+        for forget in forgets.as_ref() {
+            self.release(forget.ino(), forget.nlookup());
+        }
+        Ok(())
+    }
+
+    fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.reply_attr(req, op.ino(), |attr| {
+            self.fs.getattr(req, &path, op.fh(), attr)
+        })
+    }
+
+    fn setattr(&self, req: &Request, op: op::Setattr<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        let ino = op.ino();
+        self.reply_attr(req, ino, |attr| self.fs.setattr(req, &path, op, attr))
+    }
+
+    fn mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<()> {
+        let (parent, name) = (op.parent(), op.name().to_owned());
+        self.reply_entry(req, parent, &name, None, |path, out| {
+            self.fs.mknod(req, path, op, out.attr())
+        })
+    }
+
+    fn mkdir(&self, req: &Request, op: op::Mkdir<'_>) -> io::Result<()> {
+        let (parent, name) = (op.parent(), op.name().to_owned());
+        self.reply_entry(req, parent, &name, None, |path, out| {
+            self.fs.mkdir(req, path, op, out.attr())
+        })
+    }
+
+    fn symlink(&self, req: &Request, op: op::Symlink<'_>) -> io::Result<()> {
+        let (parent, name) = (op.parent(), op.name().to_owned());
+        self.reply_entry(req, parent, &name, None, |path, out| {
+            self.fs.symlink(req, path, op, out.attr())
+        })
+    }
+
+    fn link(&self, req: &Request, op: op::Link<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.reply_entry(
+            req,
+            op.newparent(),
+            op.newname(),
+            Some(op.ino()),
+            |newpath, out| self.fs.link(req, &path, newpath, out.attr()),
+        )
+    }
+
+    fn unlink(&self, req: &Request, op: op::Unlink<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.parent()).join(op.name());
+        if let Err(err) = self.fs.unlink(req, &path) {
+            return reply_io_error(req, err);
+        }
+        self.names
+            .lock()
+            .unwrap()
+            .remove_link(op.parent(), op.name());
+        req.reply(())
+    }
+
+    fn rmdir(&self, req: &Request, op: op::Rmdir<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.parent()).join(op.name());
+        if let Err(err) = self.fs.rmdir(req, &path) {
+            return reply_io_error(req, err);
+        }
+        self.names
+            .lock()
+            .unwrap()
+            .remove_link(op.parent(), op.name());
+        req.reply(())
+    }
+
+    fn rename(&self, req: &Request, op: op::Rename<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.parent()).join(op.name());
+        let newpath = try_path!(self, req, op.newparent()).join(op.newname());
+        if let Err(err) = self.fs.rename(req, &path, &newpath, op.flags()) {
+            return reply_io_error(req, err);
+        }
+
+        let mut names = self.names.lock().unwrap();
+        let src = names.remove_link(op.parent(), op.name());
+        let dst = names.remove_link(op.newparent(), op.newname());
+        if let Some(ino) = src {
+            names.add_link(ino, op.newparent(), op.newname());
+        }
+        if op.flags() & libc::RENAME_EXCHANGE != 0 {
+            if let Some(ino) = dst {
+                names.add_link(ino, op.parent(), op.name());
+            }
+        }
+        drop(names);
+
+        req.reply(())
+    }
+
+    fn create(&self, req: &Request, op: op::Create<'_>) -> io::Result<()> {
+        let (parent, name) = (op.parent(), op.name().to_owned());
+        let path = try_path!(self, req, parent).join(&name);
+        let mut entry = EntryOut::default();
+        let mut open = OpenOut::default();
+        if let Err(err) = self.fs.create(req, &path, op, entry.attr(), &mut open) {
+            return reply_io_error(req, err);
+        }
+        entry.ttl_entry(self.entry_ttl);
+        entry.ttl_attr(self.attr_ttl);
+
+        let (ino, generation) = self
+            .acquire_entry(parent, &name, None)
+            .unwrap_or_else(|| unreachable!());
+        entry.ino(ino);
+        entry.generation(generation);
+        entry.attr().ino(ino);
+        self.reply_acquired(ino, req.reply((entry, open)))
+    }
+
+    fn readlink(&self, req: &Request, op: op::Readlink<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.readlink(req, &path, op)
+    }
+
+    fn open(&self, req: &Request, op: op::Open<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.open(req, &path, op)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.read(req, &path, op)
+    }
+
+    fn write(&self, req: &Request, op: op::Write<'_>, data: Bytes) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.write(req, &path, op, data)
+    }
+
+    fn flush(&self, req: &Request, op: op::Flush<'_>) -> io::Result<()> {
+        let path = try_opened_path!(self, req, op.ino());
+        self.fs.flush(req, path.as_deref(), op)
+    }
+
+    fn release(&self, req: &Request, op: op::Release<'_>) -> io::Result<()> {
+        let path = try_opened_path!(self, req, op.ino());
+        self.fs.release(req, path.as_deref(), op)
+    }
+
+    fn fsync(&self, req: &Request, op: op::Fsync<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.fsync(req, &path, op)
+    }
+
+    fn opendir(&self, req: &Request, op: op::Opendir<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.opendir(req, &path, op)
+    }
+
+    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.readdir(req, &path, op)
+    }
+
+    fn releasedir(&self, req: &Request, op: op::Releasedir<'_>) -> io::Result<()> {
+        let path = try_opened_path!(self, req, op.ino());
+        self.fs.releasedir(req, path.as_deref(), op)
+    }
+
+    fn fsyncdir(&self, req: &Request, op: op::Fsyncdir<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.fsyncdir(req, &path, op)
+    }
+
+    fn statfs(&self, req: &Request, op: op::Statfs<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.statfs(req, &path, op)
+    }
+
+    fn setxattr(&self, req: &Request, op: op::Setxattr<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.setxattr(req, &path, op)
+    }
+
+    fn getxattr(&self, req: &Request, op: op::Getxattr<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.getxattr(req, &path, op)
+    }
+
+    fn listxattr(&self, req: &Request, op: op::Listxattr<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.listxattr(req, &path, op)
+    }
+
+    fn removexattr(&self, req: &Request, op: op::Removexattr<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.removexattr(req, &path, op)
+    }
+
+    fn access(&self, req: &Request, op: op::Access<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.access(req, &path, op)
+    }
+
+    fn fallocate(&self, req: &Request, op: op::Fallocate<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.fallocate(req, &path, op)
+    }
+
+    fn lseek(&self, req: &Request, op: op::Lseek<'_>) -> io::Result<()> {
+        let path = try_path!(self, req, op.ino());
+        self.fs.lseek(req, &path, op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fs, session::testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use zerocopy::AsBytes as _;
+
+    struct Tree;
+
+    impl PathFilesystem for Tree {
+        fn getattr(
+            &self,
+            _: &Request,
+            _: &Path,
+            _: Option<u64>,
+            attr: &mut FileAttr,
+        ) -> io::Result<()> {
+            attr.mode(libc::S_IFDIR | 0o755);
+            Ok(())
+        }
+
+        fn link(&self, _: &Request, _: &Path, _: &Path, _: &mut FileAttr) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn unlink(&self, _: &Request, _: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn rename(&self, _: &Request, _: &Path, _: &Path, _: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn release(
+            &self,
+            req: &Request,
+            path: Option<&Path>,
+            _: op::Release<'_>,
+        ) -> io::Result<()> {
+            match path {
+                Some(..) => req.reply_error(libc::EINVAL),
+                None => req.reply(()),
+            }
+        }
+    }
+
+    #[test]
+    fn track_renames_and_links() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let fs = PathFs::new(Tree);
+        let mut unique = 0;
+        let mut request = |opcode, nodeid, arg: &[&[u8]]| {
+            unique += 2;
+            kernel.send(opcode, unique, nodeid, arg);
+            fs::dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+            let (header, arg) = kernel.recv().unwrap();
+            assert_eq!(header.error, 0);
+            arg
+        };
+
+        request(FUSE_LOOKUP, FUSE_ROOT_ID, &[b"d\0"]);
+        request(FUSE_LOOKUP, 2, &[b"f\0"]);
+        assert_eq!(fs.path(3).unwrap(), Path::new("d/f"));
+
+        let rename_in = fuse_rename_in {
+            newdir: FUSE_ROOT_ID,
+        };
+        request(
+            FUSE_RENAME,
+            FUSE_ROOT_ID,
+            &[rename_in.as_bytes(), b"d\0", b"e\0"],
+        );
+        assert_eq!(fs.path(3).unwrap(), Path::new("e/f"));
+
+        let link_in = fuse_link_in { oldnodeid: 3 };
+        let arg = request(FUSE_LINK, FUSE_ROOT_ID, &[link_in.as_bytes(), b"g\0"]);
+        let mut out = fuse_entry_out::default();
+        out.as_bytes_mut().copy_from_slice(&arg);
+        assert_eq!((out.nodeid, out.attr.ino), (3, 3));
+
+        request(FUSE_UNLINK, 2, &[b"f\0"]);
+        assert_eq!(fs.path(3).unwrap(), Path::new("g"));
+
+        let forget_in = fuse_forget_in { nlookup: 2 };
+        kernel.send(FUSE_FORGET, 100, 3, &[forget_in.as_bytes()]);
+        fs::dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+        assert!(fs.path(3).is_none());
+        assert_eq!(fs.path(2).unwrap(), Path::new("e"));
+    }
+
+    #[test]
+    fn stale_removed_inodes() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let fs = PathFs::new(Tree);
+        let mut unique = 0;
+        let mut request = |opcode, nodeid, arg: &[&[u8]]| {
+            unique += 2;
+            kernel.send(opcode, unique, nodeid, arg);
+            fs::dispatch(&fs, &session.next_request().unwrap().unwrap()).unwrap();
+            kernel.recv().unwrap().0.error
+        };
+
+        assert_eq!(request(FUSE_LOOKUP, FUSE_ROOT_ID, &[b"a\0"]), 0);
+        assert_eq!(request(FUSE_LOOKUP, FUSE_ROOT_ID, &[b"b\0"]), 0);
+
+        // "b" is replaced with "a" and no longer refers to the inode 3.
+        let rename_in = fuse_rename_in {
+            newdir: FUSE_ROOT_ID,
+        };
+        let arg: &[&[u8]] = &[rename_in.as_bytes(), b"a\0", b"b\0"];
+        assert_eq!(request(FUSE_RENAME, FUSE_ROOT_ID, arg), 0);
+        assert_eq!(fs.path(2).unwrap(), Path::new("b"));
+        assert!(fs.path(3).is_none());
+
+        let getattr_in = fuse_getattr_in::default();
+        let arg: &[&[u8]] = &[getattr_in.as_bytes()];
+        assert_eq!(request(FUSE_GETATTR, 3, arg), -libc::ESTALE);
+        assert_eq!(request(FUSE_GETATTR, 2, arg), 0);
+        assert_eq!(request(FUSE_GETATTR, 4, arg), -libc::ENOENT);
+
+        assert_eq!(request(FUSE_UNLINK, FUSE_ROOT_ID, &[b"b\0"]), 0);
+        assert_eq!(request(FUSE_GETATTR, 2, arg), -libc::ESTALE);
+
+        // The removed files are still released without the path.
+        let release_in = fuse_release_in::default();
+        assert_eq!(request(FUSE_RELEASE, 2, &[release_in.as_bytes()]), 0);
+    }
+}
@@ -0,0 +1,468 @@
+//! A blocking runtime that handles the requests on multiple worker threads.
+
+use crate::{
+    fs,
+    session::{Request, Session},
+};
+use std::{
+    io,
+    os::unix::prelude::*,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, Scope},
+};
+
+/// A pool of worker threads that receive the requests from a session concurrently.
+///
+/// The pool starts with `num_threads` workers. When all the workers are busy in
+/// handling the requests, a new worker is spawned until the number of workers reaches
+/// `max_threads`. A worker exits after completing a request if the number of idle
+/// workers exceeds `max_idle_threads`, in the same way as the multi-threaded loop of
+/// libfuse.
+///
+/// Multiple workers make sense only if the kernel sends the requests concurrently,
+/// e.g. with `KernelConfig::async_read` and `KernelConfig::parallel_dirops`.
+#[derive(Debug, Clone)]
+pub struct WorkerPool {
+    num_threads: usize,
+    max_threads: usize,
+    max_idle_threads: usize,
+    stop: StopHandle,
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self {
+            num_threads: 1,
+            max_threads: 10,
+            max_idle_threads: usize::MAX,
+            stop: StopHandle::default(),
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Create a pool with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Specify the number of workers started at the beginning.
+    ///
+    /// The value is clamped to `max_threads`.
+    ///
+    /// # Panics
+    /// Panics if `n` is zero.
+    pub fn num_threads(&mut self, n: usize) -> &mut Self {
+        assert!(n > 0, "num_threads must be greater than zero");
+        self.num_threads = n;
+        self
+    }
+
+    /// Specify the maximum number of workers.
+    ///
+    /// # Panics
+    /// Panics if `n` is zero.
+    pub fn max_threads(&mut self, n: usize) -> &mut Self {
+        assert!(n > 0, "max_threads must be greater than zero");
+        self.max_threads = n;
+        self
+    }
+
+    /// Specify the maximum number of idle workers kept in the pool.
+    ///
+    /// By default, the idle workers are not terminated until the pool stops.
+    pub fn max_idle_threads(&mut self, n: usize) -> &mut Self {
+        self.max_idle_threads = n;
+        self
+    }
+
+    /// Return a handle to stop this pool.
+    ///
+    /// Once stopped, the subsequent calls of `run` return immediately.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Receive the requests from the session on the workers and handle them,
+    /// until the session is closed or the pool is stopped.
+    ///
+    /// The device file of the session is switched to the non-blocking mode while
+    /// running, so that the idle workers can be woken up by `StopHandle::stop`.
+    /// If the requests are transported over io_uring, the workers waiting for
+    /// the requests observe the stop after receiving the next request.
+    ///
+    /// The errors returned from the handler are logged with `tracing`, and the requests
+    /// left unreplied by the failed handler are replied with `EIO`. When receiving
+    /// a request fails, the pool stops and the error is returned after all the workers
+    /// have exited.
+    pub fn run<H>(&self, session: &Session, handler: H) -> io::Result<()>
+    where
+        H: Fn(&Request) -> io::Result<()> + Sync,
+    {
+        let wake = EventFd::new()?;
+        let _nonblocking = if session.io_uring() {
+            None
+        } else {
+            Some(Nonblocking::enable(session.as_raw_fd())?)
+        };
+
+        // A stop requested before the start of the pool is also observed by the workers,
+        // since the event is written after the wakeup has been installed.
+        *self.stop.inner.wake.lock().unwrap() = Some(wake.0);
+        if self.stop.is_stopped() {
+            notify(wake.0)?;
+        }
+
+        let num_threads = self.num_threads.min(self.max_threads);
+        let pool = Pool {
+            config: self,
+            session,
+            handler: &handler,
+            wake: &wake,
+            state: Mutex::new(State {
+                threads: num_threads,
+                idle: num_threads,
+            }),
+            error: Mutex::new(None),
+        };
+
+        thread::scope(|scope| {
+            for _ in 0..num_threads {
+                let pool = &pool;
+                scope.spawn(move || pool.worker(scope));
+            }
+        });
+
+        self.stop.inner.wake.lock().unwrap().take();
+
+        match pool.error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A handle to stop the workers of `WorkerPool`.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    inner: Arc<StopInner>,
+}
+
+#[derive(Debug, Default)]
+struct StopInner {
+    stopped: AtomicBool,
+    wake: Mutex<Option<RawFd>>,
+}
+
+impl StopHandle {
+    /// Request the workers to stop.
+    ///
+    /// The workers exit after replying to the requests being handled,
+    /// and the requests that are not yet received are left in the kernel.
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        if let Some(fd) = *self.inner.wake.lock().unwrap() {
+            let _ = notify(fd);
+        }
+    }
+
+    /// Return whether the stop has been requested.
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::SeqCst)
+    }
+}
+
+struct Pool<'a, H> {
+    config: &'a WorkerPool,
+    session: &'a Session,
+    handler: &'a H,
+    wake: &'a EventFd,
+    state: Mutex<State>,
+    error: Mutex<Option<io::Error>>,
+}
+
+/// The number of workers.
+struct State {
+    threads: usize,
+    idle: usize,
+}
+
+impl<'a, H> Pool<'a, H>
+where
+    H: Fn(&Request) -> io::Result<()> + Sync,
+{
+    fn worker<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>) {
+        loop {
+            let req = match self.receive() {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(err) => {
+                    self.fail(err);
+                    break;
+                }
+            };
+
+            let spawn = {
+                let mut state = self.state.lock().unwrap();
+                state.idle -= 1;
+                let spawn = state.idle == 0 && state.threads < self.config.max_threads;
+                if spawn {
+                    state.threads += 1;
+                    state.idle += 1;
+                }
+                spawn
+            };
+            if spawn {
+                tracing::debug!("spawn a new worker");
+                scope.spawn(move || self.worker(scope));
+            }
+
+            if let Err(err) = (self.handler)(&req) {
+                fs::log_error(&req, &err);
+                fs::reply_failed(&req);
+            }
+
+            let mut state = self.state.lock().unwrap();
+            // At least one worker keeps waiting for the requests.
+            if state.idle > 0 && state.idle >= self.config.max_idle_threads {
+                state.threads -= 1;
+                return;
+            }
+            state.idle += 1;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.threads -= 1;
+        state.idle -= 1;
+    }
+
+    /// Wait for an incoming request, or return `None` if the pool is stopping.
+    fn receive(&self) -> io::Result<Option<Request>> {
+        loop {
+            if self.config.stop.is_stopped() {
+                return Ok(None);
+            }
+
+            match self.session.next_request() {
+                Ok(Some(req)) => return Ok(Some(req)),
+                Ok(None) => {
+                    // The session is closed, so the other workers will receive no more requests.
+                    self.config.stop.stop();
+                    return Ok(None);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.wait_readable()?,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Wait until the device file becomes readable or the pool is stopped.
+    fn wait_readable(&self) -> io::Result<()> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.session.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wake.0,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if res >= 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    fn fail(&self, err: io::Error) {
+        self.error.lock().unwrap().get_or_insert(err);
+        self.config.stop.stop();
+    }
+}
+
+/// An eventfd to wake up the idle workers.
+///
+/// The event is never consumed, so that all the workers observe it.
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn notify(fd: RawFd) -> io::Result<()> {
+    let value = 1u64;
+    let res = unsafe { libc::write(fd, &value as *const u64 as *const libc::c_void, 8) };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        // The counter has been saturated, which also makes it readable.
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// A guard that restores the file status flags on drop.
+struct Nonblocking {
+    fd: RawFd,
+    flags: libc::c_int,
+}
+
+impl Nonblocking {
+    fn enable(fd: RawFd) -> io::Result<Self> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, flags })
+    }
+}
+
+impl Drop for Nonblocking {
+    fn drop(&mut self) {
+        unsafe {
+            libc::fcntl(self.fd, libc::F_SETFL, self.flags);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::{
+        sync::Condvar,
+        time::{Duration, Instant},
+    };
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn grow_when_all_workers_busy() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let arrived = (Mutex::new(0), Condvar::new());
+
+        for unique in [2, 4, 6] {
+            kernel.send(
+                FUSE_GETATTR,
+                unique,
+                1,
+                &[fuse_getattr_in::default().as_bytes()],
+            );
+        }
+        kernel.send(FUSE_DESTROY, 8, 0, &[]);
+
+        let mut pool = WorkerPool::new();
+        pool.num_threads(1).max_threads(4);
+        pool.run(&session, |req| {
+            if req.unique() == 8 {
+                return req.reply(());
+            }
+
+            // Each request is released only after all of them are handled concurrently.
+            let (count, cond) = &arrived;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut count = count.lock().unwrap();
+            *count += 1;
+            cond.notify_all();
+            while *count < 3 {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                if timeout.is_zero() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no new workers"));
+                }
+                count = cond.wait_timeout(count, timeout).unwrap().0;
+            }
+            req.reply_error(libc::ENOENT)
+        })
+        .unwrap();
+
+        let mut replies = vec![];
+        while let Some((header, _)) = kernel.recv() {
+            replies.push((header.unique, header.error));
+        }
+        replies.sort_unstable();
+        assert_eq!(
+            replies,
+            vec![
+                (2, -libc::ENOENT),
+                (4, -libc::ENOENT),
+                (6, -libc::ENOENT),
+                (8, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn reply_eio_on_handler_error() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        let getattr_in = fuse_getattr_in::default();
+        kernel.send(FUSE_GETATTR, 2, 1, &[getattr_in.as_bytes()]);
+        kernel.send(FUSE_GETATTR, 4, 2, &[getattr_in.as_bytes()]);
+        kernel.send(FUSE_DESTROY, 6, 0, &[]);
+
+        // The pool keeps running after the handler fails.
+        WorkerPool::new()
+            .run(&session, |req| match req.unique() {
+                2 => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+                _ => req.reply(()),
+            })
+            .unwrap();
+
+        let mut replies = vec![];
+        while let Some((header, _)) = kernel.recv() {
+            replies.push((header.unique, header.error));
+        }
+        replies.sort_unstable();
+        assert_eq!(replies, vec![(2, -libc::EIO), (4, 0), (6, 0)]);
+    }
+
+    #[test]
+    fn stop_idle_workers() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        let mut pool = WorkerPool::new();
+        pool.num_threads(2);
+        let stop = pool.stop_handle();
+
+        thread::scope(|scope| {
+            let handle = scope.spawn(|| pool.run(&session, |req| req.reply(())));
+            thread::sleep(Duration::from_millis(50));
+            stop.stop();
+            handle.join().unwrap().unwrap();
+        });
+        assert!(kernel.recv().is_none());
+
+        // The device file is switched back to the blocking mode.
+        let flags = unsafe { libc::fcntl(session.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+    }
+}
@@ -1,6 +1,13 @@
-use crate::atomic_bytes::{AtomicBytes, FillBytes};
+use crate::{
+    atomic_bytes::{AtomicBytes, FillBytes},
+    errno::Errno,
+    session::Request,
+};
 use polyfuse_kernel::*;
-use std::{convert::TryInto as _, ffi::OsStr, fmt, mem, os::unix::prelude::*, time::Duration};
+use std::{
+    convert::TryInto as _, ffi::OsStr, fmt, io, marker::PhantomData, mem, os::unix::prelude::*,
+    time::Duration,
+};
 use zerocopy::AsBytes as _;
 
 /// Attributes about a file.
@@ -278,6 +285,14 @@ impl OpenOut {
     pub fn cache_dir(&mut self, enabled: bool) {
         self.set_flag(FOPEN_CACHE_DIR, enabled);
     }
+
+    /// Route read/write requests on this file directly to a backing file.
+    ///
+    /// The backing ID must be obtained by `Session::register_backing_fd`.
+    pub fn backing_id(&mut self, backing_id: i32) {
+        self.out.backing_id = backing_id;
+        self.set_flag(FOPEN_PASSTHROUGH, true);
+    }
 }
 
 #[derive(Default)]
@@ -575,6 +590,128 @@ impl PollOut {
     }
 }
 
+#[derive(Default)]
+pub struct IoctlOut {
+    out: fuse_ioctl_out,
+    in_iovs: Vec<fuse_ioctl_iovec>,
+    out_iovs: Vec<fuse_ioctl_iovec>,
+}
+
+impl fmt::Debug for IoctlOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoctlOut")
+            .field("result", &self.out.result)
+            .field("flags", &self.out.flags)
+            .field("in_iovs", &self.in_iovs.len())
+            .field("out_iovs", &self.out_iovs.len())
+            .finish()
+    }
+}
+
+impl AtomicBytes for IoctlOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len()
+            + mem::size_of::<fuse_ioctl_iovec>() * (self.in_iovs.len() + self.out_iovs.len())
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1 + (!self.in_iovs.is_empty()) as usize + (!self.out_iovs.is_empty()) as usize
+    }
+
+    fn fill_bytes<'a, F: FillBytes<'a>>(&'a self, dst: &mut F) {
+        dst.put(self.out.as_bytes());
+        if !self.in_iovs.is_empty() {
+            dst.put(self.in_iovs.as_bytes());
+        }
+        if !self.out_iovs.is_empty() {
+            dst.put(self.out_iovs.as_bytes());
+        }
+    }
+}
+
+impl IoctlOut {
+    /// Set the return value of `ioctl(2)` passed to the caller.
+    ///
+    /// The output data, if any, should be sent together with this value,
+    /// e.g. `req.reply((out, data))` or `IoctlReply::reply_data`.  The length
+    /// of the data must be within `Ioctl::out_size`.
+    pub fn result(&mut self, result: i32) {
+        self.out.result = result;
+    }
+
+    /// Append a region of the caller's memory that should be read
+    /// and passed to the filesystem when the kernel retries the request.
+    ///
+    /// Calling this method (or `out_iov`) turns the reply into a retry
+    /// request, so the result value and output data are ignored by the kernel.
+    /// The retry is only allowed for unrestricted ioctls, that is,
+    /// `Ioctl::unrestricted` returns `true`.
+    pub fn in_iov(&mut self, base: u64, len: u64) {
+        self.in_iovs.push(fuse_ioctl_iovec { base, len });
+        self.set_retry();
+    }
+
+    /// Append a region of the caller's memory that should be written
+    /// with the output data when the kernel retries the request.
+    ///
+    /// See the documentation of `in_iov` for details.
+    pub fn out_iov(&mut self, base: u64, len: u64) {
+        self.out_iovs.push(fuse_ioctl_iovec { base, len });
+        self.set_retry();
+    }
+
+    fn set_retry(&mut self) {
+        let num_iovs = self.in_iovs.len() + self.out_iovs.len();
+        assert!(
+            num_iovs <= FUSE_IOCTL_MAX_IOV as usize,
+            "the number of iovecs must be less or equal to {}",
+            FUSE_IOCTL_MAX_IOV,
+        );
+        self.out.flags |= FUSE_IOCTL_RETRY;
+        self.out.in_iovs = self.in_iovs.len() as u32;
+        self.out.out_iovs = self.out_iovs.len() as u32;
+    }
+}
+
+#[derive(Default)]
+pub struct LseekOut {
+    out: fuse_lseek_out,
+}
+
+impl fmt::Debug for LseekOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LseekOut")
+            .field("offset", &self.out.offset)
+            .finish()
+    }
+}
+
+impl AtomicBytes for LseekOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn fill_bytes<'a, F: FillBytes<'a>>(&'a self, dst: &mut F) {
+        dst.put(self.out.as_bytes());
+    }
+}
+
+impl LseekOut {
+    /// Set the resulting offset, measured from the beginning of the file.
+    pub fn offset(&mut self, offset: u64) {
+        self.out.offset = offset;
+    }
+}
+
 pub struct ReaddirOut {
     buf: Vec<u8>,
 }
@@ -636,7 +773,413 @@ impl ReaddirOut {
     }
 }
 
+/// The reply of `readdirplus` requests, which contains the directory entries
+/// along with their attributes.
+///
+/// Unlike `ReaddirOut`, the kernel takes a reference to each entry in the reply,
+/// in the same way as `lookup`. The entries other than `.`, `..` and the ones with
+/// zero inode numbers increment the lookup counts, and they must be released by
+/// the later `forget` requests. The inode numbers of those entries are returned
+/// by `lookups`.
+pub struct ReaddirPlusOut {
+    buf: Vec<u8>,
+    lookups: Vec<(u64, usize)>,
+}
+
+impl fmt::Debug for ReaddirPlusOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaddirPlusOut")
+            .field("lookups", &self.lookups().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl AtomicBytes for ReaddirPlusOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.buf.size()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.buf.count()
+    }
+
+    fn fill_bytes<'a, F: FillBytes<'a>>(&'a self, dst: &mut F) {
+        self.buf.fill_bytes(dst)
+    }
+}
+
+impl ReaddirPlusOut {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(capacity),
+            lookups: vec![],
+        }
+    }
+
+    /// Append a directory entry to the reply.
+    ///
+    /// The inode number of the entry is taken from `entry`. If the remaining capacity
+    /// is not enough for the entry, it is not appended and `true` is returned.
+    pub fn entry(&mut self, name: &OsStr, typ: u32, off: u64, entry: EntryOut) -> bool {
+        let name = name.as_bytes();
+        let remaining = self.buf.capacity() - self.buf.len();
+
+        let entry_size = mem::size_of::<fuse_direntplus>() + name.len();
+        let aligned_entry_size = aligned(entry_size);
+
+        if remaining < aligned_entry_size {
+            return true;
+        }
+
+        let nodeid = entry.out.nodeid;
+        let direntplus = fuse_direntplus {
+            entry_out: entry.out,
+            dirent: fuse_dirent {
+                ino: entry.out.attr.ino,
+                off,
+                namelen: name.len().try_into().expect("name length is too long"),
+                typ,
+                name: [],
+            },
+        };
+        let lenbefore = self.buf.len();
+        self.buf.extend_from_slice(direntplus.as_bytes());
+        self.buf.extend_from_slice(name);
+        self.buf.resize(lenbefore + aligned_entry_size, 0);
+
+        // The kernel does not take the references to the entries of `.` and `..`.
+        if nodeid != 0 && name != b"." && name != b".." {
+            self.lookups.push((nodeid, lenbefore));
+        }
+
+        false
+    }
+
+    /// Return the inode numbers of the entries whose lookup counts are incremented
+    /// by this reply.
+    ///
+    /// An inode number appears multiple times if it is appended more than once.
+    pub fn lookups(&self) -> impl Iterator<Item = u64> + '_ {
+        self.lookups.iter().map(|&(nodeid, _)| nodeid)
+    }
+
+    /// Overwrite the generation of the `i`-th entry returned by `lookups`.
+    pub(crate) fn set_generation(&mut self, i: usize, generation: u64) {
+        let (_, pos) = self.lookups[i];
+        let mut entry_out = fuse_entry_out::default();
+        let len = mem::size_of::<fuse_entry_out>();
+        entry_out
+            .as_bytes_mut()
+            .copy_from_slice(&self.buf[pos..pos + len]);
+        entry_out.generation = generation;
+        self.buf[pos..pos + len].copy_from_slice(entry_out.as_bytes());
+    }
+}
+
+/// A handle to reply to a request with a value of type `T`.
+///
+/// The handles are obtained from the decoded operations, e.g. `op::Lookup::reply_handle`,
+/// and accept only the type of reply expected by the kernel for that operation. Since
+/// a handle is consumed on use, the request cannot be replied twice through it.
+/// If the handle is dropped while the request has not been replied in any way,
+/// the request is replied with `EIO` and a warning is logged. The operations that take no reply, such as `forget`,
+/// provide no handles.
+#[must_use = "the request is replied with EIO if the handle is dropped"]
+pub struct ReplyHandle<T> {
+    req: Option<Request>,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T> fmt::Debug for ReplyHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplyHandle")
+            .field("unique", &self.request().unique())
+            .finish()
+    }
+}
+
+impl<T> ReplyHandle<T> {
+    pub(crate) fn new(req: &Request) -> Self {
+        Self {
+            req: Some(req.clone()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return the request to be replied.
+    pub fn request(&self) -> &Request {
+        self.req.as_ref().expect("the request has been replied")
+    }
+
+    /// Reply to the request with an error code.
+    pub fn reply_error(mut self, code: i32) -> io::Result<()> {
+        self.take().reply_error(code)
+    }
+
+    /// Take the request without replying to it.
+    ///
+    /// The caller is responsible for replying to the returned request, e.g. through
+    /// `InodeTable::reply_entry`.
+    pub fn into_request(mut self) -> Request {
+        self.take()
+    }
+
+    fn take(&mut self) -> Request {
+        self.req.take().expect("the request has been replied")
+    }
+}
+
+impl<T> ReplyHandle<T>
+where
+    T: AtomicBytes,
+{
+    /// Reply to the request with the value.
+    pub fn reply(mut self, arg: T) -> io::Result<()> {
+        self.take().reply(arg)
+    }
+
+    /// Reply to the request with the result of a handler.
+    pub fn reply_result(mut self, res: Result<T, Errno>) -> io::Result<()> {
+        self.take().reply_result(res)
+    }
+}
+
+impl<T> Drop for ReplyHandle<T> {
+    fn drop(&mut self) {
+        if let Some(req) = self.req.take() {
+            // The request may have been replied through another clone.
+            if req.is_replied() {
+                return;
+            }
+            tracing::warn!(
+                "the request is dropped without replying, reply EIO instead (unique={})",
+                req.unique()
+            );
+            if let Err(err) = req.reply_error(libc::EIO) {
+                tracing::warn!("failed to reply EIO: {}", err);
+            }
+        }
+    }
+}
+
+/// The marker for the replies with an arbitrary data, to `read` and `readlink`.
+#[derive(Debug)]
+pub enum Data {}
+
+/// The marker for the replies to `getxattr` and `listxattr`.
+#[derive(Debug)]
+pub enum Xattr {}
+
+impl ReplyHandle<Data> {
+    /// Reply to the request with the data.
+    pub fn reply<B>(mut self, data: B) -> io::Result<()>
+    where
+        B: AtomicBytes,
+    {
+        self.take().reply(data)
+    }
+
+    /// Reply to the request with at most `len` bytes of data read from the file at `offset`.
+    ///
+    /// See `Request::reply_splice` for details.
+    pub fn reply_splice<F>(mut self, fd: &F, offset: u64, len: usize) -> io::Result<()>
+    where
+        F: AsRawFd + ?Sized,
+    {
+        self.take().reply_splice(fd, offset, len)
+    }
+}
+
+impl ReplyHandle<Xattr> {
+    /// Reply to the request with the size of the value, when the requested size is zero.
+    pub fn reply_size(mut self, out: XattrOut) -> io::Result<()> {
+        self.take().reply(out)
+    }
+
+    /// Reply to the request with the value, or with the list of the names.
+    pub fn reply_data<B>(mut self, data: B) -> io::Result<()>
+    where
+        B: AtomicBytes,
+    {
+        self.take().reply(data)
+    }
+}
+
+impl ReplyHandle<IoctlOut> {
+    /// Reply to the request with the result value and the output data.
+    ///
+    /// The length of the data must be within `Ioctl::out_size`.
+    pub fn reply_data<B>(mut self, out: IoctlOut, data: B) -> io::Result<()>
+    where
+        B: AtomicBytes,
+    {
+        self.take().reply((out, data))
+    }
+}
+
+pub type EntryReply = ReplyHandle<EntryOut>;
+pub type AttrReply = ReplyHandle<AttrOut>;
+pub type OpenReply = ReplyHandle<OpenOut>;
+pub type WriteReply = ReplyHandle<WriteOut>;
+pub type StatfsReply = ReplyHandle<StatfsOut>;
+pub type CreateReply = ReplyHandle<(EntryOut, OpenOut)>;
+pub type EmptyReply = ReplyHandle<()>;
+pub type DataReply = ReplyHandle<Data>;
+pub type XattrReply = ReplyHandle<Xattr>;
+pub type LkReply = ReplyHandle<LkOut>;
+pub type BmapReply = ReplyHandle<BmapOut>;
+pub type PollReply = ReplyHandle<PollOut>;
+pub type IoctlReply = ReplyHandle<IoctlOut>;
+pub type LseekReply = ReplyHandle<LseekOut>;
+
+/// A handle to reply to a `readdir` request, depending on its mode.
+#[derive(Debug)]
+#[must_use = "the request is replied with EIO if the handle is dropped"]
+pub enum ReaddirReply {
+    /// The handle for a request in the normal mode.
+    Normal(ReplyHandle<ReaddirOut>),
+    /// The handle for a request in the `readdirplus` mode.
+    Plus(ReplyHandle<ReaddirPlusOut>),
+}
+
+impl ReaddirReply {
+    /// Return the request to be replied.
+    pub fn request(&self) -> &Request {
+        match self {
+            Self::Normal(reply) => reply.request(),
+            Self::Plus(reply) => reply.request(),
+        }
+    }
+
+    /// Reply to the request with an error code, regardless of the mode.
+    pub fn reply_error(self, code: i32) -> io::Result<()> {
+        match self {
+            Self::Normal(reply) => reply.reply_error(code),
+            Self::Plus(reply) => reply.reply_error(code),
+        }
+    }
+}
+
 #[inline]
 const fn aligned(len: usize) -> usize {
     (len + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{session::testing, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn reply_through_handle() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        kernel.send(FUSE_LOOKUP, 2, 1, &[b"foo\0"]);
+        let req = session.next_request().unwrap().unwrap();
+        let reply = match req.operation().unwrap() {
+            Operation::Lookup(op) => op.reply_handle(&req),
+            op => panic!("unexpected operation: {:?}", op),
+        };
+        let mut out = super::EntryOut::default();
+        out.ino(10);
+        reply.reply(out).unwrap();
+
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+        assert_eq!(arg.len(), fuse_entry_out::default().as_bytes().len());
+        assert!(kernel.recv().is_none());
+    }
+
+    #[test]
+    fn readdir_handle_by_mode() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        kernel.send(
+            FUSE_READDIRPLUS,
+            2,
+            1,
+            &[fuse_read_in::default().as_bytes()],
+        );
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Readdir(op) => match op.reply_handle(&req) {
+                super::ReaddirReply::Plus(reply) => {
+                    reply.reply(super::ReaddirPlusOut::new(0)).unwrap()
+                }
+                reply => panic!("unexpected handle: {:?}", reply),
+            },
+            op => panic!("unexpected operation: {:?}", op),
+        }
+
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+        assert!(arg.is_empty());
+    }
+
+    #[test]
+    fn ioctl_reply_with_data() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        let ioctl_in = fuse_ioctl_in {
+            out_size: 4,
+            ..Default::default()
+        };
+        kernel.send(FUSE_IOCTL, 2, 1, &[ioctl_in.as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        let reply = match req.operation().unwrap() {
+            Operation::Ioctl(op) => op.reply_handle(&req),
+            op => panic!("unexpected operation: {:?}", op),
+        };
+        let mut out = super::IoctlOut::default();
+        out.result(42);
+        reply.reply_data(out, &b"data"[..]).unwrap();
+
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, 0));
+        let out_len = fuse_ioctl_out::default().as_bytes().len();
+        assert_eq!(arg.len(), out_len + 4);
+        assert_eq!(arg[..4], 42i32.to_ne_bytes());
+        assert_eq!(arg[out_len..], b"data"[..]);
+    }
+
+    #[test]
+    fn reply_eio_on_drop() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        kernel.send(FUSE_GETATTR, 2, 1, &[fuse_getattr_in::default().as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Getattr(op) => drop(op.reply_handle(&req)),
+            op => panic!("unexpected operation: {:?}", op),
+        }
+
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, -libc::EIO));
+        assert!(kernel.recv().is_none());
+    }
+
+    #[test]
+    fn reply_only_once() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        kernel.send(FUSE_GETATTR, 2, 1, &[fuse_getattr_in::default().as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        let handle = match req.operation().unwrap() {
+            Operation::Getattr(op) => op.reply_handle(&req),
+            op => panic!("unexpected operation: {:?}", op),
+        };
+        req.reply_error(libc::ENOENT).unwrap();
+
+        // Neither the clones nor the dropped handle reply to the request again.
+        let err = req.clone().reply(()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        drop(handle);
+
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, -libc::ENOENT));
+        assert!(kernel.recv().is_none());
+    }
+}
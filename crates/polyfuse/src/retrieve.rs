@@ -0,0 +1,293 @@
+//! Correlation of `notify_retrieve` notifications with their replies.
+//!
+//! `Notifier::retrieve_data` returns a `RetrieveHandle`, and the session passes the
+//! data in the matching `FUSE_NOTIFY_REPLY` message to that handle instead of
+//! returning it from `next_request`.
+
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+/// The data retrieved from the page cache of the kernel.
+#[derive(Debug, Clone)]
+pub struct Retrieved {
+    offset: u64,
+    data: Bytes,
+}
+
+impl Retrieved {
+    /// Return the starting offset of the retrieved data.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Return the retrieved data.
+    ///
+    /// The data may be shorter than the requested size, e.g. if some of the pages
+    /// are not in the cache.
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    /// Take the retrieved data.
+    pub fn into_data(self) -> Bytes {
+        self.data
+    }
+}
+
+/// A handle to wait for the reply to a `notify_retrieve` notification.
+///
+/// The reply is received through `recv` or `recv_timeout`, or by awaiting the handle
+/// itself. The kernel never replies if the inode is not cached, so the callers are
+/// expected to bound the wait with a timeout. Dropping the handle cancels the wait,
+/// and the late reply is then returned from `Session::next_request` as an ordinary
+/// `Operation::NotifyReply`.
+pub struct RetrieveHandle {
+    registry: Arc<Registry>,
+    unique: u64,
+    slot: Arc<Slot>,
+}
+
+impl fmt::Debug for RetrieveHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetrieveHandle")
+            .field("unique", &self.unique)
+            .finish()
+    }
+}
+
+impl RetrieveHandle {
+    /// Return the unique ID of the notification.
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Block the current thread until the reply is received.
+    ///
+    /// An error is returned if the session is closed before the reply.
+    pub fn recv(self) -> io::Result<Retrieved> {
+        let mut inner = self.slot.inner.lock().unwrap();
+        loop {
+            if let Some(res) = inner.result.take() {
+                return res;
+            }
+            inner = self.slot.cond.wait(inner).unwrap();
+        }
+    }
+
+    /// Block the current thread until the reply is received or the timeout expires.
+    ///
+    /// An error of `io::ErrorKind::TimedOut` is returned on timeout.
+    pub fn recv_timeout(self, timeout: Duration) -> io::Result<Retrieved> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.slot.inner.lock().unwrap();
+        loop {
+            if let Some(res) = inner.result.take() {
+                return res;
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout == Duration::from_secs(0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for the retrieved data",
+                ));
+            }
+            inner = self.slot.cond.wait_timeout(inner, timeout).unwrap().0;
+        }
+    }
+}
+
+impl Future for RetrieveHandle {
+    type Output = io::Result<Retrieved>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.slot.inner.lock().unwrap();
+        match inner.result.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RetrieveHandle {
+    fn drop(&mut self) {
+        self.registry
+            .inner
+            .lock()
+            .unwrap()
+            .slots
+            .remove(&self.unique);
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    inner: Mutex<SlotInner>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct SlotInner {
+    result: Option<io::Result<Retrieved>>,
+    waker: Option<Waker>,
+}
+
+impl Slot {
+    fn complete(&self, res: io::Result<Retrieved>) {
+        let waker = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.result = Some(res);
+            inner.waker.take()
+        };
+        self.cond.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The handles waiting for the replies, keyed by the unique IDs of the notifications.
+#[derive(Default)]
+pub(crate) struct Registry {
+    inner: Mutex<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    slots: HashMap<u64, Arc<Slot>>,
+    closed: bool,
+}
+
+impl Registry {
+    /// Register a handle before sending the notification.
+    pub(crate) fn register(self: &Arc<Self>, unique: u64) -> RetrieveHandle {
+        let slot = Arc::new(Slot::default());
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            slot.complete(Err(closed()));
+        } else {
+            inner.slots.insert(unique, slot.clone());
+        }
+        RetrieveHandle {
+            registry: self.clone(),
+            unique,
+            slot,
+        }
+    }
+
+    /// Pass the data in a `FUSE_NOTIFY_REPLY` message to the waiting handle.
+    ///
+    /// Return `false` if no handle is waiting for the reply.
+    pub(crate) fn complete(&self, unique: u64, offset: u64, data: Bytes) -> bool {
+        let slot = self.inner.lock().unwrap().slots.remove(&unique);
+        match slot {
+            Some(slot) => {
+                slot.complete(Ok(Retrieved { offset, data }));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fail all the waiting handles since no more replies will arrive.
+    pub(crate) fn close(&self) {
+        let slots = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.closed = true;
+            std::mem::take(&mut inner.slots)
+        };
+        for slot in slots.values() {
+            slot.complete(Err(closed()));
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "the session is closed before the data is retrieved",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{session::testing, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use std::{io, mem, time::Duration};
+    use zerocopy::AsBytes as _;
+
+    fn notify_reply_in(offset: u64, size: u32) -> fuse_notify_retrieve_in {
+        fuse_notify_retrieve_in {
+            offset,
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn route_notify_reply() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let notifier = session.notifier();
+
+        let handle = notifier.retrieve_data(2, 4096, 8).unwrap();
+        let (header, arg) = kernel.recv().unwrap();
+        assert_eq!(header.error, fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32);
+        let mut out = fuse_notify_retrieve_out::default();
+        out.as_bytes_mut()
+            .copy_from_slice(&arg[..mem::size_of::<fuse_notify_retrieve_out>()]);
+        assert_eq!(out.notify_unique, handle.unique());
+
+        let reply_in = notify_reply_in(4096, 5);
+        kernel.send(
+            FUSE_NOTIFY_REPLY,
+            handle.unique(),
+            2,
+            &[reply_in.as_bytes(), b"hello"],
+        );
+        kernel.send(FUSE_STATFS, 2, 1, &[]);
+
+        // The reply is passed to the handle and not returned from the session.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+        let retrieved = handle.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(retrieved.offset(), 4096);
+        assert_eq!(&retrieved.data()[..], b"hello");
+    }
+
+    #[test]
+    fn late_notify_reply() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        let notifier = session.notifier();
+
+        let handle = notifier.retrieve_data(2, 0, 8).unwrap();
+        let unique = handle.unique();
+        let err = handle.recv_timeout(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The reply after the timeout is returned as an ordinary request.
+        let reply_in = notify_reply_in(0, 0);
+        kernel.send(FUSE_NOTIFY_REPLY, unique, 2, &[reply_in.as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::NotifyReply(op, _) => assert_eq!(op.unique(), unique),
+            op => panic!("unexpected operation: {:?}", op),
+        }
+
+        // The handles waiting for the replies fail when the session is closed.
+        let handle = notifier.retrieve_data(2, 0, 8).unwrap();
+        drop(session);
+        let err = handle.recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+}
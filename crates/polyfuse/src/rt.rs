@@ -0,0 +1,298 @@
+//! Common parts of the integration with the async runtimes.
+//!
+//! The device file is switched to the non-blocking mode and registered to the reactor
+//! of each runtime, and the synchronous operations are retried on `WouldBlock` after
+//! the readiness of the file descriptor is notified.
+
+use crate::{
+    conn::PendingMount,
+    session::{Handshake, KernelConfig, Session},
+};
+use std::{
+    future, io, mem,
+    os::unix::prelude::*,
+    path::PathBuf,
+    task::{Context, Poll},
+};
+
+/// A file descriptor registered to the reactor of an async runtime.
+pub(crate) trait Registration: Sized {
+    /// Register the file descriptor, which must outlive the returned value.
+    fn register(fd: RawFd) -> io::Result<Self>;
+
+    /// Attempt a read operation until it does not return `WouldBlock`.
+    fn poll_read_with<R, F>(&self, cx: &mut Context<'_>, f: &mut F) -> Poll<io::Result<R>>
+    where
+        F: FnMut() -> io::Result<R>;
+
+    /// Attempt a write operation until it does not return `WouldBlock`.
+    fn poll_write_with<R, F>(&self, cx: &mut Context<'_>, f: &mut F) -> Poll<io::Result<R>>
+    where
+        F: FnMut() -> io::Result<R>;
+}
+
+/// A borrowed file descriptor to be registered to the reactors.
+pub(crate) struct Fd(pub(crate) RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl AsFd for Fd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The registration is always dropped before the file descriptor is closed.
+        unsafe { BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+pub(crate) async fn read_with<T, R, F>(io: &T, mut f: F) -> io::Result<R>
+where
+    T: Registration,
+    F: FnMut() -> io::Result<R>,
+{
+    future::poll_fn(|cx| io.poll_read_with(cx, &mut f)).await
+}
+
+pub(crate) async fn write_with<T, R, F>(io: &T, mut f: F) -> io::Result<R>
+where
+    T: Registration,
+    F: FnMut() -> io::Result<R>,
+{
+    future::poll_fn(|cx| io.poll_write_with(cx, &mut f)).await
+}
+
+/// Mount the filesystem and establish a session without blocking the current thread.
+pub(crate) async fn mount<T>(
+    mountpoint: PathBuf,
+    mut config: KernelConfig,
+) -> io::Result<(Session, T)>
+where
+    T: Registration,
+{
+    // The completions of io_uring are waited by blocking the thread,
+    // which cannot be driven by the reactors.
+    config.io_uring(false);
+    config.probe();
+
+    let mut mount = PendingMount::spawn(mountpoint, mem::take(&mut config.mountopts))?;
+    mount.set_nonblocking()?;
+    {
+        let socket = T::register(mount.as_raw_fd())?;
+        read_with(&socket, || mount.receive_fd()).await?;
+        if mount.waits_exit() {
+            read_with(&socket, || mount.check_exited()).await?;
+        }
+    }
+    let conn = mount.finish()?;
+
+    conn.set_nonblocking()?;
+    let io = T::register(conn.as_raw_fd())?;
+
+    let mut handshake = Handshake::new();
+    while !read_with(&io, || handshake.step(&mut config.init_out, &conn, &conn)).await? {}
+
+    Ok((Session::new(conn, config), io))
+}
+
+/// Define the session types bound to a `Registration` of the specific runtime.
+macro_rules! async_session {
+    ($Registration:ty) => {
+        use crate::{
+            atomic_bytes::AtomicBytes,
+            buf::RequestBuffer,
+            errno::Errno,
+            retrieve::RetrieveHandle,
+            rt,
+            session::{KernelConfig, Notifier, Request, Session},
+        };
+        use std::{
+            ffi::OsStr, fmt, io, ops::Deref, os::unix::prelude::*, path::PathBuf, sync::Arc,
+        };
+
+        /// A FUSE session whose device file is driven by the reactor of the async runtime.
+        ///
+        /// The requests over io_uring are not supported, and `KernelConfig::io_uring`
+        /// is ignored in this session.
+        pub struct AsyncSession {
+            io: Arc<$Registration>,
+            session: Session,
+        }
+
+        impl fmt::Debug for AsyncSession {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct("AsyncSession").finish()
+            }
+        }
+
+        impl AsRawFd for AsyncSession {
+            fn as_raw_fd(&self) -> RawFd {
+                self.session.as_raw_fd()
+            }
+        }
+
+        impl AsyncSession {
+            /// Start a FUSE daemon mount on the specified path.
+            ///
+            /// Unlike `Session::mount`, the current thread is not blocked while
+            /// waiting for `fusermount` and the initialization by the kernel.
+            pub async fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
+                let (session, io) = rt::mount::<$Registration>(mountpoint, config).await?;
+                Ok(Self {
+                    io: Arc::new(io),
+                    session,
+                })
+            }
+
+            /// Return the reference to the underlying session.
+            pub fn get_ref(&self) -> &Session {
+                &self.session
+            }
+
+            /// Receive an incoming FUSE request from the kernel.
+            ///
+            /// See the documentation of `Session::next_request` for details.
+            pub async fn next_request(&self) -> io::Result<Option<AsyncRequest>> {
+                let req = rt::read_with(&*self.io, || self.session.next_request()).await?;
+                Ok(req.map(|req| AsyncRequest {
+                    io: self.io.clone(),
+                    req,
+                }))
+            }
+
+            /// Receive an incoming FUSE request from the kernel into the specified buffer.
+            ///
+            /// See the documentation of `Session::next_request_into` for details.
+            pub async fn next_request_into(
+                &self,
+                buf: &mut RequestBuffer,
+            ) -> io::Result<Option<AsyncRequest>> {
+                let req = rt::read_with(&*self.io, || self.session.next_request_into(buf)).await?;
+                Ok(req.map(|req| AsyncRequest {
+                    io: self.io.clone(),
+                    req,
+                }))
+            }
+
+            /// Create an instance of `AsyncNotifier` corresponding to this session.
+            pub fn notifier(&self) -> AsyncNotifier {
+                AsyncNotifier {
+                    io: self.io.clone(),
+                    notifier: self.session.notifier(),
+                }
+            }
+        }
+
+        /// An incoming FUSE request received by `AsyncSession`.
+        ///
+        /// The methods of `Request` are available through `Deref`,
+        /// except for the replies that are shadowed by the async versions.
+        #[derive(Clone)]
+        pub struct AsyncRequest {
+            io: Arc<$Registration>,
+            req: Request,
+        }
+
+        impl Deref for AsyncRequest {
+            type Target = Request;
+
+            fn deref(&self) -> &Self::Target {
+                &self.req
+            }
+        }
+
+        impl AsyncRequest {
+            /// Take the underlying request.
+            pub fn into_inner(self) -> Request {
+                self.req
+            }
+
+            pub async fn reply<T>(&self, arg: T) -> io::Result<()>
+            where
+                T: AtomicBytes,
+            {
+                rt::write_with(&*self.io, || self.req.reply(&arg)).await
+            }
+
+            pub async fn reply_error(&self, code: i32) -> io::Result<()> {
+                rt::write_with(&*self.io, || self.req.reply_error(code)).await
+            }
+
+            pub async fn reply_result<T>(&self, res: Result<T, Errno>) -> io::Result<()>
+            where
+                T: AtomicBytes,
+            {
+                match res {
+                    Ok(arg) => self.reply(arg).await,
+                    Err(errno) => self.reply_error(errno.raw()).await,
+                }
+            }
+        }
+
+        /// The async version of `Notifier`.
+        #[derive(Clone)]
+        pub struct AsyncNotifier {
+            io: Arc<$Registration>,
+            notifier: Notifier,
+        }
+
+        impl AsyncNotifier {
+            /// Notify the cache invalidation about an inode to the kernel.
+            pub async fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
+                rt::write_with(&*self.io, || self.notifier.inval_inode(ino, off, len)).await
+            }
+
+            /// Notify the invalidation about a directory entry to the kernel.
+            pub async fn inval_entry<T>(&self, parent: u64, name: T) -> io::Result<()>
+            where
+                T: AsRef<OsStr>,
+            {
+                let name = name.as_ref();
+                rt::write_with(&*self.io, || self.notifier.inval_entry(parent, name)).await
+            }
+
+            /// Notify the invalidation about a directory entry to the kernel.
+            ///
+            /// See the documentation of `Notifier::delete` for details.
+            pub async fn delete<T>(&self, parent: u64, child: u64, name: T) -> io::Result<()>
+            where
+                T: AsRef<OsStr>,
+            {
+                let name = name.as_ref();
+                rt::write_with(&*self.io, || self.notifier.delete(parent, child, name)).await
+            }
+
+            /// Push the data in an inode for updating the kernel cache.
+            pub async fn store<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
+            where
+                T: AtomicBytes,
+            {
+                rt::write_with(&*self.io, || self.notifier.store(ino, offset, &data)).await
+            }
+
+            /// Retrieve data in an inode from the kernel cache.
+            pub async fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
+                rt::write_with(&*self.io, || self.notifier.retrieve(ino, offset, size)).await
+            }
+
+            /// Retrieve data in an inode from the kernel cache, and return a handle to
+            /// receive it.
+            ///
+            /// See the documentation of `Notifier::retrieve_data` for details.
+            pub async fn retrieve_data(
+                &self,
+                ino: u64,
+                offset: u64,
+                size: u32,
+            ) -> io::Result<RetrieveHandle> {
+                rt::write_with(&*self.io, || self.notifier.retrieve_data(ino, offset, size)).await
+            }
+
+            /// Send I/O readiness to the kernel.
+            pub async fn poll_wakeup(&self, kh: u64) -> io::Result<()> {
+                rt::write_with(&*self.io, || self.notifier.poll_wakeup(kh)).await
+            }
+        }
+    };
+}
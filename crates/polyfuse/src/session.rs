@@ -1,29 +1,34 @@
 use crate::{
     atomic_bytes::{AtomicBytes, FillBytes},
-    conn::{Connection, MountOptions},
-    decoder::Decoder,
-    op::{DecodeError, Operation},
+    buf::{self, AlignedBuf, BufferPool, RequestBuffer, DEFAULT_MAX_IDLE_BUFFERS},
+    conn::{Connection, MountOptions, Pipe},
+    errno::Errno,
+    interrupt::{CancelToken, Registry, Tracked},
+    op::{DecodeError, DecodeErrorPolicy, Operation},
+    retrieve::{self, RetrieveHandle},
+    uring::{self, Commit, Received, Transport},
 };
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use polyfuse_kernel::*;
 use std::{
     cmp,
     convert::{TryFrom, TryInto as _},
     ffi::OsStr,
     fmt,
+    fs::File,
     io::{self, prelude::*, IoSlice},
-    mem::{self, MaybeUninit},
+    mem::{self, ManuallyDrop, MaybeUninit},
     os::unix::prelude::*,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
 use zerocopy::AsBytes as _;
 
 // The minimum supported ABI minor version by polyfuse.
-const MINIMUM_SUPPORTED_MINOR_VERSION: u32 = 23;
+pub(crate) const MINIMUM_SUPPORTED_MINOR_VERSION: u32 = 23;
 
 const DEFAULT_MAX_WRITE: u32 = 16 * 1024 * 1024;
 const MIN_MAX_WRITE: u32 = FUSE_MIN_READ_BUFFER - BUFFER_HEADER_SIZE as u32;
@@ -33,20 +38,21 @@ const MAX_MAX_PAGES: usize = 256;
 //const DEFAULT_MAX_PAGES_PER_REQ: usize = 32;
 const BUFFER_HEADER_SIZE: usize = 0x1000;
 
-// TODO: add FUSE_IOCTL_DIR
-const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ
+// The INIT flags are handled as a 64-bit word, where the upper half corresponds to `flags2`.
+const DEFAULT_INIT_FLAGS: u64 = (FUSE_ASYNC_READ
     | FUSE_PARALLEL_DIROPS
     | FUSE_AUTO_INVAL_DATA
     | FUSE_HANDLE_KILLPRIV
     | FUSE_ASYNC_DIO
-    | FUSE_ATOMIC_O_TRUNC;
+    | FUSE_ATOMIC_O_TRUNC) as u64;
 
-const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
+const INIT_FLAGS_MASK: u64 = (FUSE_ASYNC_READ
     | FUSE_ATOMIC_O_TRUNC
     | FUSE_AUTO_INVAL_DATA
     | FUSE_ASYNC_DIO
     | FUSE_PARALLEL_DIROPS
     | FUSE_HANDLE_KILLPRIV
+    | FUSE_HANDLE_KILLPRIV_V2
     | FUSE_POSIX_LOCKS
     | FUSE_FLOCK_LOCKS
     | FUSE_EXPORT_SUPPORT
@@ -54,15 +60,31 @@ const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
     | FUSE_WRITEBACK_CACHE
     | FUSE_POSIX_ACL
     | FUSE_DO_READDIRPLUS
-    | FUSE_READDIRPLUS_AUTO;
+    | FUSE_READDIRPLUS_AUTO
+    | FUSE_HAS_IOCTL_DIR
+    | FUSE_SPLICE_READ
+    | FUSE_SPLICE_WRITE
+    | FUSE_SPLICE_MOVE) as u64
+    | FUSE_DIRECT_IO_ALLOW_MMAP
+    | FUSE_PASSTHROUGH
+    | FUSE_NO_EXPORT_SUPPORT
+    | FUSE_OVER_IO_URING;
+
+// copied from linux/fs.h
+const FILESYSTEM_MAX_STACK_DEPTH: u32 = 2;
+
+const DEFAULT_URING_QUEUE_DEPTH: u16 = 8;
 
 // ==== KernelConfig ====
 
 /// Parameters for setting up the connection with FUSE driver
 /// and the kernel side behavior.
 pub struct KernelConfig {
-    mountopts: MountOptions,
-    init_out: fuse_init_out,
+    pub(crate) mountopts: MountOptions,
+    pub(crate) init_out: fuse_init_out,
+    uring_queue_depth: u16,
+    max_idle_buffers: usize,
+    decode_error_policy: DecodeErrorPolicy,
 }
 
 impl Default for KernelConfig {
@@ -70,6 +92,9 @@ impl Default for KernelConfig {
         Self {
             mountopts: MountOptions::default(),
             init_out: default_init_out(),
+            uring_queue_depth: DEFAULT_URING_QUEUE_DEPTH,
+            max_idle_buffers: DEFAULT_MAX_IDLE_BUFFERS,
+            decode_error_policy: DecodeErrorPolicy::default(),
         }
     }
 }
@@ -112,12 +137,14 @@ impl KernelConfig {
     }
 
     #[inline]
-    fn set_init_flag(&mut self, flag: u32, enabled: bool) {
+    fn set_init_flag(&mut self, flag: impl Into<u64>, enabled: bool) {
+        let mut flags = init_flags(&self.init_out);
         if enabled {
-            self.init_out.flags |= flag;
+            flags |= flag.into();
         } else {
-            self.init_out.flags &= !flag;
+            flags &= !flag.into();
         }
+        set_init_flags(&mut self.init_out, flags);
     }
 
     /// Specify that the filesystem supports asynchronous read requests.
@@ -169,6 +196,15 @@ impl KernelConfig {
         self
     }
 
+    /// Specify that the filesystem is responsible for unsetting setuid and setgid bits
+    /// and uses the `KILL_SUIDGID` request flags to know when to do so.
+    ///
+    /// This option requires ABI 7.33 or later.
+    pub fn handle_killpriv_v2(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_HANDLE_KILLPRIV_V2, enabled);
+        self
+    }
+
     /// The filesystem supports the POSIX-style file lock.
     pub fn posix_locks(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_POSIX_LOCKS, enabled);
@@ -187,6 +223,15 @@ impl KernelConfig {
         self
     }
 
+    /// Specify that the filesystem does not support exporting via NFS,
+    /// even if it handles lookups of `"."` and `".."`.
+    ///
+    /// This option requires ABI 7.40 or later.
+    pub fn no_export_support(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_NO_EXPORT_SUPPORT, enabled);
+        self
+    }
+
     /// Specify that the kernel should not apply the umask to the file mode
     /// on `create` operations.
     pub fn dont_mask(&mut self, enabled: bool) -> &mut Self {
@@ -220,6 +265,131 @@ impl KernelConfig {
         self
     }
 
+    /// Specify that the filesystem supports `ioctl` requests on directories.
+    pub fn ioctl_dir(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_HAS_IOCTL_DIR, enabled);
+        self
+    }
+
+    /// Specify that the kernel allows shared `mmap` on files opened with `direct_io`.
+    ///
+    /// This option requires ABI 7.39 or later.
+    pub fn direct_io_allow_mmap(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_DIRECT_IO_ALLOW_MMAP, enabled);
+        self
+    }
+
+    /// Specify that the filesystem routes read/write requests on opened files
+    /// directly to the backing files registered with `Session::register_backing_fd`.
+    ///
+    /// This option requires ABI 7.40 or later, and it is ignored by the kernel
+    /// if `writeback_cache` is enabled.
+    pub fn passthrough(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_PASSTHROUGH, enabled);
+        self
+    }
+
+    /// Set the maximum stacking depth of the filesystem.
+    ///
+    /// This value is meaningful only if `passthrough` is enabled. The backing files
+    /// must reside on filesystems whose stacking depth is less than this value,
+    /// i.e. 1 means the backing files are not on a stacked filesystem such as overlayfs.
+    ///
+    /// The default value is 1.
+    ///
+    /// # Panics
+    /// It causes an assertion panic if the setting value is 0 or greater than 2.
+    pub fn max_stack_depth(&mut self, depth: u32) -> &mut Self {
+        assert!(
+            depth > 0 && depth <= FILESYSTEM_MAX_STACK_DEPTH,
+            "max_stack_depth must be between 1 and {}",
+            FILESYSTEM_MAX_STACK_DEPTH,
+        );
+        self.init_out.max_stack_depth = depth;
+        self
+    }
+
+    /// Specify that the filesystem reads requests from the kernel using `splice(2)`.
+    ///
+    /// When this option is enabled, the payload of `write` requests is kept in a pipe
+    /// and can be moved into the destination file with `Request::write_data_to`
+    /// without being copied into the userspace buffer.
+    ///
+    /// The pipe must be able to hold the whole request message, so `max_write` should be
+    /// small enough to fit in `/proc/sys/fs/pipe-max-size`. Otherwise, this option is
+    /// disabled at mount time.
+    pub fn splice_read(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_SPLICE_READ, enabled);
+        self
+    }
+
+    /// Specify that the filesystem replies the file data to the kernel using `splice(2)`.
+    ///
+    /// When this option is enabled, `Request::reply_splice` moves the data from the file
+    /// into the kernel without copying it into the userspace buffer.
+    pub fn splice_write(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_SPLICE_WRITE, enabled);
+        self
+    }
+
+    /// Specify that the pages are moved instead of copied when splicing the data.
+    ///
+    /// This option is meaningful only if `splice_read` or `splice_write` is enabled.
+    pub fn splice_move(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_SPLICE_MOVE, enabled);
+        self
+    }
+
+    /// Specify that the requests are transported over io_uring instead of the device file.
+    ///
+    /// This option requires ABI 7.42 or later, and the kernel advertises the support only if
+    /// the `enable_uring` parameter of the fuse module is turned on. If the kernel or the
+    /// io_uring in the running system does not support it, the session falls back to reading
+    /// the requests from the device file.
+    ///
+    /// The kernel requires a queue of ring entries per possible CPU, and each entry holds
+    /// a payload buffer large enough for `max_write` bytes.
+    /// `splice_read` has no effect on the requests received through the ring entries.
+    ///
+    /// The kernel dispatches the requests in the context of the threads calling
+    /// `Session::next_request`, so they should be kept alive until the session ends.
+    pub fn io_uring(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_OVER_IO_URING, enabled);
+        self
+    }
+
+    /// Set the number of ring entries registered in each queue when `io_uring` is enabled.
+    ///
+    /// The default value is 8.
+    ///
+    /// # Panics
+    /// It causes an assertion panic if the setting value is 0.
+    pub fn io_uring_queue_depth(&mut self, depth: u16) -> &mut Self {
+        assert!(depth > 0, "io_uring_queue_depth must be greater than 0");
+        self.uring_queue_depth = depth;
+        self
+    }
+
+    /// Set the maximum number of idle buffers kept for receiving the requests.
+    ///
+    /// The buffers are large enough to hold `max_write` bytes and recycled for the
+    /// subsequent requests instead of being allocated for each request.
+    ///
+    /// The default value is 8.
+    pub fn max_idle_buffers(&mut self, count: usize) -> &mut Self {
+        self.max_idle_buffers = count;
+        self
+    }
+
+    /// Specify how the session handles the requests that fail to be decoded.
+    ///
+    /// By default, such requests are returned from `Session::next_request`, and
+    /// the caller is responsible for replying to them.
+    pub fn decode_error_policy(&mut self, policy: DecodeErrorPolicy) -> &mut Self {
+        self.decode_error_policy = policy;
+        self
+    }
+
     /// Set the maximum readahead.
     pub fn max_readahead(&mut self, value: u32) -> &mut Self {
         self.init_out.max_readahead = value;
@@ -275,6 +445,28 @@ impl KernelConfig {
         self.init_out.time_gran = time_gran;
         self
     }
+
+    /// Disable the features that are not available in this environment.
+    pub(crate) fn probe(&mut self) {
+        if self.init_out.flags & FUSE_SPLICE_READ != 0 {
+            let bufsize = BUFFER_HEADER_SIZE + self.init_out.max_write as usize;
+            if let Err(err) = Pipe::new().and_then(|mut pipe| pipe.reserve(bufsize)) {
+                tracing::warn!(
+                    "splice_read is disabled since a pipe cannot hold {} bytes: {}",
+                    bufsize,
+                    err
+                );
+                self.init_out.flags &= !FUSE_SPLICE_READ;
+            }
+        }
+
+        if init_flags(&self.init_out) & FUSE_OVER_IO_URING != 0 {
+            if let Err(err) = uring::probe() {
+                tracing::warn!("io_uring is disabled since it is not available: {}", err);
+                self.set_init_flag(FUSE_OVER_IO_URING, false);
+            }
+        }
+    }
 }
 
 // ==== Session ====
@@ -282,6 +474,7 @@ impl KernelConfig {
 /// The object containing the contextrual information about a FUSE session.
 pub struct Session {
     inner: Arc<SessionInner>,
+    init_out: fuse_init_out,
 }
 
 impl fmt::Debug for Session {
@@ -290,15 +483,57 @@ impl fmt::Debug for Session {
     }
 }
 
-struct SessionInner {
-    conn: Connection,
-    init_out: fuse_init_out,
+pub(crate) struct SessionInner {
+    conn: Arc<Connection>,
     bufsize: usize,
+    buffers: Arc<BufferPool>,
     exited: AtomicBool,
+    destroyed: AtomicBool,
     notify_unique: AtomicU64,
+    splice_read: bool,
+    splice_write: bool,
+    splice_flags: u32,
+    pipes: Mutex<Vec<Pipe>>,
+    uring: Option<Arc<Transport>>,
+    interrupts: Arc<Registry>,
+    retrieves: Arc<retrieve::Registry>,
+    decode_error_policy: DecodeErrorPolicy,
 }
 
 impl SessionInner {
+    pub(crate) fn new(conn: Connection, bufsize: usize, max_idle_buffers: usize) -> Self {
+        Self {
+            conn: Arc::new(conn),
+            bufsize,
+            buffers: Arc::new(BufferPool::new(
+                bufsize - mem::size_of::<fuse_in_header>(),
+                max_idle_buffers,
+            )),
+            exited: AtomicBool::new(false),
+            destroyed: AtomicBool::new(false),
+            notify_unique: AtomicU64::new(0),
+            splice_read: false,
+            splice_write: false,
+            splice_flags: 0,
+            pipes: Mutex::default(),
+            uring: None,
+            interrupts: Arc::default(),
+            retrieves: Arc::default(),
+            decode_error_policy: DecodeErrorPolicy::default(),
+        }
+    }
+
+    fn get_pipe(&self) -> io::Result<Pipe> {
+        match self.pipes.lock().unwrap().pop() {
+            Some(pipe) => Ok(pipe),
+            None => Pipe::new(),
+        }
+    }
+
+    fn put_pipe(&self, pipe: Pipe) {
+        self.pipes.lock().unwrap().push(pipe);
+    }
+
     #[inline]
     fn exited(&self) -> bool {
         // FIXME: choose appropriate atomic ordering.
@@ -306,15 +541,21 @@ impl SessionInner {
     }
 
     #[inline]
-    fn exit(&self) {
+    pub(crate) fn exit(&self) {
         // FIXME: choose appropriate atomic ordering.
         self.exited.store(true, Ordering::SeqCst)
     }
+
+    #[inline]
+    pub(crate) fn conn(&self) -> &Arc<Connection> {
+        &self.conn
+    }
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
         self.inner.exit();
+        self.inner.retrieves.close();
     }
 }
 
@@ -326,26 +567,56 @@ impl AsRawFd for Session {
 
 impl Session {
     /// Start a FUSE daemon mount on the specified path.
-    pub fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
+    pub fn mount(mountpoint: PathBuf, mut config: KernelConfig) -> io::Result<Self> {
+        config.probe();
+
+        let conn = Connection::open(mountpoint, mem::take(&mut config.mountopts))?;
+
+        init_session(&mut config.init_out, &conn, &conn)?;
+
+        Ok(Self::new(conn, config))
+    }
+
+    /// Create a session over the connection that has completed the handshake.
+    pub(crate) fn new(conn: Connection, config: KernelConfig) -> Self {
         let KernelConfig {
-            mountopts,
-            mut init_out,
+            init_out,
+            uring_queue_depth,
+            max_idle_buffers,
+            decode_error_policy,
+            ..
         } = config;
 
-        let conn = Connection::open(mountpoint, mountopts)?;
-
-        init_session(&mut init_out, &conn, &conn)?;
         let bufsize = BUFFER_HEADER_SIZE + init_out.max_write as usize;
 
-        Ok(Self {
-            inner: Arc::new(SessionInner {
-                conn,
-                init_out,
-                bufsize,
-                exited: AtomicBool::new(false),
-                notify_unique: AtomicU64::new(0),
-            }),
-        })
+        let mut inner = SessionInner::new(conn, bufsize, max_idle_buffers);
+        inner.splice_read = init_out.flags & FUSE_SPLICE_READ != 0;
+        inner.splice_write = init_out.flags & FUSE_SPLICE_WRITE != 0;
+        inner.decode_error_policy = decode_error_policy;
+        if init_out.flags & FUSE_SPLICE_MOVE != 0 {
+            inner.splice_flags |= libc::SPLICE_F_MOVE;
+        }
+
+        if init_flags(&init_out) & FUSE_OVER_IO_URING != 0 {
+            // The requests keep coming through the device file until all the queues are
+            // registered, so it is safe to fall back to the classic transport on failure.
+            match Transport::new(
+                inner.conn.clone(),
+                inner.buffers.clone(),
+                &init_out,
+                uring_queue_depth,
+            ) {
+                Ok(transport) => inner.uring = Some(transport),
+                Err(err) => {
+                    tracing::warn!("failed to set up the io_uring transport: {}", err);
+                }
+            }
+        }
+
+        Self {
+            inner: Arc::new(inner),
+            init_out,
+        }
     }
 
     /// Return whether the kernel supports for zero-message opens.
@@ -355,69 +626,406 @@ impl Session {
     /// subsequent `open` requests.  Otherwise, the filesystem should
     /// implement the handler for `open` requests appropriately.
     pub fn no_open_support(&self) -> bool {
-        self.inner.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
+        self.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
     }
 
     /// Return whether the kernel supports for zero-message opendirs.
     ///
     /// See the documentation of `no_open_support` for details.
     pub fn no_opendir_support(&self) -> bool {
-        self.inner.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+        self.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+    }
+
+    /// Return whether the passthrough of read/write requests is enabled in this session.
+    pub fn passthrough(&self) -> bool {
+        init_flags(&self.init_out) & FUSE_PASSTHROUGH != 0
+    }
+
+    /// Return whether the requests in this session are transported over io_uring.
+    pub fn io_uring(&self) -> bool {
+        self.inner.uring.is_some()
+    }
+
+    /// Register a file descriptor as a backing file for passthrough I/O.
+    ///
+    /// The returned backing ID is attached to an opened file with `OpenOut::backing_id`.
+    /// Since the kernel holds its own reference to the backing file after the reply
+    /// to `open`, the ID may be unregistered at any time after that.
+    ///
+    /// This operation requires `KernelConfig::passthrough` and the `CAP_SYS_ADMIN` capability.
+    pub fn register_backing_fd<T>(&self, fd: &T) -> io::Result<i32>
+    where
+        T: AsRawFd + ?Sized,
+    {
+        self.inner.conn.backing_open(fd.as_raw_fd())
+    }
+
+    /// Unregister a backing file registered with `register_backing_fd`.
+    pub fn unregister_backing_fd(&self, backing_id: i32) -> io::Result<()> {
+        self.inner.conn.backing_close(backing_id)
     }
 
     /// Receive an incoming FUSE request from the kernel.
+    ///
+    /// This method returns `None` when the session is closed, that is,
+    /// after a `FUSE_DESTROY` request has been received or the connection
+    /// with the kernel has been aborted (e.g. by unmounting the filesystem).
     pub fn next_request(&self) -> io::Result<Option<Request>> {
-        let mut conn = &self.inner.conn;
-
-        // FIXME: Align the allocated region in `arg` with the FUSE argument types.
-        let mut header = fuse_in_header::default();
-        let cap = self.inner.bufsize - mem::size_of::<fuse_in_header>();
-        let mut arg = BytesMut::with_capacity(cap);
-        unsafe {
-            arg.set_len(cap);
-        }
-
-        loop {
-            match conn.read_vectored(&mut [
-                io::IoSliceMut::new(header.as_bytes_mut()),
-                io::IoSliceMut::new(&mut arg[..]),
-            ]) {
-                Ok(len) => {
-                    if len < mem::size_of::<fuse_in_header>() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "dequeued request message is too short",
-                        ));
-                    }
-                    arg.truncate(len - mem::size_of::<fuse_in_header>());
-                    break;
-                }
+        match self.inner.uring {
+            Some(ref transport) => next_request_uring(&self.inner, transport),
+            None => next_request(&self.inner, &self.inner.conn, None),
+        }
+    }
 
-                Err(err) => match err.raw_os_error() {
-                    Some(libc::ENODEV) => {
-                        tracing::debug!("ENODEV");
-                        return Ok(None);
-                    }
-                    Some(libc::ENOENT) => {
-                        tracing::debug!("ENOENT");
-                        continue;
-                    }
-                    _ => return Err(err),
-                },
-            }
+    /// Receive an incoming FUSE request from the kernel into the specified buffer.
+    ///
+    /// Small arguments are copied out of the buffer, while large ones (e.g. the payload of
+    /// `write` requests) share the allocation with `buf` until the returned request is dropped,
+    /// after which the allocation is reused by the subsequent calls with the same buffer.
+    ///
+    /// The buffer is not used if the request is received via `splice(2)` or io_uring.
+    pub fn next_request_into(&self, buf: &mut RequestBuffer) -> io::Result<Option<Request>> {
+        match self.inner.uring {
+            Some(ref transport) => next_request_uring(&self.inner, transport),
+            None => next_request(&self.inner, &self.inner.conn, Some(buf)),
         }
+    }
 
-        Ok(Some(Request {
+    /// Create a new channel to receive the requests in this session.
+    ///
+    /// Each channel has its own queue in the kernel, and the requests received
+    /// from a channel are replied through that channel. Assigning a channel to each
+    /// worker thread avoids the contention on the single device file descriptor.
+    ///
+    /// This method requires `/dev/fuse` to be accessible by the calling process.
+    /// If the requests are transported over io_uring, the channels receive only the
+    /// messages not dispatched to the ring entries, such as `forget` and `interrupt`.
+    pub fn clone_channel(&self) -> io::Result<Channel> {
+        let conn = self.inner.conn.clone_device()?;
+        Ok(Channel {
             session: self.inner.clone(),
-            header,
-            arg: arg.freeze(),
-        }))
+            conn: Arc::new(conn),
+        })
     }
 
     /// Create an instance of `Notifier` corresponding to this session.
     pub fn notifier(&self) -> Notifier {
-        Notifier {
-            session: self.inner.clone(),
+        Notifier::new(self.inner.clone())
+    }
+}
+
+// ==== Channel ====
+
+/// A channel for receiving the requests in a session, created by `Session::clone_channel`.
+pub struct Channel {
+    session: Arc<SessionInner>,
+    conn: Arc<Connection>,
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channel").finish()
+    }
+}
+
+impl AsRawFd for Channel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl Channel {
+    /// Receive an incoming FUSE request from the kernel through this channel.
+    ///
+    /// See the documentation of `Session::next_request` for details.
+    pub fn next_request(&self) -> io::Result<Option<Request>> {
+        next_request(&self.session, &self.conn, None)
+    }
+
+    /// Receive an incoming FUSE request through this channel into the specified buffer.
+    ///
+    /// See the documentation of `Session::next_request_into` for details.
+    pub fn next_request_into(&self, buf: &mut RequestBuffer) -> io::Result<Option<Request>> {
+        next_request(&self.session, &self.conn, Some(buf))
+    }
+}
+
+pub(crate) fn next_request(
+    session: &Arc<SessionInner>,
+    conn: &Arc<Connection>,
+    mut buf: Option<&mut RequestBuffer>,
+) -> io::Result<Option<Request>> {
+    loop {
+        // FIXME: choose appropriate atomic ordering.
+        if session.destroyed.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+
+        let req = if session.splice_read {
+            receive_splice(session, conn)?
+        } else {
+            receive(session, conn, buf.as_deref_mut())?
+        };
+
+        match req {
+            Some(req) => {
+                if let Some(req) = track(session, req) {
+                    return Ok(Some(req));
+                }
+            }
+            None => {
+                session.retrieves.close();
+                return Ok(None);
+            }
+        }
+    }
+}
+
+fn next_request_uring(
+    session: &Arc<SessionInner>,
+    transport: &Arc<Transport>,
+) -> io::Result<Option<Request>> {
+    loop {
+        // FIXME: choose appropriate atomic ordering.
+        if session.destroyed.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+
+        let req = transport.receive()?.map(
+            |Received {
+                 header,
+                 arg,
+                 commit,
+             }| Request {
+                session: session.clone(),
+                conn: session.conn.clone(),
+                header,
+                arg,
+                data: None,
+                commit: commit.map(Arc::new),
+                tracked: None,
+                replied: Arc::default(),
+            },
+        );
+
+        match req {
+            Some(req) => {
+                if let Some(req) = track(session, req) {
+                    return Ok(Some(req));
+                }
+            }
+            None => {
+                session.retrieves.close();
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Update the state of the session by a received request.
+///
+/// `None` is returned if the request is consumed by the session.
+fn track(session: &SessionInner, mut req: Request) -> Option<Request> {
+    if let DecodeErrorPolicy::Reply(..) = session.decode_error_policy {
+        if let Err(err) = req.operation() {
+            if let Err(err) = req.reply_undecodable(&err) {
+                tracing::error!("failed to reply to the undecodable request: {}", err);
+            }
+            return None;
+        }
+    }
+
+    match req.header.opcode {
+        FUSE_DESTROY => {
+            // The kernel sends no more requests after FUSE_DESTROY.
+            tracing::debug!("FUSE_DESTROY");
+            // FIXME: choose appropriate atomic ordering.
+            session.destroyed.store(true, Ordering::SeqCst);
+            session.retrieves.close();
+        }
+        FUSE_INTERRUPT => {
+            let mut arg = fuse_interrupt_in::default();
+            if let Some(bytes) = req.arg.get(..mem::size_of::<fuse_interrupt_in>()) {
+                arg.as_bytes_mut().copy_from_slice(bytes);
+                session
+                    .interrupts
+                    .interrupt(req.header.unique, arg.unique, &req.conn);
+            }
+        }
+        FUSE_NOTIFY_REPLY => {
+            let len = mem::size_of::<fuse_notify_retrieve_in>();
+            if req.arg.len() >= len {
+                let mut arg = fuse_notify_retrieve_in::default();
+                arg.as_bytes_mut().copy_from_slice(&req.arg[..len]);
+                let size = cmp::min(arg.size as usize, req.arg.len() - len);
+                let data = req.arg.slice(len..len + size);
+                if session
+                    .retrieves
+                    .complete(req.header.unique, arg.offset, data)
+                {
+                    return None;
+                }
+            }
+        }
+        // The kernel does not interrupt the requests that expect no reply.
+        FUSE_FORGET | FUSE_BATCH_FORGET => (),
+        _ => req.tracked = Some(Arc::new(session.interrupts.track(req.header.unique))),
+    }
+    Some(req)
+}
+
+fn receive(
+    session: &Arc<SessionInner>,
+    conn: &Arc<Connection>,
+    buf: Option<&mut RequestBuffer>,
+) -> io::Result<Option<Request>> {
+    let cap = session.buffers.capacity();
+    let (header, arg) = match buf {
+        Some(buf) => match receive_into(conn, buf.get_mut(cap))? {
+            Some((header, len)) => (header, buf.take_arg(len, header.opcode)),
+            None => return Ok(None),
+        },
+        None => {
+            let mut buf = session.buffers.get();
+            match receive_into(conn, &mut buf) {
+                Ok(Some((header, len))) => {
+                    (header, session.buffers.take_arg(buf, len, header.opcode))
+                }
+                Ok(None) => {
+                    session.buffers.put(buf);
+                    return Ok(None);
+                }
+                Err(err) => {
+                    session.buffers.put(buf);
+                    return Err(err);
+                }
+            }
+        }
+    };
+
+    Ok(Some(Request {
+        session: session.clone(),
+        conn: conn.clone(),
+        header,
+        arg,
+        data: None,
+        commit: None,
+        tracked: None,
+        replied: Arc::default(),
+    }))
+}
+
+/// Read a request message from the device, storing its argument part into `buf`.
+///
+/// The header and the length of the argument part are returned.
+fn receive_into(
+    conn: &Connection,
+    buf: &mut AlignedBuf,
+) -> io::Result<Option<(fuse_in_header, usize)>> {
+    let mut reader = conn;
+    let mut header = fuse_in_header::default();
+
+    let len = match receive_with(|| {
+        reader.read_vectored(&mut [
+            io::IoSliceMut::new(header.as_bytes_mut()),
+            io::IoSliceMut::new(buf.arg_mut()),
+        ])
+    })? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    Ok(Some((header, len - mem::size_of::<fuse_in_header>())))
+}
+
+fn receive_splice(
+    session: &Arc<SessionInner>,
+    conn: &Arc<Connection>,
+) -> io::Result<Option<Request>> {
+    let mut pipe = session.get_pipe()?;
+    pipe.reserve(session.bufsize)?;
+
+    let fd = conn.as_raw_fd();
+    let len = match receive_with(|| pipe.splice_from(fd, None, session.bufsize, 0)) {
+        Ok(Some(len)) => len,
+        Ok(None) => return Ok(None),
+        Err(err) => {
+            // The pipe is still empty, e.g. after `WouldBlock` from the non-blocking device.
+            session.put_pipe(pipe);
+            return Err(err);
+        }
+    };
+
+    let mut header = fuse_in_header::default();
+    (&pipe).read_exact(header.as_bytes_mut())?;
+    let arg_len = len - mem::size_of::<fuse_in_header>();
+
+    // Keep the payload of write requests in the pipe,
+    // so that it can be moved into the destination without copying.
+    let (arg, data) = match header.opcode {
+        FUSE_WRITE if arg_len > mem::size_of::<fuse_write_in>() => {
+            let mut arg = [0u8; mem::size_of::<fuse_write_in>()];
+            (&pipe).read_exact(&mut arg[..])?;
+            let data = SplicedData {
+                pipe,
+                len: arg_len - arg.len(),
+            };
+            (
+                buf::copy_arg(header.opcode, &[&arg[..]]),
+                Some(Arc::new(Mutex::new(Some(data)))),
+            )
+        }
+        _ => {
+            let mut buf = session.buffers.get();
+            (&pipe).read_exact(&mut buf.arg_mut()[..arg_len])?;
+            session.put_pipe(pipe);
+            (session.buffers.take_arg(buf, arg_len, header.opcode), None)
+        }
+    };
+
+    Ok(Some(Request {
+        session: session.clone(),
+        conn: conn.clone(),
+        header,
+        arg,
+        data,
+        commit: None,
+        tracked: None,
+        replied: Arc::default(),
+    }))
+}
+
+/// Receive a request message from the kernel, retrying if the request has been
+/// interrupted before being dequeued.
+///
+/// `None` is returned if the connection has been aborted.
+fn receive_with<F>(mut f: F) -> io::Result<Option<usize>>
+where
+    F: FnMut() -> io::Result<usize>,
+{
+    loop {
+        match f() {
+            Ok(len) => {
+                if len < mem::size_of::<fuse_in_header>() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "dequeued request message is too short",
+                    ));
+                }
+                return Ok(Some(len));
+            }
+
+            Err(err) => match err.raw_os_error() {
+                Some(libc::ENODEV) => {
+                    tracing::debug!("ENODEV");
+                    return Ok(None);
+                }
+                Some(libc::ENOENT) => {
+                    tracing::debug!("ENOENT");
+                    continue;
+                }
+                _ => return Err(err),
+            },
         }
     }
 }
@@ -427,19 +1035,54 @@ where
     R: io::Read,
     W: io::Write,
 {
-    // FIXME: align the allocated buffer in `buf` with FUSE argument types.
-    let mut header = fuse_in_header::default();
-    let cap = pagesize() * MAX_MAX_PAGES;
-    let mut arg = Vec::with_capacity(cap);
-    unsafe {
-        arg.set_len(cap);
+    let mut handshake = Handshake::new();
+    while !handshake.step(init_out, &mut reader, &mut writer)? {}
+    Ok(())
+}
+
+/// The exchange of `FUSE_INIT` messages at the beginning of a session.
+pub(crate) struct Handshake {
+    buf: AlignedBuf,
+    attempts: usize,
+}
+
+impl Handshake {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: AlignedBuf::with_arg_capacity(pagesize() * MAX_MAX_PAGES),
+            attempts: 0,
+        }
     }
 
-    for _ in 0..10 {
+    /// Process a message from the kernel, and return `true` once the session is initialized.
+    ///
+    /// The state is left untouched if reading from the device fails, so that the call
+    /// can be retried after a `WouldBlock` error from the non-blocking device.
+    pub(crate) fn step<R, W>(
+        &mut self,
+        init_out: &mut fuse_init_out,
+        mut reader: R,
+        mut writer: W,
+    ) -> io::Result<bool>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        if self.attempts >= 10 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "session initialization is aborted",
+            ));
+        }
+
+        let mut header = fuse_in_header::default();
+        let arg = self.buf.arg_mut();
+
         let len = reader.read_vectored(&mut [
             io::IoSliceMut::new(header.as_bytes_mut()),
             io::IoSliceMut::new(&mut arg[..]),
         ])?;
+        self.attempts += 1;
         if len < mem::size_of::<fuse_in_header>() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -447,31 +1090,45 @@ where
             ));
         }
 
-        let mut decoder = Decoder::new(&arg[..]);
+        let arg_len = len - mem::size_of::<fuse_in_header>();
 
         match header.opcode {
             FUSE_INIT => {
-                let init_in = decoder
-                    .fetch::<fuse_init_in>() //
-                    .map_err(|_| {
-                        io::Error::new(io::ErrorKind::Other, "failed to decode fuse_init_in")
-                    })?;
+                // The kernel before ABI 7.36 sends only the compat part of `fuse_init_in`.
+                if arg_len < FUSE_COMPAT_INIT_IN_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "failed to decode fuse_init_in",
+                    ));
+                }
+                let mut init_in = fuse_init_in::default();
+                let init_in_len = cmp::min(arg_len, mem::size_of::<fuse_init_in>());
+                init_in.as_bytes_mut()[..init_in_len].copy_from_slice(&arg[..init_in_len]);
 
-                let capable = init_in.flags & INIT_FLAGS_MASK;
-                let readonly_flags = init_in.flags & !INIT_FLAGS_MASK;
+                let mut in_flags = init_in.flags as u64;
+                if init_in.flags & FUSE_INIT_EXT != 0 {
+                    in_flags |= (init_in.flags2 as u64) << 32;
+                }
+
+                let capable = in_flags & INIT_FLAGS_MASK;
+                let readonly_flags = in_flags & !INIT_FLAGS_MASK;
 
                 tracing::debug!("INIT request:");
                 tracing::debug!("  proto = {}.{}:", init_in.major, init_in.minor);
                 tracing::debug!("  flags = 0x{:08x} ({:?})", init_in.flags, capable);
+                tracing::debug!("  flags2 = 0x{:08x}", init_in.flags2);
                 tracing::debug!("  max_readahead = 0x{:08X}", init_in.max_readahead);
-                tracing::debug!("  max_pages = {}", readonly_flags & FUSE_MAX_PAGES != 0);
+                tracing::debug!(
+                    "  max_pages = {}",
+                    readonly_flags & FUSE_MAX_PAGES as u64 != 0
+                );
                 tracing::debug!(
                     "  no_open_support = {}",
-                    readonly_flags & FUSE_NO_OPEN_SUPPORT != 0
+                    readonly_flags & FUSE_NO_OPEN_SUPPORT as u64 != 0
                 );
                 tracing::debug!(
                     "  no_opendir_support = {}",
-                    readonly_flags & FUSE_NO_OPENDIR_SUPPORT != 0
+                    readonly_flags & FUSE_NO_OPENDIR_SUPPORT as u64 != 0
                 );
 
                 if init_in.major > 7 {
@@ -485,7 +1142,7 @@ where
                         &mut writer,
                         Reply::new(header.unique, 0, init_out.as_bytes()),
                     )?;
-                    continue;
+                    return Ok(false);
                 }
 
                 if init_in.major < 7 || init_in.minor < MINIMUM_SUPPORTED_MINOR_VERSION {
@@ -496,15 +1153,31 @@ where
                         init_in.minor
                     );
                     write_bytes(&mut writer, Reply::new(header.unique, libc::EPROTO, ()))?;
-                    continue;
+                    return Ok(false);
                 }
 
                 init_out.minor = cmp::min(init_out.minor, init_in.minor);
 
                 init_out.max_readahead = cmp::min(init_out.max_readahead, init_in.max_readahead);
 
-                init_out.flags &= capable;
-                init_out.flags |= FUSE_BIG_WRITES; // the flag was superseded by `max_write`.
+                let mut flags = init_flags(init_out) & capable;
+                flags |= FUSE_BIG_WRITES as u64; // the flag was superseded by `max_write`.
+
+                // The kernel reads `flags2` only if FUSE_INIT_EXT is set in the reply.
+                if init_in.flags & FUSE_INIT_EXT != 0 {
+                    flags |= FUSE_INIT_EXT as u64;
+                }
+
+                // The kernel does not enable the passthrough mode with writeback caching.
+                if flags & FUSE_PASSTHROUGH != 0 && flags & FUSE_WRITEBACK_CACHE as u64 != 0 {
+                    tracing::warn!("passthrough is disabled since writeback_cache is enabled");
+                    flags &= !FUSE_PASSTHROUGH;
+                }
+                if flags & FUSE_PASSTHROUGH == 0 {
+                    init_out.max_stack_depth = 0;
+                }
+
+                set_init_flags(init_out, flags);
 
                 if init_in.flags & FUSE_MAX_PAGES != 0 {
                     init_out.flags |= FUSE_MAX_PAGES;
@@ -520,6 +1193,7 @@ where
                 tracing::debug!("Reply to INIT:");
                 tracing::debug!("  proto = {}.{}:", init_out.major, init_out.minor);
                 tracing::debug!("  flags = 0x{:08x}", init_out.flags);
+                tracing::debug!("  flags2 = 0x{:08x}", init_out.flags2);
                 tracing::debug!("  max_readahead = 0x{:08X}", init_out.max_readahead);
                 tracing::debug!("  max_write = 0x{:08X}", init_out.max_write);
                 tracing::debug!("  max_background = 0x{:04X}", init_out.max_background);
@@ -528,11 +1202,12 @@ where
                     init_out.congestion_threshold
                 );
                 tracing::debug!("  time_gran = {}", init_out.time_gran);
+                tracing::debug!("  max_stack_depth = {}", init_out.max_stack_depth);
                 write_bytes(writer, Reply::new(header.unique, 0, init_out.as_bytes()))?;
 
-                init_out.flags |= readonly_flags;
+                set_init_flags(init_out, init_flags(init_out) | readonly_flags);
 
-                return Ok(());
+                Ok(true)
             }
 
             _ => {
@@ -541,25 +1216,35 @@ where
                     header.opcode
                 );
                 write_bytes(&mut writer, Reply::new(header.unique, libc::EIO, ()))?;
-                continue;
+                Ok(false)
             }
         }
     }
-
-    Err(io::Error::new(
-        io::ErrorKind::ConnectionRefused,
-        "session initialization is aborted",
-    ))
 }
 
 // ==== Request ====
 
 /// Context about an incoming FUSE request.
+///
+/// A request is replied at most once, including through its clones. The subsequent
+/// replies fail with `io::ErrorKind::InvalidInput`.
 #[derive(Clone)]
 pub struct Request {
     session: Arc<SessionInner>,
+    conn: Arc<Connection>,
     header: fuse_in_header,
     arg: Bytes,
+    data: Option<Arc<Mutex<Option<SplicedData>>>>,
+    commit: Option<Arc<Commit>>,
+    tracked: Option<Arc<Tracked>>,
+    /// Whether the request has been replied, shared among the clones.
+    replied: Arc<AtomicBool>,
+}
+
+/// The payload of a `write` request remaining in the pipe.
+struct SplicedData {
+    pipe: Pipe,
+    len: usize,
 }
 
 impl Request {
@@ -587,17 +1272,38 @@ impl Request {
         self.header.pid
     }
 
+    /// Return a token to observe whether this request is interrupted by the kernel.
+    ///
+    /// The tokens of the requests that expect no reply, such as `forget`, are never
+    /// cancelled.
+    pub fn cancel_token(&self) -> CancelToken {
+        match self.tracked {
+            Some(ref tracked) => tracked.token(),
+            None => CancelToken::never(),
+        }
+    }
+
+    /// Return whether this request has been interrupted by the kernel.
+    pub fn is_interrupted(&self) -> bool {
+        self.cancel_token().is_cancelled()
+    }
+
     /// Decode the argument of this request.
+    ///
+    /// If `KernelConfig::splice_read` is enabled, the payload of `write` requests
+    /// is not contained in `Operation::Write`. Use `write_data_to` to obtain it instead.
+    ///
+    /// After the session has exited, the request is not decoded and `Operation::Unknown`
+    /// is returned regardless of the opcode (see `op::Unknown::is_exited`).
     pub fn operation(&self) -> Result<Operation<'_, Bytes>, DecodeError> {
         if self.session.exited() {
-            return Ok(Operation::unknown());
+            return Ok(Operation::exited(&self.header, &self.arg));
         }
 
         let (arg, data) = match self.header.opcode {
-            FUSE_WRITE | FUSE_NOTIFY_REPLY => {
+            FUSE_WRITE | FUSE_NOTIFY_REPLY if self.arg.len() >= mem::size_of::<fuse_write_in>() => {
                 let (arg, data) = self.arg.split_at(mem::size_of::<fuse_write_in>());
-                let data = self.arg.slice_ref(data);
-                (arg, data)
+                (arg, self.arg.slice_ref(data))
             }
             _ => (&self.arg[..], Bytes::new()),
         };
@@ -605,15 +1311,263 @@ impl Request {
         Operation::decode(&self.header, arg, data)
     }
 
+    /// Log the error of a request that cannot be decoded, and reply to the request.
+    ///
+    /// The request is replied with the error number of `DecodeErrorPolicy::Reply`,
+    /// or `EIO` if the other policy is used. The requests that take no reply, such as
+    /// `forget`, are not replied.
+    pub(crate) fn reply_undecodable(&self, err: &DecodeError) -> io::Result<()> {
+        tracing::error!("{}", err);
+        let errno = match self.session.decode_error_policy {
+            DecodeErrorPolicy::Reply(errno) => errno,
+            DecodeErrorPolicy::Return => Errno::EIO,
+        };
+        if self.expects_reply() {
+            self.reply_error(errno.raw())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reply to the request with the specified error number, unless the request
+    /// has already been replied or takes no reply.
+    ///
+    /// This is used to complete the requests whose handler has failed, so that the
+    /// kernel does not wait for their replies forever.
+    pub(crate) fn reply_error_if_unreplied(&self, code: i32) -> io::Result<()> {
+        if self.expects_reply() && !self.is_replied() {
+            self.reply_error(code)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn expects_reply(&self) -> bool {
+        !matches!(
+            self.header.opcode,
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT | FUSE_NOTIFY_REPLY
+        )
+    }
+
+    /// Write the payload of a `write` request into the specified file at `offset`.
+    ///
+    /// If `KernelConfig::splice_read` is enabled, the payload is moved from the pipe
+    /// into the file with `splice(2)`, without being copied into the userspace buffer.
+    /// Otherwise, the data is written using `pwrite(2)`.
+    ///
+    /// This method returns the number of written bytes.
+    pub fn write_data_to<T>(&self, fd: &T, offset: u64) -> io::Result<usize>
+    where
+        T: AsRawFd + ?Sized,
+    {
+        if self.header.opcode != FUSE_WRITE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the request is not a write request",
+            ));
+        }
+
+        if let Some(ref spliced) = self.data {
+            let SplicedData { pipe, len } = spliced.lock().unwrap().take().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the payload has already been consumed",
+                )
+            })?;
+
+            let mut offset = offset as i64;
+            let mut remaining = len;
+            while remaining > 0 {
+                let n = pipe.splice_to(
+                    fd.as_raw_fd(),
+                    Some(&mut offset),
+                    remaining,
+                    self.session.splice_flags,
+                )?;
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                remaining -= n;
+            }
+            self.session.put_pipe(pipe);
+
+            return Ok(len);
+        }
+
+        let data = self
+            .arg
+            .get(mem::size_of::<fuse_write_in>()..)
+            .unwrap_or(&[]);
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) });
+        file.write_all_at(data, offset)?;
+
+        Ok(data.len())
+    }
+
     pub fn reply<T>(&self, arg: T) -> io::Result<()>
     where
         T: AtomicBytes,
     {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), 0, arg))
+        self.send_reply(Reply::new(self.unique(), 0, arg))
     }
 
+    /// Reply to the kernel with an error number.
+    ///
+    /// If `code` is not a valid error number (see `Errno::new`), the request is
+    /// replied with `EIO` instead of sending a malformed reply.
     pub fn reply_error(&self, code: i32) -> io::Result<()> {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), code, ()))
+        let errno = Errno::new(code).unwrap_or_else(|| {
+            tracing::warn!("invalid error number {}, reply EIO instead", code);
+            Errno::EIO
+        });
+        self.send_reply(Reply::new(self.unique(), errno.raw(), ()))
+    }
+
+    /// Reply to the kernel with the result of a handler.
+    pub fn reply_result<T>(&self, res: Result<T, Errno>) -> io::Result<()>
+    where
+        T: AtomicBytes,
+    {
+        match res {
+            Ok(arg) => self.reply(arg),
+            Err(errno) => self.reply_error(errno.raw()),
+        }
+    }
+
+    /// Return whether the request has been replied through this request or its clones.
+    pub(crate) fn is_replied(&self) -> bool {
+        self.replied.load(Ordering::Acquire)
+    }
+
+    /// Mark the request as replied before sending the reply, and run `f` to send it.
+    ///
+    /// The mark is kept even if `f` fails, except for `WouldBlock` from the device file
+    /// in the non-blocking mode, after which the reply is retried by the async runtimes.
+    /// The ring entry of an io_uring request is committed with `EIO` on failure, so its
+    /// reply is never retried.
+    fn with_reply<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        if self.replied.swap(true, Ordering::AcqRel) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the request has already been replied",
+            ));
+        }
+        let res = f();
+        if let Err(ref err) = res {
+            if self.commit.is_none() && err.kind() == io::ErrorKind::WouldBlock {
+                self.replied.store(false, Ordering::Release);
+            }
+        }
+        res
+    }
+
+    fn send_reply<T>(&self, reply: Reply<T>) -> io::Result<()>
+    where
+        T: AtomicBytes,
+    {
+        self.with_reply(|| self.write_reply(reply))
+    }
+
+    fn write_reply<T>(&self, reply: Reply<T>) -> io::Result<()>
+    where
+        T: AtomicBytes,
+    {
+        match self.commit {
+            Some(ref commit) => commit.send(reply),
+            None => write_bytes(&*self.conn, reply),
+        }
+    }
+
+    /// Reply to the kernel with at most `len` bytes of data read from the file at `offset`.
+    ///
+    /// If `KernelConfig::splice_write` is enabled, the data is moved from the file into
+    /// the kernel with `splice(2)`, without being copied into the userspace buffer.
+    /// Otherwise, the data is read into a temporary buffer using `pread(2)`.
+    pub fn reply_splice<T>(&self, fd: &T, offset: u64, len: usize) -> io::Result<()>
+    where
+        T: AsRawFd + ?Sized,
+    {
+        self.with_reply(|| self.write_splice(fd.as_raw_fd(), offset, len))
+    }
+
+    fn write_splice(&self, fd: RawFd, offset: u64, len: usize) -> io::Result<()> {
+        if self.session.splice_write
+            && self.commit.is_none()
+            && self.try_reply_splice(fd, offset, len)?
+        {
+            return Ok(());
+        }
+
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let mut buf = vec![0u8; len];
+        let mut nread = 0;
+        while nread < len {
+            match file.read_at(&mut buf[nread..], offset + nread as u64) {
+                Ok(0) => break,
+                Ok(n) => nread += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        self.write_reply(Reply::new(self.unique(), 0, &buf[..nread]))
+    }
+
+    fn try_reply_splice(&self, fd: RawFd, offset: u64, len: usize) -> io::Result<bool> {
+        let session = &*self.session;
+        let header_len = mem::size_of::<fuse_out_header>();
+
+        // Since the data may be shorter than `len`, it is filled into the separate pipe
+        // at first and then moved after the header.
+        let mut data = session.get_pipe()?;
+        let mut msg = session.get_pipe()?;
+        if data.reserve(len + 2 * pagesize()).is_err() || msg.reserve(len + 3 * pagesize()).is_err()
+        {
+            tracing::debug!("pipe is too small to splice {} bytes", len);
+            session.put_pipe(data);
+            session.put_pipe(msg);
+            return Ok(false);
+        }
+
+        let mut offset = offset as i64;
+        let mut nread = 0;
+        while nread < len {
+            let n = data.splice_from(fd, Some(&mut offset), len - nread, session.splice_flags)?;
+            if n == 0 {
+                break;
+            }
+            nread += n;
+        }
+
+        let header = fuse_out_header {
+            len: (header_len + nread)
+                .try_into()
+                .expect("Argument size is too large"),
+            error: 0,
+            unique: self.unique(),
+        };
+        (&msg).write_all(header.as_bytes())?;
+        msg.splice_all_from(&data, nread, session.splice_flags)?;
+
+        let written = msg.splice_to(
+            self.conn.as_raw_fd(),
+            None,
+            header_len + nread,
+            session.splice_flags,
+        )?;
+        if written < header_len + nread {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "written data is too short",
+            ));
+        }
+
+        session.put_pipe(data);
+        session.put_pipe(msg);
+
+        Ok(true)
     }
 }
 
@@ -625,6 +1579,10 @@ pub struct Notifier {
 }
 
 impl Notifier {
+    pub(crate) fn new(session: Arc<SessionInner>) -> Self {
+        Self { session }
+    }
+
     /// Notify the cache invalidation about an inode to the kernel.
     pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
         let total_len = u32::try_from(
@@ -633,7 +1591,7 @@ impl Notifier {
         .unwrap();
 
         return write_bytes(
-            &self.session.conn,
+            &*self.session.conn,
             InvalInode {
                 header: fuse_out_header {
                     len: total_len,
@@ -680,7 +1638,7 @@ impl Notifier {
         .unwrap();
 
         return write_bytes(
-            &self.session.conn,
+            &*self.session.conn,
             InvalEntry {
                 header: fuse_out_header {
                     len: total_len,
@@ -690,7 +1648,7 @@ impl Notifier {
                 arg: fuse_notify_inval_entry_out {
                     parent,
                     namelen,
-                    padding: 0,
+                    flags: 0,
                 },
                 name,
             },
@@ -746,7 +1704,7 @@ impl Notifier {
         .expect("payload is too long");
 
         return write_bytes(
-            &self.session.conn,
+            &*self.session.conn,
             Delete {
                 header: fuse_out_header {
                     len: total_len,
@@ -807,7 +1765,7 @@ impl Notifier {
         .expect("payload is too long");
 
         return write_bytes(
-            &self.session.conn,
+            &*self.session.conn,
             Store {
                 header: fuse_out_header {
                     len: total_len,
@@ -853,17 +1811,43 @@ impl Notifier {
     }
 
     /// Retrieve data in an inode from the kernel cache.
+    ///
+    /// The retrieved data is delivered as `Operation::NotifyReply` with the returned
+    /// unique ID. Use `retrieve_data` to receive it without matching the replies by hand.
     pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
+        // FIXME: choose appropriate memory ordering.
+        let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
+        self.send_retrieve(notify_unique, ino, offset, size)?;
+        Ok(notify_unique)
+    }
+
+    /// Retrieve data in an inode from the kernel cache, and return a handle to receive it.
+    ///
+    /// The session passes the reply from the kernel to the returned handle, and it is
+    /// not returned from `Session::next_request`. Note that the requests must still be
+    /// received from the session to receive the reply.
+    pub fn retrieve_data(&self, ino: u64, offset: u64, size: u32) -> io::Result<RetrieveHandle> {
+        // FIXME: choose appropriate memory ordering.
+        let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
+        let handle = self.session.retrieves.register(notify_unique);
+        self.send_retrieve(notify_unique, ino, offset, size)?;
+        Ok(handle)
+    }
+
+    fn send_retrieve(
+        &self,
+        notify_unique: u64,
+        ino: u64,
+        offset: u64,
+        size: u32,
+    ) -> io::Result<()> {
         let total_len = u32::try_from(
             mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_retrieve_out>(),
         )
         .unwrap();
 
-        // FIXME: choose appropriate memory ordering.
-        let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
-
-        write_bytes(
-            &self.session.conn,
+        return write_bytes(
+            &*self.session.conn,
             Retrieve {
                 header: fuse_out_header {
                     len: total_len,
@@ -878,9 +1862,7 @@ impl Notifier {
                     padding: 0,
                 },
             },
-        )?;
-
-        return Ok(notify_unique);
+        );
 
         struct Retrieve {
             header: fuse_out_header,
@@ -910,7 +1892,7 @@ impl Notifier {
         .unwrap();
 
         return write_bytes(
-            &self.session.conn,
+            &*self.session.conn,
             PollWakeup {
                 header: fuse_out_header {
                     len: total_len,
@@ -944,7 +1926,7 @@ impl Notifier {
 
 // ==== utils ====
 
-struct Reply<T> {
+pub(crate) struct Reply<T> {
     header: fuse_out_header,
     arg: T,
 }
@@ -953,7 +1935,7 @@ where
     T: AtomicBytes,
 {
     #[inline]
-    fn new(unique: u64, error: i32, arg: T) -> Self {
+    pub(crate) fn new(unique: u64, error: i32, arg: T) -> Self {
         let len = (mem::size_of::<fuse_out_header>() + arg.size())
             .try_into()
             .expect("Argument size is too large");
@@ -988,7 +1970,7 @@ where
 }
 
 #[inline]
-fn write_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
+pub(crate) fn write_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
 where
     W: io::Write,
     T: AtomicBytes,
@@ -1082,21 +2064,93 @@ const fn default_init_out() -> fuse_init_out {
         major: FUSE_KERNEL_VERSION,
         minor: FUSE_KERNEL_MINOR_VERSION,
         max_readahead: u32::MAX,
-        flags: DEFAULT_INIT_FLAGS,
+        flags: DEFAULT_INIT_FLAGS as u32,
         max_background: 0,
         congestion_threshold: 0,
         max_write: DEFAULT_MAX_WRITE,
         time_gran: 1,
         max_pages: 0,
-        padding: 0,
-        unused: [0; 8],
+        map_alignment: 0,
+        flags2: (DEFAULT_INIT_FLAGS >> 32) as u32,
+        max_stack_depth: 1,
+        unused: [0; 6],
+    }
+}
+
+#[inline]
+fn init_flags(init_out: &fuse_init_out) -> u64 {
+    init_out.flags as u64 | (init_out.flags2 as u64) << 32
+}
+
+#[inline]
+fn set_init_flags(init_out: &mut fuse_init_out, flags: u64) {
+    init_out.flags = flags as u32;
+    init_out.flags2 = (flags >> 32) as u32;
+}
+
+/// A session connected to a fake kernel, for testing the request handling.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::{os::unix::net::UnixStream, time::Duration};
+
+    pub(crate) struct FakeKernel {
+        socket: UnixStream,
+    }
+
+    pub(crate) fn session(config: KernelConfig) -> (Session, FakeKernel) {
+        let (conn, socket) = Connection::socketpair().unwrap();
+        let session = Session::new(conn, config);
+        (session, FakeKernel { socket })
+    }
+
+    impl FakeKernel {
+        /// Send a request message to the session.
+        pub(crate) fn send(&self, opcode: u32, unique: u64, nodeid: u64, arg: &[&[u8]]) {
+            let len = mem::size_of::<fuse_in_header>() + arg.iter().map(|a| a.len()).sum::<usize>();
+            let header = fuse_in_header {
+                len: len as u32,
+                opcode,
+                unique,
+                nodeid,
+                ..Default::default()
+            };
+            let mut msg = header.as_bytes().to_vec();
+            for chunk in arg {
+                msg.extend_from_slice(chunk);
+            }
+            (&self.socket).write_all(&msg).unwrap();
+        }
+
+        /// Receive a reply or notification message from the session, if any.
+        ///
+        /// `None` is also returned after the session is dropped.
+        pub(crate) fn recv(&self) -> Option<(fuse_out_header, Vec<u8>)> {
+            self.socket
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            let mut msg = vec![0u8; 0x10000];
+            let len = match (&self.socket).read(&mut msg[..]) {
+                Ok(0) => return None,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(err) => panic!("failed to receive a message: {}", err),
+            };
+            let mut header = fuse_out_header::default();
+            header
+                .as_bytes_mut()
+                .copy_from_slice(&msg[..mem::size_of::<fuse_out_header>()]);
+            assert_eq!(header.len as usize, len);
+            Some((header, msg[mem::size_of::<fuse_out_header>()..len].to_vec()))
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::mem;
+    use crate::reply::AttrOut;
+    use std::{mem, time::Duration};
 
     #[test]
     fn init_default() {
@@ -1115,10 +2169,11 @@ mod tests {
             major: 7,
             minor: 23,
             max_readahead: 40,
-            flags: INIT_FLAGS_MASK
+            flags: INIT_FLAGS_MASK as u32
                 | FUSE_MAX_PAGES
                 | FUSE_NO_OPEN_SUPPORT
                 | FUSE_NO_OPENDIR_SUPPORT,
+            ..Default::default()
         };
 
         let mut input = Vec::with_capacity(input_len);
@@ -1154,14 +2209,13 @@ mod tests {
             major: 7,
             minor: 23,
             max_readahead: 40,
-            flags: DEFAULT_INIT_FLAGS | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
+            flags: DEFAULT_INIT_FLAGS as u32 | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
             max_background: 0,
             congestion_threshold: 0,
             max_write: DEFAULT_MAX_WRITE,
             time_gran: 1,
             max_pages: expected_max_pages,
-            padding: 0,
-            unused: [0; 8],
+            ..Default::default()
         };
 
         let mut expected = Vec::with_capacity(output_len);
@@ -1194,6 +2248,110 @@ mod tests {
         );
     }
 
+    fn init_in_bytes(unique: u64, init_in: &[u8]) -> Vec<u8> {
+        let in_header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + init_in.len()) as u32,
+            opcode: FUSE_INIT,
+            unique,
+            ..Default::default()
+        };
+        let mut input = in_header.as_bytes().to_vec();
+        input.extend_from_slice(init_in);
+        input
+    }
+
+    #[test]
+    fn init_ext() {
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 40,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK as u32 | FUSE_INIT_EXT,
+            flags2: ((FUSE_DIRECT_IO_ALLOW_MMAP | FUSE_HAS_RESEND) >> 32) as u32,
+            ..Default::default()
+        };
+        let input = init_in_bytes(2, init_in.as_bytes());
+
+        let mut output = Vec::<u8>::new();
+        let mut config = KernelConfig::default();
+        config.direct_io_allow_mmap(true);
+        let mut init_out = config.init_out;
+        init_session(&mut init_out, &input[..], &mut output).expect("initialization failed");
+
+        let mut reply = fuse_init_out::default();
+        reply
+            .as_bytes_mut()
+            .copy_from_slice(&output[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(reply.minor, 40);
+        assert!(reply.flags & FUSE_INIT_EXT != 0);
+        assert_eq!(reply.flags2, (FUSE_DIRECT_IO_ALLOW_MMAP >> 32) as u32);
+
+        assert!(init_flags(&init_out) & FUSE_HAS_RESEND != 0);
+    }
+
+    #[test]
+    fn init_compat_init_in() {
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 31,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK as u32,
+            ..Default::default()
+        };
+        let input = init_in_bytes(2, &init_in.as_bytes()[..FUSE_COMPAT_INIT_IN_SIZE]);
+
+        let mut output = Vec::<u8>::new();
+        let mut config = KernelConfig::default();
+        config.direct_io_allow_mmap(true);
+        let mut init_out = config.init_out;
+        init_session(&mut init_out, &input[..], &mut output).expect("initialization failed");
+
+        let mut reply = fuse_init_out::default();
+        reply
+            .as_bytes_mut()
+            .copy_from_slice(&output[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(reply.minor, 31);
+        assert!(reply.flags & FUSE_INIT_EXT == 0);
+        assert_eq!(reply.flags2, 0);
+    }
+
+    #[test]
+    fn init_passthrough() {
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 40,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK as u32 | FUSE_INIT_EXT,
+            flags2: (FUSE_PASSTHROUGH >> 32) as u32,
+            ..Default::default()
+        };
+        let input = init_in_bytes(2, init_in.as_bytes());
+
+        let init = |config: &KernelConfig| {
+            let mut output = Vec::<u8>::new();
+            let mut init_out = config.init_out;
+            init_session(&mut init_out, &input[..], &mut output).expect("initialization failed");
+
+            let mut reply = fuse_init_out::default();
+            reply
+                .as_bytes_mut()
+                .copy_from_slice(&output[mem::size_of::<fuse_out_header>()..]);
+            reply
+        };
+
+        let mut config = KernelConfig::default();
+        config.passthrough(true).max_stack_depth(2);
+        let reply = init(&config);
+        assert_eq!(reply.flags2, (FUSE_PASSTHROUGH >> 32) as u32);
+        assert_eq!(reply.max_stack_depth, 2);
+
+        config.writeback_cache(true);
+        let reply = init(&config);
+        assert!(reply.flags & FUSE_WRITEBACK_CACHE != 0);
+        assert_eq!(reply.flags2, 0);
+        assert_eq!(reply.max_stack_depth, 0);
+    }
+
     #[inline]
     fn bytes(bytes: &[u8]) -> &[u8] {
         bytes
@@ -1248,4 +2406,178 @@ mod tests {
         );
         assert_eq!(buf[16..], *b"hello, this is a message.", "payload");
     }
+
+    #[test]
+    fn decode_error_details() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+
+        kernel.send(FUSE_LOOKUP, 2, 1, &[b"foo"]);
+        let req = session.next_request().unwrap().unwrap();
+        let err = req.operation().unwrap_err();
+        assert_eq!(err.opcode(), FUSE_LOOKUP);
+        assert_eq!(err.unique(), 2);
+        assert_eq!(err.offset(), mem::size_of::<fuse_in_header>());
+        assert_eq!(err.kind(), crate::op::DecodeErrorKind::MissingNul);
+
+        // The truncated write requests are reported instead of panicking.
+        kernel.send(FUSE_WRITE, 4, 1, &[&[0u8; 8]]);
+        let req = session.next_request().unwrap().unwrap();
+        let err = req.operation().unwrap_err();
+        assert_eq!(err.kind(), crate::op::DecodeErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reply_to_undecodable_requests() {
+        let mut config = KernelConfig::default();
+        config.decode_error_policy(DecodeErrorPolicy::Reply(Errno::ENOSYS));
+        let (session, kernel) = testing::session(config);
+
+        kernel.send(FUSE_GETATTR, 2, 1, &[]);
+        kernel.send(FUSE_FORGET, 4, 1, &[]);
+        kernel.send(FUSE_STATFS, 6, 1, &[]);
+
+        // The undecodable requests are skipped.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 6);
+
+        let (header, _) = kernel.recv().unwrap();
+        assert_eq!((header.unique, header.error), (2, -libc::ENOSYS));
+        assert!(kernel.recv().is_none());
+    }
+
+    #[test]
+    fn recycle_buffer_on_receive_error() {
+        let (session, _kernel) = testing::session(KernelConfig::default());
+        session.inner.conn.set_nonblocking().unwrap();
+
+        match session.next_request() {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+            Ok(..) => panic!("unexpected success"),
+        }
+        assert_eq!(session.inner.buffers.num_idle(), 1);
+    }
+
+    #[test]
+    fn no_retry_after_failed_reply() {
+        let (session, kernel) = testing::session(KernelConfig::default());
+        kernel.send(FUSE_GETATTR, 2, 1, &[fuse_getattr_in::default().as_bytes()]);
+        let req = session.next_request().unwrap().unwrap();
+        drop(kernel);
+
+        // Only `WouldBlock` allows the reply to be sent again.
+        let err = req.reply_error(libc::ENOENT).unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = req.reply_error(libc::EIO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// Mount a filesystem by `mount(2)` directly, and set up the session on it.
+    ///
+    /// `None` is returned if the process is not privileged to mount it.
+    fn mount_device(mountpoint: &Path, mut config: KernelConfig) -> Option<Session> {
+        use std::ffi::CString;
+
+        let conn = Connection::open_device(Path::new("/dev/fuse")).ok()?;
+        let source = CString::new("polyfuse").unwrap();
+        let target = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+        let fstype = CString::new("fuse").unwrap();
+        let opts = format!(
+            "fd={},rootmode=40000,user_id=0,group_id=0",
+            conn.as_raw_fd()
+        );
+        let opts = CString::new(opts).unwrap();
+        let res = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                fstype.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                opts.as_ptr().cast(),
+            )
+        };
+        if res != 0 {
+            tracing::debug!("cannot mount: {}", io::Error::last_os_error());
+            return None;
+        }
+
+        config.probe();
+        if let Err(err) = init_session(&mut config.init_out, &conn, &conn) {
+            panic!("failed to initialize the session: {}", err);
+        }
+        Some(Session::new(conn, config))
+    }
+
+    /// Handle the requests over the ring entries of io_uring, from the registration of
+    /// the entries to the commits of the replies.
+    ///
+    /// This test requires the privilege to mount and fuse-over-io_uring enabled in the
+    /// kernel (`/sys/module/fuse/parameters/enable_uring`), and is skipped otherwise.
+    #[test]
+    fn serve_over_io_uring() {
+        use std::{ffi::CString, sync::mpsc, thread};
+
+        let enabled = std::fs::read_to_string("/sys/module/fuse/parameters/enable_uring")
+            .map_or(false, |s| s.trim() == "Y");
+        if !enabled {
+            tracing::debug!("fuse-over-io_uring is not enabled");
+            return;
+        }
+
+        let mountpoint =
+            std::env::temp_dir().join(format!("polyfuse-uring-{}", std::process::id()));
+        std::fs::create_dir_all(&mountpoint).unwrap();
+        let mut config = KernelConfig::default();
+        config.io_uring(true);
+        let session = match mount_device(&mountpoint, config) {
+            Some(session) => session,
+            None => {
+                std::fs::remove_dir(&mountpoint).unwrap();
+                return;
+            }
+        };
+        assert!(session.io_uring());
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut committed = 0;
+            while let Some(req) = session.next_request().unwrap() {
+                if req.commit.is_some() {
+                    committed += 1;
+                }
+                match req.operation().unwrap() {
+                    Operation::Getattr(op) => {
+                        let mut out = AttrOut::default();
+                        out.attr().ino(op.ino());
+                        out.attr().mode(libc::S_IFDIR | 0o755);
+                        out.attr().nlink(2);
+                        req.reply(out).unwrap();
+                    }
+                    Operation::Lookup(..) => req.reply_error(libc::ENOENT).unwrap(),
+                    Operation::Forget(..) | Operation::Interrupt(..) => (),
+                    _ => req.reply_error(libc::ENOSYS).unwrap(),
+                }
+            }
+            tx.send(committed).unwrap();
+        });
+
+        let res = std::panic::catch_unwind(|| {
+            let metadata = std::fs::metadata(&mountpoint).unwrap();
+            assert!(metadata.is_dir());
+            let err = std::fs::metadata(mountpoint.join("missing")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        });
+
+        let target = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+        unsafe {
+            libc::umount2(target.as_ptr(), libc::MNT_DETACH);
+        }
+        std::fs::remove_dir(&mountpoint).unwrap();
+        if let Err(payload) = res {
+            std::panic::resume_unwind(payload);
+        }
+
+        // The session is closed by the unmount, after the replies have been committed.
+        let committed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(committed >= 2, "committed = {}", committed);
+    }
 }
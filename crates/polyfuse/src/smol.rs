@@ -0,0 +1,3 @@
+//! Integration with [`smol`](https://docs.rs/smol).
+
+pub use crate::async_io::{AsyncNotifier, AsyncRequest, AsyncSession};
@@ -0,0 +1,64 @@
+//! Integration with [`tokio`](https://docs.rs/tokio).
+//!
+//! The session must be mounted within the context of a Tokio runtime.
+
+use ::tokio::io::{unix::AsyncFd, Interest};
+use std::task::{ready, Context, Poll};
+
+async_session!(Registration);
+
+pub(crate) struct Registration(AsyncFd<rt::Fd>);
+
+impl rt::Registration for Registration {
+    fn register(fd: RawFd) -> io::Result<Self> {
+        AsyncFd::with_interest(rt::Fd(fd), Interest::READABLE | Interest::WRITABLE).map(Self)
+    }
+
+    fn poll_read_with<R, F>(&self, cx: &mut Context<'_>, f: &mut F) -> Poll<io::Result<R>>
+    where
+        F: FnMut() -> io::Result<R>,
+    {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            if let Ok(res) = guard.try_io(|_| f()) {
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    fn poll_write_with<R, F>(&self, cx: &mut Context<'_>, f: &mut F) -> Poll<io::Result<R>>
+    where
+        F: FnMut() -> io::Result<R>,
+    {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            if let Ok(res) = guard.try_io(|_| f()) {
+                return Poll::Ready(res);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::Registration as _;
+    use std::{io::prelude::*, os::unix::net::UnixStream};
+
+    #[::tokio::test]
+    async fn read_after_readiness() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+        let io = Registration::register(rx.as_raw_fd()).unwrap();
+
+        let reader = ::tokio::spawn(async move {
+            let mut buf = [0u8; 5];
+            let len = rt::read_with(&io, || (&rx).read(&mut buf[..])).await?;
+            io::Result::Ok(buf[..len].to_vec())
+        });
+        ::tokio::task::yield_now().await;
+
+        tx.write_all(b"hello").unwrap();
+        assert_eq!(reader.await.unwrap().unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,1002 @@
+//! The transport of FUSE requests over io_uring (fuse-over-io_uring).
+//!
+//! The kernel delivers the requests to the *ring entries* registered by the daemon,
+//! which are grouped into per-CPU queues. Each entry owns a header buffer and a payload
+//! buffer; a request is written into them, and the reply is written back into the same
+//! buffers and committed with `FUSE_IO_URING_CMD_COMMIT_AND_FETCH`, which also makes the
+//! entry wait for the next request.
+//!
+//! Some messages (e.g. `FUSE_FORGET` and `FUSE_INTERRUPT`) are still delivered through
+//! the device file, so it is read through the same ring as well.
+//!
+//! The kernel dispatches a request to an entry in the context of the thread that submitted
+//! the command for that entry. Since the replies may be sent from arbitrary threads, the
+//! commands are always submitted by the threads receiving the requests, and the replying
+//! threads only queue the commits and wake them up.
+
+use crate::{
+    atomic_bytes::AtomicBytes,
+    buf::{self, pagesize, AlignedBuf, BufferPool},
+    conn::Connection,
+    session::{write_bytes, Reply},
+};
+use bytes::Bytes;
+use libc::{c_long, c_void, iovec};
+use polyfuse_kernel::*;
+use std::{
+    cmp, fs,
+    io::{self, IoSlice},
+    mem,
+    os::unix::prelude::*,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use zerocopy::AsBytes as _;
+
+// copied from linux/io_uring.h
+const IORING_SETUP_CQSIZE: u32 = 1 << 3;
+const IORING_SETUP_SQE128: u32 = 1 << 10;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_OP_NOP: u8 = 0;
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_URING_CMD: u8 = 46;
+
+const SQ_ENTRIES: u32 = 16;
+
+// The user data of the completions for reading the device file.
+const DEVICE_READ: u64 = u64::MAX;
+
+// The user data of the completions for waking up the receiving threads.
+const WAKE: u64 = u64::MAX - 1;
+
+// ==== io_uring ====
+
+#[derive(Default)]
+#[repr(C)]
+struct io_sqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct io_cqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct io_uring_params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+}
+
+/// A submission queue entry, in the 128-byte layout enabled by `IORING_SETUP_SQE128`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct io_uring_sqe128 {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    cmd_op: u32, // union with `off`
+    __pad1: u32,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32,
+    cmd: [u8; 80],
+}
+
+impl Default for io_uring_sqe128 {
+    fn default() -> Self {
+        unsafe { mem::zeroed() }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct io_uring_cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory region shared with the kernel.
+struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    #[inline]
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.cast::<u8>().add(offset as usize).cast()
+    }
+}
+
+struct SubmissionQueue {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    entries: u32,
+    array: *mut u32,
+    sqes: *mut io_uring_sqe128,
+}
+
+struct CompletionQueue {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    cqes: *const io_uring_cqe,
+}
+
+impl CompletionQueue {
+    fn pop(&mut self) -> Option<io_uring_cqe> {
+        unsafe {
+            let head = (*self.head).load(Ordering::Relaxed);
+            if head == (*self.tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = ptr::read(self.cqes.add((head & self.mask) as usize));
+            (*self.head).store(head.wrapping_add(1), Ordering::Release);
+            Some(cqe)
+        }
+    }
+}
+
+/// A minimal io_uring instance that is capable of `IORING_OP_URING_CMD`.
+struct IoUring {
+    fd: RawFd,
+    sq: Mutex<SubmissionQueue>,
+    cq: Mutex<CompletionQueue>,
+    _sq_ring: Mmap,
+    _cq_ring: Option<Mmap>,
+    _sqes: Mmap,
+}
+
+// The raw pointers in the queues refer to the regions owned by `IoUring`,
+// and the accesses to them are serialized with the mutexes.
+unsafe impl Send for IoUring {}
+unsafe impl Sync for IoUring {}
+
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl IoUring {
+    fn new(sq_entries: u32, cq_entries: u32) -> io::Result<Self> {
+        let mut params = io_uring_params {
+            flags: IORING_SETUP_SQE128 | IORING_SETUP_CQSIZE,
+            cq_entries,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                sq_entries as c_long,
+                &mut params as *mut io_uring_params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+
+        let res = Self::map(fd, &params);
+        if res.is_err() {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+        res
+    }
+
+    fn map(fd: RawFd, params: &io_uring_params) -> io::Result<Self> {
+        let sq_off = &params.sq_off;
+        let cq_off = &params.cq_off;
+
+        let sq_ring_len =
+            sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_ring_len =
+            cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+
+        let (sq_ring, cq_ring) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            let len = cmp::max(sq_ring_len, cq_ring_len);
+            (Mmap::new(fd, len, IORING_OFF_SQ_RING)?, None)
+        } else {
+            (
+                Mmap::new(fd, sq_ring_len, IORING_OFF_SQ_RING)?,
+                Some(Mmap::new(fd, cq_ring_len, IORING_OFF_CQ_RING)?),
+            )
+        };
+        let sqes = Mmap::new(
+            fd,
+            params.sq_entries as usize * mem::size_of::<io_uring_sqe128>(),
+            IORING_OFF_SQES,
+        )?;
+
+        unsafe {
+            let sq = SubmissionQueue {
+                head: sq_ring.at(sq_off.head),
+                tail: sq_ring.at(sq_off.tail),
+                mask: *sq_ring.at::<u32>(sq_off.ring_mask),
+                entries: *sq_ring.at::<u32>(sq_off.ring_entries),
+                array: sq_ring.at(sq_off.array),
+                sqes: sqes.ptr.cast(),
+            };
+
+            let cq_ring_ref = cq_ring.as_ref().unwrap_or(&sq_ring);
+            let cq = CompletionQueue {
+                head: cq_ring_ref.at(cq_off.head),
+                tail: cq_ring_ref.at(cq_off.tail),
+                mask: *cq_ring_ref.at::<u32>(cq_off.ring_mask),
+                cqes: cq_ring_ref.at(cq_off.cqes),
+            };
+
+            Ok(Self {
+                fd,
+                sq: Mutex::new(sq),
+                cq: Mutex::new(cq),
+                _sq_ring: sq_ring,
+                _cq_ring: cq_ring,
+                _sqes: sqes,
+            })
+        }
+    }
+
+    /// Push an entry into the submission queue and submit it to the kernel.
+    fn submit(&self, sqe: &io_uring_sqe128) -> io::Result<()> {
+        let sq = self.sq.lock().unwrap();
+        unsafe {
+            let head = (*sq.head).load(Ordering::Acquire);
+            let tail = (*sq.tail).load(Ordering::Relaxed);
+            if tail.wrapping_sub(head) >= sq.entries {
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+            let index = tail & sq.mask;
+            ptr::write(sq.sqes.add(index as usize), *sqe);
+            *sq.array.add(index as usize) = index;
+            (*sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.enter(1, 0, 0)?;
+        Ok(())
+    }
+
+    /// Wait for a completion of the submitted entries.
+    ///
+    /// The completion queue is not locked while waiting, so that multiple threads can
+    /// wait at the same time. The waiters may be woken up by a single completion, in
+    /// which case the ones that lose the race wait again.
+    fn wait(&self) -> io::Result<io_uring_cqe> {
+        loop {
+            if let Some(cqe) = self.peek() {
+                return Ok(cqe);
+            }
+            self.enter(0, 1, IORING_ENTER_GETEVENTS)?;
+        }
+    }
+
+    /// Take a completion if available, without waiting.
+    fn peek(&self) -> Option<io_uring_cqe> {
+        self.cq.lock().unwrap().pop()
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32, flags: u32) -> io::Result<usize> {
+        loop {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd as c_long,
+                    to_submit as c_long,
+                    min_complete as c_long,
+                    flags as c_long,
+                    ptr::null::<c_void>(),
+                    0 as c_long,
+                )
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            return Ok(res as usize);
+        }
+    }
+}
+
+/// Check if the running kernel is capable of the io_uring features used by the transport.
+pub(crate) fn probe() -> io::Result<()> {
+    let ring = IoUring::new(1, 2)?;
+    ring.submit(&io_uring_sqe128 {
+        opcode: IORING_OP_NOP,
+        ..Default::default()
+    })?;
+    let cqe = ring.wait()?;
+    if cqe.res < 0 {
+        return Err(io::Error::from_raw_os_error(-cqe.res));
+    }
+    Ok(())
+}
+
+// ==== Transport ====
+
+/// A ring entry registered to the kernel.
+struct Entry {
+    qid: u16,
+    header: *mut fuse_uring_req_header,
+    payload: *mut u8,
+    payload_len: usize,
+    iov: [iovec; FUSE_URING_IOV_SEGS],
+}
+
+// The buffers of an entry are accessed only by the owner of the request
+// that is currently dispatched to the entry.
+unsafe impl Send for Entry {}
+unsafe impl Sync for Entry {}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(self.header));
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                self.payload,
+                self.payload_len,
+            )));
+        }
+    }
+}
+
+impl Entry {
+    fn new(qid: u16, payload_len: usize) -> Self {
+        let header = Box::into_raw(Box::new(fuse_uring_req_header::default()));
+        let payload = Box::into_raw(vec![0u8; payload_len].into_boxed_slice()).cast::<u8>();
+        Self {
+            qid,
+            header,
+            payload,
+            payload_len,
+            iov: [
+                iovec {
+                    iov_base: header.cast(),
+                    iov_len: mem::size_of::<fuse_uring_req_header>(),
+                },
+                iovec {
+                    iov_base: payload.cast(),
+                    iov_len: payload_len,
+                },
+            ],
+        }
+    }
+}
+
+/// The buffers of a pending read from the device file.
+struct DeviceRead {
+    header: Box<fuse_in_header>,
+    arg: AlignedBuf,
+    _iov: Box<[iovec; 2]>,
+}
+
+unsafe impl Send for DeviceRead {}
+
+/// A message received through the transport.
+pub(crate) struct Received {
+    pub(crate) header: fuse_in_header,
+    pub(crate) arg: Bytes,
+    pub(crate) commit: Option<Commit>,
+}
+
+pub(crate) struct Transport {
+    ring: IoUring,
+    entries: Vec<Entry>,
+    conn: Arc<Connection>,
+    buffers: Arc<BufferPool>,
+    device_read: Mutex<Option<DeviceRead>>,
+    commits: Mutex<Vec<(usize, u64)>>,
+    started: AtomicBool,
+    live_entries: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        while let Some(cqe) = self.ring.peek() {
+            if cqe.user_data < WAKE && cqe.res < 0 {
+                self.live_entries.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        // The kernel may still write into the buffers of the entries or the pending read
+        // until the ring is torn down asynchronously, so they are leaked instead of
+        // being freed while registered.
+        if let Some(read) = self.device_read.get_mut().unwrap().take() {
+            mem::forget(read);
+        }
+        if self.live_entries.load(Ordering::Acquire) > 0 {
+            tracing::debug!("leaking the buffers of the ring entries still registered");
+            mem::forget(mem::take(&mut self.entries));
+        }
+    }
+}
+
+impl Transport {
+    /// Set up the ring and the buffers of `depth` entries for each queue.
+    ///
+    /// The entries are registered when the requests are received at first, since
+    /// the kernel requires the reply to `FUSE_INIT` to have been sent.
+    pub(crate) fn new(
+        conn: Arc<Connection>,
+        buffers: Arc<BufferPool>,
+        init_out: &fuse_init_out,
+        depth: u16,
+    ) -> io::Result<Arc<Self>> {
+        let nr_queues = possible_cpus();
+        let depth = depth as usize;
+        let nr_entries = nr_queues * depth;
+
+        // The payload buffer must be capable of the largest argument in the connection.
+        let payload_len = cmp::max(
+            cmp::max(FUSE_MIN_READ_BUFFER, init_out.max_write) as usize,
+            init_out.max_pages as usize * pagesize(),
+        );
+
+        // Reserve the completion queue for the entries, the device read and a wakeup.
+        let ring = IoUring::new(SQ_ENTRIES, (nr_entries + 2) as u32)?;
+        let entries = (0..nr_entries)
+            .map(|i| Entry::new((i / depth) as u16, payload_len))
+            .collect();
+
+        tracing::debug!(
+            "set up {} ring entries ({} queues, payload size = {})",
+            nr_entries,
+            nr_queues,
+            payload_len
+        );
+
+        Ok(Arc::new(Self {
+            ring,
+            entries,
+            conn,
+            buffers,
+            device_read: Mutex::new(None),
+            commits: Mutex::new(Vec::new()),
+            started: AtomicBool::new(false),
+            live_entries: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        }))
+    }
+
+    fn start(&self) -> io::Result<()> {
+        if self
+            .started
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        // The registration errors are reported as the completions of the entries,
+        // and the device file is still read in that case.
+        for index in 0..self.entries.len() {
+            self.register(index)?;
+            self.live_entries.fetch_add(1, Ordering::AcqRel);
+        }
+        self.read_device()
+    }
+
+    fn register(&self, index: usize) -> io::Result<()> {
+        let entry = &self.entries[index];
+        self.submit_cmd(
+            index,
+            FUSE_IO_URING_CMD_REGISTER,
+            fuse_uring_cmd_req {
+                qid: entry.qid,
+                ..Default::default()
+            },
+            entry.iov.as_ptr(),
+            entry.iov.len() as u32,
+        )
+    }
+
+    fn commit_and_fetch(&self, index: usize, commit_id: u64) -> io::Result<()> {
+        self.submit_cmd(
+            index,
+            FUSE_IO_URING_CMD_COMMIT_AND_FETCH,
+            fuse_uring_cmd_req {
+                commit_id,
+                qid: self.entries[index].qid,
+                ..Default::default()
+            },
+            ptr::null(),
+            0,
+        )
+    }
+
+    fn submit_cmd(
+        &self,
+        index: usize,
+        cmd_op: u32,
+        req: fuse_uring_cmd_req,
+        iov: *const iovec,
+        iovcnt: u32,
+    ) -> io::Result<()> {
+        let mut sqe = io_uring_sqe128 {
+            opcode: IORING_OP_URING_CMD,
+            fd: self.conn.as_raw_fd(),
+            cmd_op,
+            addr: iov as u64,
+            len: iovcnt,
+            user_data: index as u64,
+            ..Default::default()
+        };
+        sqe.cmd[..mem::size_of::<fuse_uring_cmd_req>()].copy_from_slice(req.as_bytes());
+        self.ring.submit(&sqe)
+    }
+
+    /// Start reading a message from the device file.
+    fn read_device(&self) -> io::Result<()> {
+        let mut header = Box::new(fuse_in_header::default());
+        let mut arg = self.buffers.get();
+        let arg_buf = arg.arg_mut();
+        let iov = Box::new([
+            iovec {
+                iov_base: header.as_bytes_mut().as_mut_ptr().cast(),
+                iov_len: mem::size_of::<fuse_in_header>(),
+            },
+            iovec {
+                iov_base: arg_buf.as_mut_ptr().cast(),
+                iov_len: arg_buf.len(),
+            },
+        ]);
+        let sqe = io_uring_sqe128 {
+            opcode: IORING_OP_READV,
+            fd: self.conn.as_raw_fd(),
+            addr: iov.as_ptr() as u64,
+            len: iov.len() as u32,
+            user_data: DEVICE_READ,
+            ..Default::default()
+        };
+
+        *self.device_read.lock().unwrap() = Some(DeviceRead {
+            header,
+            arg,
+            _iov: iov,
+        });
+        self.ring.submit(&sqe)
+    }
+
+    /// Receive a message from either the ring entries or the device file.
+    ///
+    /// `None` is returned if the connection has been aborted.
+    pub(crate) fn receive(self: &Arc<Self>) -> io::Result<Option<Received>> {
+        loop {
+            // FIXME: choose appropriate atomic ordering.
+            if self.closed.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+
+            self.start()?;
+
+            let commits = mem::take(&mut *self.commits.lock().unwrap());
+            for (index, commit_id) in commits {
+                self.commit_and_fetch(index, commit_id)?;
+            }
+
+            let cqe = self.ring.wait()?;
+
+            if cqe.user_data == WAKE {
+                continue;
+            }
+
+            if cqe.user_data == DEVICE_READ {
+                match self.complete_read(cqe.res)? {
+                    Some(received) => return Ok(Some(received)),
+                    None => continue,
+                }
+            }
+
+            let index = cqe.user_data as usize;
+            if cqe.res < 0 {
+                self.live_entries.fetch_sub(1, Ordering::AcqRel);
+                match -cqe.res {
+                    libc::ENOTCONN | libc::ECONNABORTED | libc::ECANCELED | libc::ENODEV => {
+                        tracing::debug!("ring entry {} is released", index);
+                        self.closed.store(true, Ordering::SeqCst);
+                        return Ok(None);
+                    }
+                    errno => {
+                        tracing::warn!(
+                            "ring entry {} is unregistered due to an error: {}",
+                            index,
+                            io::Error::from_raw_os_error(errno)
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            return self.fetch(index).map(Some);
+        }
+    }
+
+    fn complete_read(&self, res: i32) -> io::Result<Option<Received>> {
+        let DeviceRead { header, arg, .. } = self
+            .device_read
+            .lock()
+            .unwrap()
+            .take()
+            .expect("no pending read from the device");
+
+        if res < 0 {
+            self.buffers.put(arg);
+            return match -res {
+                libc::ENODEV => {
+                    tracing::debug!("ENODEV");
+                    self.closed.store(true, Ordering::SeqCst);
+                    Ok(None)
+                }
+                libc::ENOENT | libc::EINTR | libc::EAGAIN => {
+                    self.read_device()?;
+                    Ok(None)
+                }
+                errno => {
+                    self.read_device()?;
+                    Err(io::Error::from_raw_os_error(errno))
+                }
+            };
+        }
+
+        self.read_device()?;
+
+        let len = res as usize;
+        if len < mem::size_of::<fuse_in_header>() {
+            self.buffers.put(arg);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dequeued request message is too short",
+            ));
+        }
+        let arg_len = len - mem::size_of::<fuse_in_header>();
+
+        Ok(Some(Received {
+            header: *header,
+            arg: self.buffers.take_arg(arg, arg_len, header.opcode),
+            commit: None,
+        }))
+    }
+
+    /// Queue the commit of an entry and wake up a receiving thread to submit it.
+    fn push_commit(&self, index: usize, commit_id: u64) -> io::Result<()> {
+        let mut commits = self.commits.lock().unwrap();
+        commits.push((index, commit_id));
+        if commits.len() > 1 {
+            // A wakeup is already in flight.
+            return Ok(());
+        }
+        drop(commits);
+
+        self.ring.submit(&io_uring_sqe128 {
+            opcode: IORING_OP_NOP,
+            user_data: WAKE,
+            ..Default::default()
+        })
+    }
+
+    /// Take the request dispatched to the entry.
+    fn fetch(self: &Arc<Self>, index: usize) -> io::Result<Received> {
+        let entry = &self.entries[index];
+        let headers = unsafe { &*entry.header };
+
+        let mut header = fuse_in_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&headers.in_out[..mem::size_of::<fuse_in_header>()]);
+
+        // The per-operation header is stored separately from the rest of the arguments.
+        let commit_id = headers.ring_ent_in_out.commit_id;
+        let payload_sz = headers.ring_ent_in_out.payload_sz as usize;
+        let arg_len = (header.len as usize).saturating_sub(mem::size_of::<fuse_in_header>());
+        let op_in_len = match arg_len.checked_sub(payload_sz) {
+            Some(len) if len <= FUSE_URING_OP_IN_OUT_SZ && payload_sz <= entry.payload_len => len,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid request message in the ring entry",
+                ))
+            }
+        };
+
+        let payload = unsafe { std::slice::from_raw_parts(entry.payload, payload_sz) };
+        let arg = buf::copy_arg(header.opcode, &[&headers.op_in[..op_in_len], payload]);
+
+        Ok(Received {
+            header,
+            arg,
+            commit: Some(Commit {
+                transport: self.clone(),
+                index,
+                commit_id,
+                committed: AtomicBool::new(false),
+            }),
+        })
+    }
+}
+
+/// The handle for committing the reply to a request dispatched to a ring entry.
+pub(crate) struct Commit {
+    transport: Arc<Transport>,
+    index: usize,
+    commit_id: u64,
+    committed: AtomicBool,
+}
+
+impl Commit {
+    /// Write the reply message into the entry and commit it to the kernel.
+    pub(crate) fn send<T>(&self, bytes: T) -> io::Result<()>
+    where
+        T: AtomicBytes,
+    {
+        if self.committed.swap(true, Ordering::AcqRel) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the reply has already been committed",
+            ));
+        }
+
+        let entry = &self.transport.entries[self.index];
+        let res = write_bytes(EntryWriter::new(entry), bytes);
+        if res.is_err() {
+            // The entry must be committed anyway so that it is reused for the subsequent requests.
+            write_bytes(
+                EntryWriter::new(entry),
+                Reply::new(self.commit_id, libc::EIO, ()),
+            )?;
+        }
+
+        self.transport.push_commit(self.index, self.commit_id)?;
+        res
+    }
+}
+
+impl Drop for Commit {
+    fn drop(&mut self) {
+        if *self.committed.get_mut() {
+            return;
+        }
+        // The entry is not reused for the subsequent requests until it is committed.
+        tracing::warn!(
+            "the request is dropped without replying, reply EIO instead (unique={})",
+            self.commit_id
+        );
+        if let Err(err) = self.send(Reply::new(self.commit_id, libc::EIO, ())) {
+            tracing::debug!("failed to commit the reply: {}", err);
+        }
+    }
+}
+
+/// A writer that fills a reply message into the buffers of a ring entry.
+struct EntryWriter<'a> {
+    entry: &'a Entry,
+    written: usize,
+}
+
+impl<'a> EntryWriter<'a> {
+    fn new(entry: &'a Entry) -> Self {
+        Self { entry, written: 0 }
+    }
+}
+
+impl io::Write for EntryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let header_len = mem::size_of::<fuse_out_header>();
+        let total = self.written + bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if total > header_len + self.entry.payload_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reply message is too large for the ring entry",
+            ));
+        }
+
+        // The out header is placed at the head of the header buffer, and the rest
+        // of the message is placed in the payload buffer.
+        let headers = unsafe { &mut *self.entry.header };
+        let payload =
+            unsafe { std::slice::from_raw_parts_mut(self.entry.payload, self.entry.payload_len) };
+        for buf in bufs {
+            let mut buf = &buf[..];
+            if self.written < header_len {
+                let n = cmp::min(buf.len(), header_len - self.written);
+                headers.in_out[self.written..self.written + n].copy_from_slice(&buf[..n]);
+                self.written += n;
+                buf = &buf[n..];
+            }
+            if !buf.is_empty() {
+                let offset = self.written - header_len;
+                payload[offset..offset + buf.len()].copy_from_slice(buf);
+                self.written += buf.len();
+            }
+        }
+        headers.ring_ent_in_out.payload_sz = self.written.saturating_sub(header_len) as u32;
+
+        Ok(total)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// ==== utils ====
+
+/// Return the number of possible CPUs, which is the number of queues required by the kernel.
+fn possible_cpus() -> usize {
+    fs::read_to_string("/sys/devices/system/cpu/possible")
+        .ok()
+        .and_then(|list| count_cpu_list(list.trim()))
+        .unwrap_or_else(|| {
+            cmp::max(unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }, 1) as usize
+        })
+}
+
+fn count_cpu_list(list: &str) -> Option<usize> {
+    let mut count = 0;
+    for range in list.split(',') {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                count += end
+                    .parse::<usize>()
+                    .ok()?
+                    .checked_sub(start.parse().ok()?)?
+                    + 1;
+            }
+            None => {
+                range.parse::<usize>().ok()?;
+                count += 1;
+            }
+        }
+    }
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(count_cpu_list("0"), Some(1));
+        assert_eq!(count_cpu_list("0-7"), Some(8));
+        assert_eq!(count_cpu_list("0-3,8-11,16"), Some(9));
+        assert_eq!(count_cpu_list("3-1"), None);
+        assert_eq!(count_cpu_list(""), None);
+    }
+
+    #[test]
+    fn ring_nop() {
+        let ring = match IoUring::new(4, 8) {
+            Ok(ring) => ring,
+            Err(err) => {
+                tracing::debug!("io_uring is not available: {}", err);
+                return;
+            }
+        };
+        for i in 0..3 {
+            ring.submit(&io_uring_sqe128 {
+                opcode: IORING_OP_NOP,
+                user_data: i,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let mut completed: Vec<_> = (0..3)
+            .map(|_| {
+                let cqe = ring.wait().unwrap();
+                assert_eq!(cqe.res, 0);
+                cqe.user_data
+            })
+            .collect();
+        completed.sort_unstable();
+        assert_eq!(completed, [0, 1, 2]);
+        assert!(ring.peek().is_none());
+    }
+
+    #[test]
+    fn commit_eio_on_drop() {
+        let (conn, _socket) = Connection::socketpair().unwrap();
+        let transport = match Transport::new(
+            Arc::new(conn),
+            Arc::new(BufferPool::new(FUSE_MIN_READ_BUFFER as usize, 1)),
+            &fuse_init_out::default(),
+            1,
+        ) {
+            Ok(transport) => transport,
+            Err(err) => {
+                tracing::debug!("io_uring is not available: {}", err);
+                return;
+            }
+        };
+
+        drop(Commit {
+            transport: transport.clone(),
+            index: 0,
+            commit_id: 42,
+            committed: AtomicBool::new(false),
+        });
+
+        // The entry is committed with EIO, so that it is reused for the subsequent requests.
+        assert_eq!(*transport.commits.lock().unwrap(), [(0, 42)]);
+        let headers = unsafe { &*transport.entries[0].header };
+        let mut out = fuse_out_header::default();
+        out.as_bytes_mut()
+            .copy_from_slice(&headers.in_out[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!((out.unique, out.error), (42, -libc::EIO));
+    }
+}
@@ -0,0 +1,86 @@
+use polyfuse::{
+    op,
+    reply::{IoctlOut, OpenOut, WriteOut},
+    CuseConfig, CuseSession, Operation, Request,
+};
+
+use anyhow::{Context as _, Result};
+use std::{io, sync::Mutex};
+
+// The ioctl command to obtain the length of the buffer, equivalent to `_IOR('E', 0, u64)`.
+const IOCTL_GET_SIZE: u32 = (2 << 30) | ((std::mem::size_of::<u64>() as u32) << 16) | (0x45 << 8);
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let devname: String = args.free_from_str()?.context("missing device name")?;
+
+    // Create a character device at `/dev/<devname>`.
+    let session = CuseSession::open(&devname, CuseConfig::default())?;
+    tracing::info!(
+        "created /dev/{} ({}:{})",
+        devname,
+        session.dev_major(),
+        session.dev_minor()
+    );
+
+    let dev = Echo::default();
+
+    while let Some(req) = session.next_request()? {
+        match req.operation()? {
+            Operation::Open(op) => dev.open(&req, op)?,
+            Operation::Read(op) => dev.read(&req, op)?,
+            Operation::Write(op, data) => dev.write(&req, op, &data[..])?,
+            Operation::Ioctl(op) => dev.ioctl(&req, op)?,
+            Operation::Release(..) => req.reply(())?,
+            _ => req.reply_error(libc::ENOSYS)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// A device that returns the last written data.
+#[derive(Default)]
+struct Echo {
+    content: Mutex<Vec<u8>>,
+}
+
+impl Echo {
+    fn open(&self, req: &Request, _op: op::Open<'_>) -> io::Result<()> {
+        let mut out = OpenOut::default();
+        out.direct_io(true);
+        out.nonseekable(true);
+        req.reply(out)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+        let content = self.content.lock().unwrap();
+        let len = std::cmp::min(content.len(), op.size() as usize);
+        req.reply(&content[..len])
+    }
+
+    fn write(&self, req: &Request, op: op::Write<'_>, data: &[u8]) -> io::Result<()> {
+        let mut content = self.content.lock().unwrap();
+        let len = std::cmp::min(data.len(), op.size() as usize);
+        content.clear();
+        content.extend_from_slice(&data[..len]);
+
+        let mut out = WriteOut::default();
+        out.size(len as u32);
+        req.reply(out)
+    }
+
+    fn ioctl(&self, req: &Request, op: op::Ioctl<'_>) -> io::Result<()> {
+        match op.cmd() {
+            IOCTL_GET_SIZE => {
+                let size = (self.content.lock().unwrap().len() as u64).to_ne_bytes();
+                let out = IoctlOut::default();
+                req.reply((out, &size[..]))
+            }
+            _ => req.reply_error(libc::ENOTTY),
+        }
+    }
+}
@@ -13,13 +13,11 @@ use polyfuse::{
 };
 
 use anyhow::{anyhow, ensure, Context as _, Result};
-use bytes::Bytes;
 use chrono::Local;
 use std::{
-    collections::HashMap,
     io, mem,
     path::PathBuf,
-    sync::{mpsc, Arc, Mutex},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
@@ -118,15 +116,6 @@ fn main() -> Result<()> {
                     }
                     _ => req.reply_error(libc::ENOENT)?,
                 },
-                Operation::NotifyReply(op, data) => {
-                    let mut retrieves = heartbeat.retrieves.lock().unwrap();
-                    if let Some(tx) = retrieves.remove(&op.unique()) {
-                        let size = std::cmp::min(data.len(), op.size() as usize);
-                        let buf = data.slice(..size);
-                        tx.send(buf).unwrap();
-                    }
-                }
-
                 _ => req.reply_error(libc::ENOSYS)?,
             }
 
@@ -145,7 +134,6 @@ enum NotifyKind {
 
 struct Heartbeat {
     inner: Mutex<Inner>,
-    retrieves: Mutex<HashMap<u64, mpsc::Sender<Bytes>>>,
 }
 
 struct Inner {
@@ -164,7 +152,6 @@ impl Heartbeat {
 
         Self {
             inner: Mutex::new(Inner { content, attr }),
-            retrieves: Mutex::default(),
         }
     }
 
@@ -185,16 +172,13 @@ impl Heartbeat {
         // To check if the cache is updated correctly, pull the
         // content from the kernel using notify_retrieve.
         tracing::info!("send notify_retrieve");
-        let data = {
-            // FIXME: choose appropriate atomic ordering.
-            let unique = notifier.retrieve(ROOT_INO, 0, 1024)?;
-            let (tx, rx) = mpsc::channel();
-            self.retrieves.lock().unwrap().insert(unique, tx);
-            rx.recv().unwrap()
-        };
+        let retrieved = notifier
+            .retrieve_data(ROOT_INO, 0, 1024)?
+            .recv_timeout(Duration::from_secs(5))?;
+        let data = retrieved.data();
         tracing::info!("--> content={:?}", data);
 
-        if data[..content.len()] != *content.as_bytes() {
+        if data.get(..content.len()) != Some(content.as_bytes()) {
             tracing::error!("mismatched data");
         }
 
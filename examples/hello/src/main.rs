@@ -2,9 +2,11 @@
 #![deny(clippy::unimplemented)]
 
 use polyfuse::{
+    fs::{self, Filesystem},
     op,
-    reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
-    KernelConfig, Operation, Request, Session,
+    pool::WorkerPool,
+    reply::{AttrOut, EntryOut, FileAttr, ReaddirOut, ReaddirReply},
+    KernelConfig, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -21,6 +23,8 @@ fn main() -> Result<()> {
 
     let mut args = pico_args::Arguments::from_env();
 
+    let threads: Option<usize> = args.opt_value_from_str(["-t", "--threads"])?;
+
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "tmountpoint must be a directory");
 
@@ -28,14 +32,14 @@ fn main() -> Result<()> {
 
     let fs = Hello::new();
 
-    while let Some(req) = session.next_request()? {
-        match req.operation()? {
-            Operation::Lookup(op) => fs.lookup(&req, op)?,
-            Operation::Getattr(op) => fs.getattr(&req, op)?,
-            Operation::Read(op) => fs.read(&req, op)?,
-            Operation::Readdir(op) => fs.readdir(&req, op)?,
-            _ => req.reply_error(libc::ENOSYS)?,
+    match threads {
+        Some(n) => {
+            // Handle the requests on multiple worker threads.
+            WorkerPool::new()
+                .max_threads(n)
+                .run(&session, |req| fs::dispatch(&fs, req))?;
         }
+        None => fs::serve(&session, &fs)?,
     }
 
     Ok(())
@@ -96,7 +100,17 @@ impl Hello {
         attr.gid(self.gid);
     }
 
+    fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
+        self.entries.iter().enumerate().map(|(i, ent)| {
+            let offset = (i + 1) as u64;
+            (offset, ent)
+        })
+    }
+}
+
+impl Filesystem for Hello {
     fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+        let reply = op.reply_handle(req);
         match op.parent() {
             ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
                 let mut out = EntryOut::default();
@@ -104,31 +118,33 @@ impl Hello {
                 out.ino(HELLO_INO);
                 out.ttl_attr(TTL);
                 out.ttl_entry(TTL);
-                req.reply(out)
+                reply.reply(out)
             }
-            _ => req.reply_error(libc::ENOENT),
+            _ => reply.reply_error(libc::ENOENT),
         }
     }
 
     fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+        let reply = op.reply_handle(req);
         let fill_attr = match op.ino() {
             ROOT_INO => Self::fill_root_attr,
             HELLO_INO => Self::fill_hello_attr,
-            _ => return req.reply_error(libc::ENOENT),
+            _ => return reply.reply_error(libc::ENOENT),
         };
 
         let mut out = AttrOut::default();
         fill_attr(self, out.attr());
         out.ttl(TTL);
 
-        req.reply(out)
+        reply.reply(out)
     }
 
     fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+        let reply = op.reply_handle(req);
         match op.ino() {
             HELLO_INO => (),
-            ROOT_INO => return req.reply_error(libc::EISDIR),
-            _ => return req.reply_error(libc::ENOENT),
+            ROOT_INO => return reply.reply_error(libc::EISDIR),
+            _ => return reply.reply_error(libc::ENOENT),
         }
 
         let mut data: &[u8] = &[];
@@ -140,19 +156,17 @@ impl Hello {
             data = &data[..std::cmp::min(data.len(), size)];
         }
 
-        req.reply(data)
-    }
-
-    fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
-        self.entries.iter().enumerate().map(|(i, ent)| {
-            let offset = (i + 1) as u64;
-            (offset, ent)
-        })
+        reply.reply(data)
     }
 
     fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+        let reply = match op.reply_handle(req) {
+            ReaddirReply::Normal(reply) => reply,
+            // `readdirplus` is not enabled in this filesystem.
+            reply @ ReaddirReply::Plus(..) => return reply.reply_error(libc::ENOSYS),
+        };
         if op.ino() != ROOT_INO {
-            return req.reply_error(libc::ENOTDIR);
+            return reply.reply_error(libc::ENOTDIR);
         }
 
         let mut out = ReaddirOut::new(op.size() as usize);
@@ -169,6 +183,6 @@ impl Hello {
             }
         }
 
-        req.reply(out)
+        reply.reply(out)
     }
 }
@@ -2,24 +2,21 @@
 #![deny(clippy::unimplemented)]
 
 use polyfuse::{
+    dir::{DirEntry, DirHandles, DirStream},
+    inode::InodeTable,
     op,
-    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, WriteOut, XattrOut},
+    reply::{AttrOut, EntryOut, FileAttr, WriteOut, XattrOut},
     KernelConfig, Operation, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
-use dashmap::DashMap;
-use slab::Slab;
 use std::{
-    collections::hash_map::{Entry, HashMap, RandomState},
+    collections::hash_map::{Entry, HashMap},
     ffi::{OsStr, OsString},
     io, mem,
     ops::Deref,
     path::PathBuf,
-    sync::{
-        atomic::{AtomicU64, AtomicUsize, Ordering},
-        Arc,
-    },
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
@@ -31,7 +28,9 @@ fn main() -> Result<()> {
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
-    let session = Session::mount(mountpoint, KernelConfig::default())?;
+    let mut config = KernelConfig::default();
+    config.readdirplus(true);
+    let session = Session::mount(mountpoint, config)?;
 
     let mut fs = MemFS::new();
 
@@ -47,114 +46,9 @@ fn main() -> Result<()> {
 
 type Ino = u64;
 
-struct INodeTable {
-    map: DashMap<Ino, INode, RandomState>,
-    next_ino: AtomicU64,
-}
-
-impl INodeTable {
-    fn new() -> Self {
-        Self {
-            map: DashMap::with_hasher(RandomState::new()),
-            next_ino: AtomicU64::new(1), // ino=1 is reserved by root node
-        }
-    }
-
-    fn get(&self, ino: Ino) -> Option<INodeRef<'_>> {
-        self.map.get(&ino).map(|ref_| INodeRef { ref_ })
-    }
-
-    fn get_mut(&self, ino: Ino) -> Option<INodeRefMut<'_>> {
-        self.map
-            .get_mut(&ino)
-            .map(|ref_mut| INodeRefMut { ref_mut })
-    }
-
-    fn occupied_entry(&self, ino: Ino) -> Option<OccupiedEntry<'_>> {
-        match self.map.entry(ino) {
-            dashmap::mapref::entry::Entry::Occupied(entry) => Some(OccupiedEntry { entry }),
-            dashmap::mapref::entry::Entry::Vacant(..) => None,
-        }
-    }
-
-    fn vacant_entry(&self) -> Option<VacantEntry<'_>> {
-        // TODO: choose appropriate atomic ordering.
-        let ino = self.next_ino.fetch_add(1, Ordering::SeqCst);
-
-        match self.map.entry(ino) {
-            dashmap::mapref::entry::Entry::Occupied(..) => None,
-            dashmap::mapref::entry::Entry::Vacant(entry) => Some(VacantEntry { entry }),
-        }
-    }
-}
-
-struct INodeRef<'a> {
-    ref_: dashmap::mapref::one::Ref<'a, Ino, INode, RandomState>,
-}
-impl std::ops::Deref for INodeRef<'_> {
-    type Target = INode;
-
-    #[inline]
-    fn deref(&self) -> &Self::Target {
-        &*self.ref_
-    }
-}
-
-struct INodeRefMut<'a> {
-    ref_mut: dashmap::mapref::one::RefMut<'a, Ino, INode, RandomState>,
-}
-impl std::ops::Deref for INodeRefMut<'_> {
-    type Target = INode;
-
-    #[inline]
-    fn deref(&self) -> &Self::Target {
-        &*self.ref_mut
-    }
-}
-impl std::ops::DerefMut for INodeRefMut<'_> {
-    #[inline]
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut *self.ref_mut
-    }
-}
-
-struct OccupiedEntry<'a> {
-    entry: dashmap::mapref::entry::OccupiedEntry<'a, Ino, INode, RandomState>,
-}
-impl<'a> OccupiedEntry<'a> {
-    fn get(&self) -> &INode {
-        self.entry.get()
-    }
-
-    fn get_mut(&mut self) -> &mut INode {
-        self.entry.get_mut()
-    }
-
-    fn remove(self) -> INode {
-        self.entry.remove()
-    }
-}
-
-struct VacantEntry<'a> {
-    entry: dashmap::mapref::entry::VacantEntry<'a, Ino, INode, RandomState>,
-}
-impl<'a> VacantEntry<'a> {
-    fn ino(&self) -> Ino {
-        *self.entry.key()
-    }
-
-    fn insert(self, inode: INode) -> INodeRefMut<'a> {
-        INodeRefMut {
-            ref_mut: self.entry.insert(inode),
-        }
-    }
-}
-
 struct INode {
     attr: libc::stat,
     xattrs: HashMap<OsString, Arc<Vec<u8>>>,
-    refcount: u64,
-    links: u64,
     kind: INodeKind,
 }
 
@@ -169,63 +63,25 @@ struct Directory {
     parent: Option<Ino>,
 }
 
-struct DirEntry {
-    name: OsString,
-    ino: u64,
-    typ: u32,
-    off: u64,
-}
-
 impl Directory {
-    fn collect_entries(&self, attr: &libc::stat) -> Vec<Arc<DirEntry>> {
-        let mut entries = Vec::with_capacity(self.children.len() + 2);
-        let mut offset: u64 = 1;
-
-        entries.push(Arc::new(DirEntry {
-            name: ".".into(),
-            ino: attr.st_ino,
-            typ: libc::DT_DIR as u32,
-            off: offset,
-        }));
-        offset += 1;
-
-        entries.push(Arc::new(DirEntry {
-            name: "..".into(),
-            ino: self.parent.unwrap_or(attr.st_ino),
-            typ: libc::DT_DIR as u32,
-            off: offset,
-        }));
-        offset += 1;
-
-        for (name, &ino) in &self.children {
-            entries.push(Arc::new(DirEntry {
-                name: name.into(),
-                ino,
-                typ: libc::DT_UNKNOWN as u32,
-                off: offset,
-            }));
-            offset += 1;
-        }
-
-        entries
+    fn stream(&self, ino: Ino) -> DirStream {
+        let entries = self
+            .children
+            .iter()
+            .map(|(name, &ino)| DirEntry::new(name, ino, libc::DT_UNKNOWN as u32));
+        DirStream::new(ino, self.parent.unwrap_or(ino), entries)
     }
 }
 
-struct DirHandle {
-    entries: Vec<Arc<DirEntry>>,
-    offset: AtomicUsize,
-}
-
 struct MemFS {
-    inodes: INodeTable,
-    dir_handles: Slab<DirHandle>,
+    inodes: InodeTable<Mutex<INode>>,
+    dirs: DirHandles,
     ttl: Duration,
 }
 
 impl MemFS {
     fn new() -> Self {
-        let inodes = INodeTable::new();
-        inodes.vacant_entry().unwrap().insert(INode {
+        let inodes = InodeTable::new(Mutex::new(INode {
             attr: {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
                 attr.st_ino = 1;
@@ -234,17 +90,15 @@ impl MemFS {
                 attr
             },
             xattrs: HashMap::new(),
-            refcount: u64::max_value() / 2,
-            links: u64::max_value() / 2,
             kind: INodeKind::Directory(Directory {
                 children: HashMap::new(),
                 parent: None,
             }),
-        });
+        }));
 
         Self {
             inodes,
-            dir_handles: Slab::new(),
+            dirs: DirHandles::new(),
             ttl: Duration::from_secs(60 * 60 * 24),
         }
     }
@@ -296,6 +150,7 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let parent = parent.lock().unwrap();
 
         let parent = match parent.kind {
             INodeKind::Directory(ref dir) => dir,
@@ -306,31 +161,17 @@ impl MemFS {
             Some(&ino) => ino,
             None => return req.reply_error(libc::ENOENT),
         };
-        let mut child = self
-            .inodes
-            .get_mut(child_ino)
-            .unwrap_or_else(|| unreachable!());
-        child.refcount += 1;
+        let child = self.inodes.get(child_ino).unwrap_or_else(|| unreachable!());
 
         let mut out = EntryOut::default();
-        out.ino(child_ino);
-        fill_attr(out.attr(), &child.attr);
+        fill_attr(out.attr(), &child.lock().unwrap().attr);
         out.ttl_entry(self.ttl);
 
-        req.reply(out)
+        self.inodes.reply_entry(req, child_ino, out)
     }
 
     fn do_forget(&self, forgets: &[op::Forget]) {
-        for forget in forgets {
-            if let Some(mut inode) = self.inodes.occupied_entry(forget.ino()) {
-                inode.get_mut().refcount =
-                    inode.get_mut().refcount.saturating_sub(forget.nlookup());
-
-                if inode.get().refcount == 0 && inode.get().links == 0 {
-                    inode.remove();
-                }
-            }
-        }
+        self.inodes.forget(forgets);
     }
 
     fn do_getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
@@ -338,6 +179,7 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let inode = inode.lock().unwrap();
 
         let mut out = AttrOut::default();
         fill_attr(out.attr(), &inode.attr);
@@ -347,10 +189,11 @@ impl MemFS {
     }
 
     fn do_setattr(&self, req: &Request, op: op::Setattr<'_>) -> io::Result<()> {
-        let mut inode = match self.inodes.get_mut(op.ino()) {
+        let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut inode = inode.lock().unwrap();
 
         fn to_duration(t: op::SetAttrTime) -> Duration {
             match t {
@@ -400,6 +243,7 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let inode = inode.lock().unwrap();
 
         let link = match inode.kind {
             INodeKind::Symlink(ref link) => link,
@@ -409,11 +253,12 @@ impl MemFS {
         req.reply(link)
     }
 
-    fn do_opendir(&mut self, req: &Request, op: op::Opendir<'_>) -> io::Result<()> {
+    fn do_opendir(&self, req: &Request, op: op::Opendir<'_>) -> io::Result<()> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let inode = inode.lock().unwrap();
         if inode.attr.st_nlink == 0 {
             return req.reply_error(libc::ENOENT);
         }
@@ -422,42 +267,24 @@ impl MemFS {
             _ => return req.reply_error(libc::ENOTDIR),
         };
 
-        let key = self.dir_handles.insert(DirHandle {
-            entries: dir.collect_entries(&inode.attr),
-            offset: AtomicUsize::new(0),
-        });
-
-        let mut out = OpenOut::default();
-        out.fh(key as u64);
-
-        req.reply(out)
+        self.dirs.reply_opendir(req, dir.stream(inode.attr.st_ino))
     }
 
     fn do_readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
-        if op.mode() == op::ReaddirMode::Plus {
-            return req.reply_error(libc::ENOSYS);
-        }
-
-        let dir = match self.dir_handles.get(op.fh() as usize) {
-            Some(dir) => dir,
-            None => return req.reply_error(libc::EINVAL),
-        };
-
-        let mut out = ReaddirOut::new(op.size() as usize);
-
-        for entry in dir.entries.iter().skip(op.offset() as usize) {
-            if out.entry(&entry.name, entry.ino, entry.typ, entry.off) {
-                break;
-            }
-            dir.offset.fetch_add(1, Ordering::SeqCst);
-        }
-
-        req.reply(out)
+        self.dirs
+            .reply_readdirplus(req, &op, &self.inodes, |entry| {
+                // The inodes removed after opening the directory are omitted.
+                let inode = self.inodes.get(entry.ino())?;
+                let mut entry_out = EntryOut::default();
+                entry_out.ino(entry.ino());
+                fill_attr(entry_out.attr(), &inode.lock().unwrap().attr);
+                entry_out.ttl_entry(self.ttl);
+                Some(entry_out)
+            })
     }
 
-    fn do_releasedir(&mut self, req: &Request, op: op::Releasedir<'_>) -> io::Result<()> {
-        self.dir_handles.remove(op.fh() as usize);
-        req.reply(())
+    fn do_releasedir(&self, req: &Request, op: op::Releasedir<'_>) -> io::Result<()> {
+        self.dirs.reply_releasedir(req, &op)
     }
 
     fn do_mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<()> {
@@ -466,33 +293,29 @@ impl MemFS {
             _ => return req.reply_error(libc::ENOTSUP),
         }
 
-        self.make_node(req, op.parent(), op.name(), |entry| INode {
+        self.make_node(req, op.parent(), op.name(), |ino| INode {
             attr: {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
-                attr.st_ino = entry.ino();
+                attr.st_ino = ino;
                 attr.st_nlink = 1;
                 attr.st_mode = op.mode();
                 attr
             },
             xattrs: HashMap::new(),
-            refcount: 1,
-            links: 1,
             kind: INodeKind::RegularFile(vec![]),
         })
     }
 
     fn do_mkdir(&self, req: &Request, op: op::Mkdir<'_>) -> io::Result<()> {
-        self.make_node(req, op.parent(), op.name(), |entry| INode {
+        self.make_node(req, op.parent(), op.name(), |ino| INode {
             attr: {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
-                attr.st_ino = entry.ino();
+                attr.st_ino = ino;
                 attr.st_nlink = 2;
                 attr.st_mode = op.mode() | libc::S_IFDIR;
                 attr
             },
             xattrs: HashMap::new(),
-            refcount: 1,
-            links: 1,
             kind: INodeKind::Directory(Directory {
                 children: HashMap::new(),
                 parent: Some(op.parent()),
@@ -501,29 +324,28 @@ impl MemFS {
     }
 
     fn do_symlink(&self, req: &Request, op: op::Symlink<'_>) -> io::Result<()> {
-        self.make_node(req, op.parent(), op.name(), |entry| INode {
+        self.make_node(req, op.parent(), op.name(), |ino| INode {
             attr: {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
-                attr.st_ino = entry.ino();
+                attr.st_ino = ino;
                 attr.st_nlink = 1;
                 attr.st_mode = libc::S_IFLNK | 0o777;
                 attr
             },
             xattrs: HashMap::new(),
-            refcount: 1,
-            links: 1,
             kind: INodeKind::Symlink(Arc::new(op.link().into())),
         })
     }
 
     fn make_node<F>(&self, req: &Request, parent: Ino, name: &OsStr, f: F) -> io::Result<()>
     where
-        F: FnOnce(&VacantEntry<'_>) -> INode,
+        F: FnOnce(Ino) -> INode,
     {
-        let mut parent = match self.inodes.get_mut(parent) {
+        let parent = match self.inodes.get(parent) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut parent = parent.lock().unwrap();
         let parent = match parent.kind {
             INodeKind::Directory(ref mut dir) => dir,
             _ => return req.reply_error(libc::ENOTDIR),
@@ -533,32 +355,31 @@ impl MemFS {
             Entry::Occupied(..) => return req.reply_error(libc::EEXIST),
             Entry::Vacant(map_entry) => map_entry,
         };
-        let inode_entry = self.inodes.vacant_entry().expect("inode number conflict");
-        let inode = f(&inode_entry);
+        let ino = self.inodes.insert_with(|ino| Mutex::new(f(ino)));
+        self.inodes.link(ino);
+        map_entry.insert(ino);
 
+        let inode = self.inodes.get(ino).unwrap_or_else(|| unreachable!());
         let mut out = EntryOut::default();
-        out.ino(inode_entry.ino());
-        fill_attr(out.attr(), &inode.attr);
+        fill_attr(out.attr(), &inode.lock().unwrap().attr);
         out.ttl_entry(self.ttl);
-        req.reply(out)?;
-
-        map_entry.insert(inode_entry.ino());
-        inode_entry.insert(inode);
 
-        Ok(())
+        self.inodes.reply_entry(req, ino, out)
     }
 
     fn do_link(&self, req: &Request, op: op::Link<'_>) -> io::Result<()> {
-        let mut inode = match self.inodes.get_mut(op.ino()) {
+        let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut inode = inode.lock().unwrap();
 
         debug_assert!(op.ino() != op.newparent());
-        let mut newparent = match self.inodes.get_mut(op.newparent()) {
+        let newparent = match self.inodes.get(op.newparent()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut newparent = newparent.lock().unwrap();
         let newparent = match newparent.kind {
             INodeKind::Directory(ref mut dir) => dir,
             _ => return req.reply_error(libc::ENOTDIR),
@@ -568,18 +389,16 @@ impl MemFS {
             Entry::Occupied(..) => return req.reply_error(libc::EEXIST),
             Entry::Vacant(entry) => {
                 entry.insert(op.ino());
-                inode.links += 1;
+                self.inodes.link(op.ino());
                 inode.attr.st_nlink += 1;
-                inode.refcount += 1;
             }
         }
 
         let mut out = EntryOut::default();
-        out.ino(op.ino());
         fill_attr(out.attr(), &inode.attr);
         out.ttl_entry(self.ttl);
 
-        req.reply(out)
+        self.inodes.reply_entry(req, op.ino(), out)
     }
 
     fn do_unlink(&self, req: &Request, op: op::Unlink<'_>) -> io::Result<()> {
@@ -591,10 +410,11 @@ impl MemFS {
     }
 
     fn unlink_node(&self, req: &Request, parent: Ino, name: &OsStr) -> io::Result<()> {
-        let mut parent = match self.inodes.get_mut(parent) {
+        let parent = match self.inodes.get(parent) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut parent = parent.lock().unwrap();
         let parent = match parent.kind {
             INodeKind::Directory(ref mut dir) => dir,
             _ => return req.reply_error(libc::ENOTDIR),
@@ -605,7 +425,8 @@ impl MemFS {
             None => return req.reply_error(libc::ENOENT),
         };
 
-        let mut inode = self.inodes.get_mut(ino).unwrap_or_else(|| unreachable!());
+        let inode = self.inodes.get(ino).unwrap_or_else(|| unreachable!());
+        let mut inode = inode.lock().unwrap();
         match inode.kind {
             INodeKind::Directory(ref dir) if !dir.children.is_empty() => {
                 return req.reply_error(libc::ENOTEMPTY);
@@ -618,8 +439,8 @@ impl MemFS {
             .remove(name)
             .unwrap_or_else(|| unreachable!());
 
-        inode.links = inode.links.saturating_sub(1);
         inode.attr.st_nlink = inode.attr.st_nlink.saturating_sub(1);
+        self.inodes.unlink(ino);
 
         req.reply(())
     }
@@ -630,10 +451,11 @@ impl MemFS {
             return req.reply_error(libc::EINVAL);
         }
 
-        let mut parent = match self.inodes.get_mut(op.parent()) {
+        let parent = match self.inodes.get(op.parent()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut parent = parent.lock().unwrap();
         let parent = match parent.kind {
             INodeKind::Directory(ref mut dir) => dir,
             _ => return req.reply_error(libc::ENOTDIR),
@@ -659,10 +481,11 @@ impl MemFS {
             }
 
             newparent => {
-                let mut newparent = match self.inodes.get_mut(newparent) {
+                let newparent = match self.inodes.get(newparent) {
                     Some(inode) => inode,
                     None => return req.reply_error(libc::ENOENT),
                 };
+                let mut newparent = newparent.lock().unwrap();
                 let newparent = match newparent.kind {
                     INodeKind::Directory(ref mut dir) => dir,
                     _ => return req.reply_error(libc::ENOTDIR),
@@ -688,6 +511,7 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let inode = inode.lock().unwrap();
 
         let value = match inode.xattrs.get(op.name()) {
             Some(value) => value,
@@ -716,10 +540,11 @@ impl MemFS {
             return req.reply_error(libc::EINVAL);
         }
 
-        let mut inode = match self.inodes.get_mut(op.ino()) {
+        let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut inode = inode.lock().unwrap();
 
         match inode.xattrs.entry(op.name().into()) {
             Entry::Occupied(entry) => {
@@ -749,6 +574,7 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let inode = inode.lock().unwrap();
 
         match op.size() {
             0 => {
@@ -777,10 +603,11 @@ impl MemFS {
     }
 
     fn do_removexattr(&self, req: &Request, op: op::Removexattr<'_>) -> io::Result<()> {
-        let mut inode = match self.inodes.get_mut(op.ino()) {
+        let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut inode = inode.lock().unwrap();
 
         match inode.xattrs.entry(op.name().into()) {
             Entry::Occupied(entry) => {
@@ -797,6 +624,7 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let inode = inode.lock().unwrap();
 
         let content = match inode.kind {
             INodeKind::RegularFile(ref content) => content,
@@ -816,10 +644,11 @@ impl MemFS {
     where
         T: Deref<Target = [u8]>,
     {
-        let mut inode = match self.inodes.get_mut(op.ino()) {
+        let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        let mut inode = inode.lock().unwrap();
 
         let content = match inode.kind {
             INodeKind::RegularFile(ref mut content) => content,
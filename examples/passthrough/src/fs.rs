@@ -419,6 +419,12 @@ pub fn posix_fallocate(
     Ok(())
 }
 
+pub fn lseek(fd: &impl AsRawFd, offset: libc::off_t, whence: libc::c_int) -> io::Result<u64> {
+    let fd = fd.as_raw_fd();
+    let offset = syscall!(lseek(fd, offset, whence))?;
+    Ok(offset as u64)
+}
+
 pub fn getxattr(
     path: impl AsRef<OsStr>,
     name: impl AsRef<OsStr>,
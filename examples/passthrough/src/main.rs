@@ -6,9 +6,10 @@ mod fs;
 use polyfuse::{
     op,
     reply::{
-        AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, Statfs, StatfsOut, WriteOut, XattrOut,
+        AttrOut, EntryOut, FileAttr, LseekOut, OpenOut, ReaddirOut, Statfs, StatfsOut, WriteOut,
+        XattrOut,
     },
-    KernelConfig, Operation, Session,
+    KernelConfig, Operation, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -20,8 +21,7 @@ use std::{
     ffi::{OsStr, OsString},
     fmt::Debug,
     fs::{File, OpenOptions},
-    io::{self, prelude::*},
-    ops::Deref,
+    io,
     os::unix::prelude::*,
     path::PathBuf,
     sync::{Arc, Mutex},
@@ -46,24 +46,43 @@ fn main() -> Result<()> {
         Some(Duration::from_secs(60 * 60 * 24)) // one day
     };
 
+    // Route read/write requests directly to the source files (requires CAP_SYS_ADMIN).
+    let passthrough = args.contains("--passthrough");
+
+    // Transfer the file data between the kernel and the source files using splice(2).
+    let splice = args.contains("--splice");
+
+    // Transport the requests over io_uring if the kernel supports it.
+    let io_uring = args.contains("--io-uring");
+
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
-    // TODO: splice read/write
     let session = Session::mount(mountpoint, {
         let mut config = KernelConfig::default();
         config.mount_option("default_permissions");
         config.mount_option("fsname=passthrough");
         config.export_support(true);
         config.flock_locks(true);
-        config.writeback_cache(timeout.is_some());
+        config.writeback_cache(timeout.is_some() && !passthrough);
+        config.passthrough(passthrough);
+        config.io_uring(io_uring);
+        if splice {
+            // The whole request message must fit in a pipe.
+            config.max_write(1024 * 1024 - 4096);
+            config.splice_read(true);
+            config.splice_write(true);
+            config.splice_move(true);
+        }
         config
     })?;
+    let session = Arc::new(session);
 
     let fs = Arc::new(Passthrough::new(source, timeout)?);
 
     while let Some(req) = session.next_request()? {
         let fs = fs.clone();
+        let session = session.clone();
 
         std::thread::spawn(move || -> Result<()> {
             let span = tracing::debug_span!("handle_request", unique = req.unique());
@@ -133,14 +152,19 @@ fn main() -> Result<()> {
                 Operation::Fsyncdir(op) => try_reply!(fs.do_fsyncdir(&op)),
                 Operation::Releasedir(op) => try_reply!(fs.do_releasedir(&op)),
 
-                Operation::Open(op) => try_reply!(fs.do_open(&op)),
-                Operation::Read(op) => try_reply!(fs.do_read(&op)),
-                Operation::Write(op, data) => try_reply!(fs.do_write(&op, data)),
+                Operation::Open(op) => try_reply!(fs.do_open(&op, &session)),
+                Operation::Read(op) => {
+                    if let Err(err) = fs.do_read(&op, &req) {
+                        req.reply_error(io_to_errno(err))?;
+                    }
+                }
+                Operation::Write(op, _) => try_reply!(fs.do_write(&op, &req)),
                 Operation::Flush(op) => try_reply!(fs.do_flush(&op)),
                 Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
                 Operation::Flock(op) => try_reply!(fs.do_flock(&op)),
                 Operation::Fallocate(op) => try_reply!(fs.do_fallocate(&op)),
-                Operation::Release(op) => try_reply!(fs.do_release(&op)),
+                Operation::Lseek(op) => try_reply!(fs.do_lseek(&op)),
+                Operation::Release(op) => try_reply!(fs.do_release(&op, &session)),
 
                 Operation::Getxattr(op) => try_reply!(fs.do_getxattr(&op)),
                 Operation::Listxattr(op) => try_reply!(fs.do_listxattr(&op)),
@@ -166,6 +190,7 @@ struct Passthrough {
     inodes: Mutex<INodeTable>,
     opened_dirs: HandlePool<Mutex<ReadDir>>,
     opened_files: HandlePool<Mutex<File>>,
+    backing_ids: Mutex<HashMap<u64, i32>>,
     timeout: Option<Duration>,
 }
 
@@ -191,6 +216,7 @@ impl Passthrough {
             inodes: Mutex::new(inodes),
             opened_dirs: HandlePool::default(),
             opened_files: HandlePool::default(),
+            backing_ids: Mutex::default(),
             timeout,
         })
     }
@@ -542,7 +568,7 @@ impl Passthrough {
         Ok(())
     }
 
-    fn do_open(&self, op: &op::Open<'_>) -> io::Result<OpenOut> {
+    fn do_open(&self, op: &op::Open<'_>, session: &Session) -> io::Result<OpenOut> {
         let inodes = self.inodes.lock().unwrap();
         let inode = inodes.get(op.ino()).ok_or_else(no_entry)?;
         let inode = inode.lock().unwrap();
@@ -563,38 +589,35 @@ impl Passthrough {
         options.custom_flags(op.flags() as i32 & !libc::O_NOFOLLOW);
 
         let file = options.open(&inode.fd.procname())?;
+        let backing_id = if session.passthrough() {
+            Some(session.register_backing_fd(&file)?)
+        } else {
+            None
+        };
         let fh = self.opened_files.insert(Mutex::new(file));
 
         let mut out = OpenOut::default();
         out.fh(fh);
+        if let Some(backing_id) = backing_id {
+            self.backing_ids.lock().unwrap().insert(fh, backing_id);
+            out.backing_id(backing_id);
+        }
 
         Ok(out)
     }
 
-    fn do_read(&self, op: &op::Read<'_>) -> io::Result<Vec<u8>> {
+    fn do_read(&self, op: &op::Read<'_>, req: &Request) -> io::Result<()> {
         let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
-        let mut file = file.lock().unwrap();
-        let file = &mut *file;
-
-        file.seek(io::SeekFrom::Start(op.offset()))?;
-
-        let mut buf = Vec::<u8>::with_capacity(op.size() as usize);
-        file.take(op.size() as u64).read_to_end(&mut buf)?;
+        let file = file.lock().unwrap();
 
-        Ok(buf)
+        req.reply_splice(&*file, op.offset(), op.size() as usize)
     }
 
-    fn do_write<T>(&self, op: &op::Write<'_>, data: T) -> io::Result<WriteOut>
-    where
-        T: Deref<Target = [u8]>,
-    {
+    fn do_write(&self, op: &op::Write<'_>, req: &Request) -> io::Result<WriteOut> {
         let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
-        let mut file = file.lock().unwrap();
-        let file = &mut *file;
+        let file = file.lock().unwrap();
 
-        let nwritten = std::cmp::min(data.len(), op.size() as usize);
-        file.seek(io::SeekFrom::Start(op.offset()))?;
-        file.write_all(&data[..nwritten])?;
+        let nwritten = req.write_data_to(&*file, op.offset())?;
         let mut out = WriteOut::default();
         out.size(nwritten as u32);
 
@@ -647,8 +670,29 @@ impl Passthrough {
         Ok(())
     }
 
-    fn do_release(&self, op: &op::Release<'_>) -> io::Result<()> {
+    fn do_lseek(&self, op: &op::Lseek<'_>) -> io::Result<LseekOut> {
+        let whence = match op.whence() {
+            Some(op::Whence::Data) => libc::SEEK_DATA,
+            Some(op::Whence::Hole) => libc::SEEK_HOLE,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+        let file = file.lock().unwrap();
+
+        let offset = fs::lseek(&*file, op.offset() as libc::off_t, whence)?;
+
+        let mut out = LseekOut::default();
+        out.offset(offset);
+
+        Ok(out)
+    }
+
+    fn do_release(&self, op: &op::Release<'_>, session: &Session) -> io::Result<()> {
         let _file = self.opened_files.remove(op.fh());
+        if let Some(backing_id) = self.backing_ids.lock().unwrap().remove(&op.fh()) {
+            session.unregister_backing_fd(backing_id)?;
+        }
         Ok(())
     }
 
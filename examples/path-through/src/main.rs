@@ -8,25 +8,27 @@
 // *standard* filesystem APIs, but also the additional path resolution
 // cost for each operation.
 //
-// This example is inteded to be used as a templete for implementing
-// the path based filesystems such as libfuse's highlevel API.
+// The translation from the inode numbers into the paths is done by
+// `polyfuse::path::PathFs`, which plays the role of libfuse's highlevel API.
 
 use polyfuse::{
-    op::{self, Forget},
-    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, WriteOut},
-    KernelConfig, Operation, Session,
+    fs, op,
+    path::{PathFilesystem, PathFs},
+    reply::{FileAttr, OpenOut, ReaddirOut, WriteOut},
+    Errno, KernelConfig, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
+use bytes::Bytes;
 use slab::Slab;
 use std::{
-    collections::hash_map::{Entry, HashMap},
     ffi::OsString,
-    fs::{self, File, Metadata, OpenOptions, ReadDir},
+    fs::{File, Metadata, OpenOptions, ReadDir},
     io::{self, prelude::*},
     ops::Deref,
     os::unix::prelude::*,
     path::{Path, PathBuf},
+    sync::Mutex,
     time::Duration,
 };
 
@@ -45,201 +47,46 @@ fn main() -> Result<()> {
 
     let session = Session::mount(mountpoint, KernelConfig::default())?;
 
-    let mut fs = PathThrough::new(source)?;
-
-    while let Some(req) = session.next_request()? {
-        let op = req.operation()?;
-        tracing::debug!("handle operation: {:#?}", op);
-
-        macro_rules! try_reply {
-            ($e:expr) => {
-                match $e {
-                    Ok(data) => req.reply(data)?,
-                    Err(err) => req.reply_error(err.raw_os_error().unwrap_or(libc::EIO))?,
-                }
-            };
-        }
-
-        match op {
-            Operation::Lookup(op) => try_reply!(fs.do_lookup(&op)),
-            Operation::Forget(forgets) => {
-                fs.do_forget(forgets.as_ref());
-            }
-            Operation::Getattr(op) => try_reply!(fs.do_getattr(&op)),
-            Operation::Setattr(op) => try_reply!(fs.do_setattr(&op)),
-            Operation::Readlink(op) => try_reply!(fs.do_readlink(&op)),
-            Operation::Opendir(op) => try_reply!(fs.do_opendir(&op)),
-            Operation::Readdir(op) => try_reply!(fs.do_readdir(&op)),
-            Operation::Releasedir(op) => try_reply!(fs.do_releasedir(&op)),
-            Operation::Open(op) => try_reply!(fs.do_open(&op)),
-            Operation::Read(op) => try_reply!(fs.do_read(&op)),
-            Operation::Write(op, data) => try_reply!(fs.do_write(&op, data)),
-            Operation::Flush(op) => try_reply!(fs.do_flush(&op)),
-            Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
-            Operation::Release(op) => try_reply!(fs.do_release(&op)),
-
-            _ => req.reply_error(libc::ENOSYS)?,
-        }
-    }
+    let fs = PathFs::new(PathThrough::new(source)?);
+    fs::serve(&session, &fs)?;
 
     Ok(())
 }
 
 type Ino = u64;
 
-struct INode {
-    ino: Ino,
-    path: PathBuf,
-    refcount: u64,
-}
-
-struct INodeTable {
-    map: HashMap<Ino, INode>,
-    path_to_ino: HashMap<PathBuf, Ino>,
-    next_ino: u64,
-}
-
-impl INodeTable {
-    fn new() -> Self {
-        INodeTable {
-            map: HashMap::new(),
-            path_to_ino: HashMap::new(),
-            next_ino: 1, // the inode number is started with 1 and the first node is root.
-        }
-    }
-
-    fn vacant_entry(&mut self) -> VacantEntry<'_> {
-        let ino = self.next_ino;
-        VacantEntry { table: self, ino }
-    }
-
-    fn get(&self, ino: Ino) -> Option<&INode> {
-        self.map.get(&ino)
-    }
-
-    fn get_by_path_mut(&mut self, path: &Path) -> Option<&mut INode> {
-        let ino = self.path_to_ino.get(path).copied()?;
-        self.map.get_mut(&ino)
-    }
-}
-
-struct VacantEntry<'a> {
-    table: &'a mut INodeTable,
-    ino: Ino,
-}
-
-impl VacantEntry<'_> {
-    fn insert(mut self, inode: INode) {
-        let path = inode.path.clone();
-        self.table.map.insert(self.ino, inode);
-        self.table.path_to_ino.insert(path, self.ino);
-        self.table.next_ino += 1;
-    }
-}
-
 struct PathThrough {
     source: PathBuf,
-    inodes: INodeTable,
-    dirs: Slab<DirHandle>,
-    files: Slab<FileHandle>,
+    dirs: Mutex<Slab<DirHandle>>,
+    files: Mutex<Slab<FileHandle>>,
 }
 
 impl PathThrough {
     fn new(source: PathBuf) -> io::Result<Self> {
         let source = source.canonicalize()?;
-
-        let mut inodes = INodeTable::new();
-        inodes.vacant_entry().insert(INode {
-            ino: 1,
-            path: PathBuf::new(),
-            refcount: u64::max_value() / 2,
-        });
-
         Ok(Self {
             source,
-            inodes,
-            dirs: Slab::new(),
-            files: Slab::new(),
+            dirs: Mutex::default(),
+            files: Mutex::default(),
         })
     }
 
-    fn do_lookup(&mut self, op: &op::Lookup<'_>) -> io::Result<EntryOut> {
-        let parent = self.inodes.get(op.parent()).ok_or_else(no_entry)?;
-        let path = parent.path.join(op.name());
-
-        let metadata = fs::symlink_metadata(self.source.join(&path))?;
-
-        let mut out = EntryOut::default();
-        fill_attr(&metadata, out.attr());
-
-        match self.inodes.get_by_path_mut(&path) {
-            Some(inode) => {
-                out.ino(inode.ino);
-                inode.refcount += 1;
-            }
-            None => {
-                let entry = self.inodes.vacant_entry();
-                out.ino(entry.ino);
-                let inode = INode {
-                    ino: entry.ino,
-                    path,
-                    refcount: 1,
-                };
-                entry.insert(inode);
-            }
-        }
-
-        Ok(out)
-    }
-
-    fn do_forget(&mut self, forgets: &[Forget]) {
-        for forget in forgets {
-            if let Entry::Occupied(mut entry) = self.inodes.map.entry(forget.ino()) {
-                let refcount = {
-                    let mut inode = entry.get_mut();
-                    inode.refcount = inode.refcount.saturating_sub(forget.nlookup());
-                    inode.refcount
-                };
-
-                if refcount == 0 {
-                    tracing::debug!("remove ino={}", entry.key());
-                    drop(entry.remove());
-                }
-            }
-        }
-    }
-
-    fn do_getattr(&mut self, op: &op::Getattr<'_>) -> io::Result<AttrOut> {
-        let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
-        let metadata = fs::symlink_metadata(self.source.join(&inode.path))?;
-
-        let mut out = AttrOut::default();
-        fill_attr(&metadata, out.attr());
-
-        Ok(out)
-    }
-
-    fn do_setattr(&mut self, op: &op::Setattr<'_>) -> io::Result<AttrOut> {
-        let fh = op.fh().ok_or_else(no_entry)?;
-        let file = self
-            .files
-            .get(fh as usize)
-            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
-
-        file.file.sync_all()?;
-
-        let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
-        let path = self.source.join(&inode.path);
+    fn do_setattr(&self, path: &Path, op: &op::Setattr<'_>) -> io::Result<()> {
+        let path = self.source.join(path);
 
         // chmod
         if let Some(mode) = op.mode() {
             let perm = std::fs::Permissions::from_mode(mode);
-            file.file.set_permissions(perm)?;
+            std::fs::set_permissions(&path, perm)?;
         }
 
         // truncate
         if let Some(size) = op.size() {
-            file.file.set_len(size)?;
+            let files = self.files.lock().unwrap();
+            match op.fh().and_then(|fh| files.get(fh as usize)) {
+                Some(file) => file.file.set_len(size)?,
+                None => OpenOptions::new().write(true).open(&path)?.set_len(size)?,
+            }
         }
 
         // chown
@@ -254,41 +101,16 @@ impl PathThrough {
 
         // TODO: utimes
 
-        let metadata = fs::symlink_metadata(self.source.join(&inode.path))?;
-
-        let mut out = AttrOut::default();
-        fill_attr(&metadata, out.attr());
-
-        Ok(out)
-    }
-
-    fn do_readlink(&mut self, op: &op::Readlink<'_>) -> io::Result<OsString> {
-        let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
-        let path = fs::read_link(self.source.join(&inode.path))?;
-        Ok(path.into_os_string())
-    }
-
-    fn do_opendir(&mut self, op: &op::Opendir<'_>) -> io::Result<OpenOut> {
-        let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
-
-        let fh = self.dirs.insert(DirHandle {
-            read_dir: fs::read_dir(self.source.join(&inode.path))?,
-            last_entry: None,
-            offset: 1,
-        }) as u64;
-
-        let mut out = OpenOut::default();
-        out.fh(fh);
-
-        Ok(out)
+        Ok(())
     }
 
-    fn do_readdir(&mut self, op: &op::Readdir<'_>) -> io::Result<ReaddirOut> {
+    fn do_readdir(&self, op: &op::Readdir<'_>) -> io::Result<ReaddirOut> {
         if op.mode() == op::ReaddirMode::Plus {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        let dir = Slab::get_mut(&mut self.dirs, op.fh() as usize).ok_or_else(invalid_handle)?;
+        let mut dirs = self.dirs.lock().unwrap();
+        let dir = dirs.get_mut(op.fh() as usize).ok_or_else(invalid_handle)?;
 
         let mut out = ReaddirOut::new(op.size() as usize);
         let mut at_least_one_entry = false;
@@ -342,14 +164,7 @@ impl PathThrough {
         Ok(out)
     }
 
-    fn do_releasedir(&mut self, op: &op::Releasedir<'_>) -> io::Result<()> {
-        let _dir = self.dirs.remove(op.fh() as usize);
-        Ok(())
-    }
-
-    fn do_open(&mut self, op: &op::Open<'_>) -> io::Result<OpenOut> {
-        let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
-
+    fn do_open(&self, path: &Path, op: &op::Open<'_>) -> io::Result<OpenOut> {
         let mut options = OpenOptions::new();
         match op.flags() as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => {
@@ -365,9 +180,8 @@ impl PathThrough {
         }
         options.custom_flags(op.flags() as i32 & !libc::O_NOFOLLOW);
 
-        let fh = self.files.insert(FileHandle {
-            file: options.open(self.source.join(&inode.path))?,
-        }) as u64;
+        let file = options.open(self.source.join(path))?;
+        let fh = self.files.lock().unwrap().insert(FileHandle { file }) as u64;
 
         let mut out = OpenOut::default();
         out.fh(fh);
@@ -375,40 +189,114 @@ impl PathThrough {
         Ok(out)
     }
 
-    fn do_read(&mut self, op: &op::Read<'_>) -> io::Result<Vec<u8>> {
-        let file = Slab::get_mut(&mut self.files, op.fh() as usize).ok_or_else(invalid_handle)?;
-        let buf = file.read(op.offset(), op.size() as usize)?;
-        Ok(buf)
+    fn with_file<R>(
+        &self,
+        fh: u64,
+        f: impl FnOnce(&mut FileHandle) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.get_mut(fh as usize).ok_or_else(invalid_handle)?;
+        f(file)
     }
+}
 
-    fn do_write<T>(&mut self, op: &op::Write<'_>, data: T) -> io::Result<WriteOut>
-    where
-        T: Deref<Target = [u8]>,
-    {
-        let file = Slab::get_mut(&mut self.files, op.fh() as usize).ok_or_else(invalid_handle)?;
-        let nwritten = std::cmp::min(data.len(), op.size() as usize);
-        file.write_all(&data[..nwritten], op.offset())?;
-
-        let mut out = WriteOut::default();
-        out.size(nwritten as u32);
-        Ok(out)
+impl PathFilesystem for PathThrough {
+    fn getattr(
+        &self,
+        _: &Request,
+        path: &Path,
+        _: Option<u64>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        let metadata = std::fs::symlink_metadata(self.source.join(path))?;
+        fill_attr(&metadata, attr);
+        Ok(())
     }
 
-    fn do_flush(&mut self, op: &op::Flush<'_>) -> io::Result<()> {
-        let file = Slab::get_mut(&mut self.files, op.fh() as usize).ok_or_else(invalid_handle)?;
-        file.fsync(false)?;
+    fn setattr(
+        &self,
+        _: &Request,
+        path: &Path,
+        op: op::Setattr<'_>,
+        attr: &mut FileAttr,
+    ) -> io::Result<()> {
+        self.do_setattr(path, &op)?;
+        let metadata = std::fs::symlink_metadata(self.source.join(path))?;
+        fill_attr(&metadata, attr);
         Ok(())
     }
 
-    fn do_fsync(&mut self, op: &op::Fsync<'_>) -> io::Result<()> {
-        let file = Slab::get_mut(&mut self.files, op.fh() as usize).ok_or_else(invalid_handle)?;
-        file.fsync(op.datasync())?;
-        Ok(())
+    fn readlink(&self, req: &Request, path: &Path, _: op::Readlink<'_>) -> io::Result<()> {
+        let res = std::fs::read_link(self.source.join(path));
+        req.reply_result(res.map(PathBuf::into_os_string).map_err(Errno::from))
     }
 
-    fn do_release(&mut self, op: &op::Release<'_>) -> io::Result<()> {
-        let _file = self.files.remove(op.fh() as usize);
-        Ok(())
+    fn opendir(&self, req: &Request, path: &Path, _: op::Opendir<'_>) -> io::Result<()> {
+        let read_dir = match std::fs::read_dir(self.source.join(path)) {
+            Ok(read_dir) => read_dir,
+            Err(err) => return req.reply_error(Errno::from(err).raw()),
+        };
+        let fh = self.dirs.lock().unwrap().insert(DirHandle {
+            read_dir,
+            last_entry: None,
+            offset: 1,
+        }) as u64;
+
+        let mut out = OpenOut::default();
+        out.fh(fh);
+        req.reply(out)
+    }
+
+    fn readdir(&self, req: &Request, _: &Path, op: op::Readdir<'_>) -> io::Result<()> {
+        req.reply_result(self.do_readdir(&op).map_err(Errno::from))
+    }
+
+    fn releasedir(
+        &self,
+        req: &Request,
+        _: Option<&Path>,
+        op: op::Releasedir<'_>,
+    ) -> io::Result<()> {
+        let _dir = self.dirs.lock().unwrap().try_remove(op.fh() as usize);
+        req.reply(())
+    }
+
+    fn open(&self, req: &Request, path: &Path, op: op::Open<'_>) -> io::Result<()> {
+        req.reply_result(self.do_open(path, &op).map_err(Errno::from))
+    }
+
+    fn read(&self, req: &Request, _: &Path, op: op::Read<'_>) -> io::Result<()> {
+        let res = self.with_file(op.fh(), |file| file.read(op.offset(), op.size() as usize));
+        req.reply_result(res.map_err(Errno::from))
+    }
+
+    fn write(&self, req: &Request, _: &Path, op: op::Write<'_>, data: Bytes) -> io::Result<()> {
+        let res = self.with_file(op.fh(), |file| {
+            let nwritten = std::cmp::min(data.len(), op.size() as usize);
+            file.write_all(&data[..nwritten], op.offset())?;
+            Ok(nwritten)
+        });
+        let res = res.map(|nwritten| {
+            let mut out = WriteOut::default();
+            out.size(nwritten as u32);
+            out
+        });
+        req.reply_result(res.map_err(Errno::from))
+    }
+
+    fn flush(&self, req: &Request, _: Option<&Path>, op: op::Flush<'_>) -> io::Result<()> {
+        let res = self.with_file(op.fh(), |file| file.fsync(false));
+        req.reply_result(res.map_err(Errno::from))
+    }
+
+    fn fsync(&self, req: &Request, _: &Path, op: op::Fsync<'_>) -> io::Result<()> {
+        let res = self.with_file(op.fh(), |file| file.fsync(op.datasync()));
+        req.reply_result(res.map_err(Errno::from))
+    }
+
+    fn release(&self, req: &Request, _: Option<&Path>, op: op::Release<'_>) -> io::Result<()> {
+        let _file = self.files.lock().unwrap().try_remove(op.fh() as usize);
+        req.reply(())
     }
 }
 
@@ -487,11 +375,6 @@ fn fill_attr(metadata: &Metadata, attr: &mut FileAttr) {
 
 // ==== utils ====
 
-#[inline]
-fn no_entry() -> io::Error {
-    io::Error::from_raw_os_error(libc::ENOENT)
-}
-
 #[inline]
 fn invalid_handle() -> io::Error {
     io::Error::from_raw_os_error(libc::EINVAL)
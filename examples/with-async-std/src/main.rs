@@ -2,9 +2,10 @@
 #![deny(clippy::unimplemented, clippy::todo)]
 
 use polyfuse::{
+    async_std::{AsyncRequest, AsyncSession},
     op,
     reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
-    KernelConfig, Operation, Request, Session,
+    KernelConfig, Operation,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -39,7 +40,7 @@ async fn main() -> Result<()> {
                 Operation::Getattr(op) => fs.getattr(&req, op).await?,
                 Operation::Read(op) => fs.read(&req, op).await?,
                 Operation::Readdir(op) => fs.readdir(&req, op).await?,
-                _ => req.reply_error(libc::ENOSYS)?,
+                _ => req.reply_error(libc::ENOSYS).await?,
             }
 
             Ok(())
@@ -104,7 +105,7 @@ impl Hello {
         attr.gid(self.gid);
     }
 
-    async fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+    async fn lookup(&self, req: &AsyncRequest, op: op::Lookup<'_>) -> io::Result<()> {
         match op.parent() {
             ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
                 let mut out = EntryOut::default();
@@ -112,30 +113,30 @@ impl Hello {
                 out.ino(HELLO_INO);
                 out.ttl_attr(TTL);
                 out.ttl_entry(TTL);
-                req.reply(out)
+                req.reply(out).await
             }
-            _ => req.reply_error(libc::ENOENT),
+            _ => req.reply_error(libc::ENOENT).await,
         }
     }
 
-    async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+    async fn getattr(&self, req: &AsyncRequest, op: op::Getattr<'_>) -> io::Result<()> {
         let mut out = AttrOut::default();
         match op.ino() {
             ROOT_INO => self.fill_root_attr(out.attr()),
             HELLO_INO => self.fill_hello_attr(out.attr()),
-            _ => return req.reply_error(libc::ENOENT),
+            _ => return req.reply_error(libc::ENOENT).await,
         };
 
         out.ttl(TTL);
 
-        req.reply(out)
+        req.reply(out).await
     }
 
-    async fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+    async fn read(&self, req: &AsyncRequest, op: op::Read<'_>) -> io::Result<()> {
         match op.ino() {
             HELLO_INO => (),
-            ROOT_INO => return req.reply_error(libc::EISDIR),
-            _ => return req.reply_error(libc::ENOENT),
+            ROOT_INO => return req.reply_error(libc::EISDIR).await,
+            _ => return req.reply_error(libc::ENOENT).await,
         }
 
         let mut data: &[u8] = &[];
@@ -147,7 +148,7 @@ impl Hello {
             data = &data[..std::cmp::min(data.len(), size)];
         }
 
-        req.reply(data)
+        req.reply(data).await
     }
 
     fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
@@ -157,9 +158,9 @@ impl Hello {
         })
     }
 
-    async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+    async fn readdir(&self, req: &AsyncRequest, op: op::Readdir<'_>) -> io::Result<()> {
         if op.ino() != ROOT_INO {
-            return req.reply_error(libc::ENOTDIR);
+            return req.reply_error(libc::ENOTDIR).await;
         }
 
         let mut out = ReaddirOut::new(op.size() as usize);
@@ -176,39 +177,6 @@ impl Hello {
             }
         }
 
-        req.reply(out)
-    }
-}
-
-// ==== AsyncSession ====
-
-struct AsyncSession {
-    inner: async_io::Async<Session>,
-}
-
-impl AsyncSession {
-    async fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
-        async_std::task::spawn_blocking(move || {
-            let session = Session::mount(mountpoint, config)?;
-            Ok(Self {
-                inner: async_io::Async::new(session)?,
-            })
-        })
-        .await
-    }
-
-    async fn next_request(&self) -> io::Result<Option<Request>> {
-        use futures::{future::poll_fn, ready, task::Poll};
-
-        poll_fn(|cx| loop {
-            match self.inner.get_ref().next_request() {
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    ready!(self.inner.poll_readable(cx))?;
-                    continue;
-                }
-                res => return Poll::Ready(res),
-            }
-        })
-        .await
+        req.reply(out).await
     }
 }
@@ -2,17 +2,15 @@
 #![deny(clippy::unimplemented, clippy::todo)]
 
 use polyfuse::{
+    fs::{AsyncFilesystem, AsyncServer, HandlerFuture},
     op,
     reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
-    KernelConfig, Operation, Request, Session,
+    KernelConfig, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
-use std::{io, os::unix::prelude::*, path::PathBuf, sync::Arc, time::Duration};
-use tokio::{
-    io::{unix::AsyncFd, Interest},
-    task::{self, JoinHandle},
-};
+use std::{os::unix::prelude::*, path::PathBuf, sync::Arc, time::Duration};
+use tokio::task;
 
 const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
 const ROOT_INO: u64 = 1;
@@ -29,27 +27,22 @@ async fn main() -> Result<()> {
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
-    let session = AsyncSession::mount(mountpoint, KernelConfig::default()).await?;
-
     let fs = Arc::new(Hello::new());
 
-    while let Some(req) = session.next_request().await? {
-        let fs = fs.clone();
+    // The requests are received on a blocking thread, and each of them
+    // is handled in its own task.
+    task::spawn_blocking(move || -> Result<()> {
+        let session = Session::mount(mountpoint, KernelConfig::default())?;
 
-        let _: JoinHandle<Result<()>> = task::spawn(async move {
-            match req.operation()? {
-                Operation::Lookup(op) => fs.lookup(&req, op).await?,
-                Operation::Getattr(op) => fs.getattr(&req, op).await?,
-                Operation::Read(op) => fs.read(&req, op).await?,
-                Operation::Readdir(op) => fs.readdir(&req, op).await?,
-                _ => req.reply_error(libc::ENOSYS)?,
-            }
-
-            Ok(())
+        let mut server = AsyncServer::new(|task| {
+            tokio::spawn(task);
         });
-    }
+        server.max_concurrency(64);
+        server.serve(&session, fs)?;
 
-    Ok(())
+        Ok(())
+    })
+    .await?
 }
 
 struct Hello {
@@ -107,117 +100,89 @@ impl Hello {
         attr.gid(self.gid);
     }
 
-    async fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
-        match op.parent() {
-            ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
-                let mut out = EntryOut::default();
-                self.fill_hello_attr(out.attr());
-                out.ino(HELLO_INO);
-                out.ttl_attr(TTL);
-                out.ttl_entry(TTL);
-                req.reply(out)
-            }
-            _ => req.reply_error(libc::ENOENT),
-        }
-    }
-
-    async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
-        let fill_attr = match op.ino() {
-            ROOT_INO => Self::fill_root_attr,
-            HELLO_INO => Self::fill_hello_attr,
-            _ => return req.reply_error(libc::ENOENT),
-        };
-
-        let mut out = AttrOut::default();
-        fill_attr(self, out.attr());
-        out.ttl(TTL);
-
-        req.reply(out)
-    }
-
-    async fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
-        match op.ino() {
-            HELLO_INO => (),
-            ROOT_INO => return req.reply_error(libc::EISDIR),
-            _ => return req.reply_error(libc::ENOENT),
-        }
-
-        let mut data: &[u8] = &[];
-
-        let offset = op.offset() as usize;
-        if offset < HELLO_CONTENT.len() {
-            let size = op.size() as usize;
-            data = &HELLO_CONTENT[offset..];
-            data = &data[..std::cmp::min(data.len(), size)];
-        }
-
-        req.reply(data)
-    }
-
     fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
         self.entries.iter().enumerate().map(|(i, ent)| {
             let offset = (i + 1) as u64;
             (offset, ent)
         })
     }
+}
 
-    async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
-        if op.ino() != ROOT_INO {
-            return req.reply_error(libc::ENOTDIR);
-        }
-
-        let mut out = ReaddirOut::new(op.size() as usize);
-
-        for (i, entry) in self.dir_entries().skip(op.offset() as usize) {
-            let full = out.entry(
-                entry.name.as_ref(), //
-                entry.ino,
-                entry.typ,
-                i + 1,
-            );
-            if full {
-                break;
+impl AsyncFilesystem for Hello {
+    fn lookup<'a>(&'a self, req: &'a Request, op: op::Lookup<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            match op.parent() {
+                ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
+                    let mut out = EntryOut::default();
+                    self.fill_hello_attr(out.attr());
+                    out.ino(HELLO_INO);
+                    out.ttl_attr(TTL);
+                    out.ttl_entry(TTL);
+                    req.reply(out)
+                }
+                _ => req.reply_error(libc::ENOENT),
             }
-        }
+        })
+    }
+
+    fn getattr<'a>(&'a self, req: &'a Request, op: op::Getattr<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let fill_attr = match op.ino() {
+                ROOT_INO => Self::fill_root_attr,
+                HELLO_INO => Self::fill_hello_attr,
+                _ => return req.reply_error(libc::ENOENT),
+            };
+
+            let mut out = AttrOut::default();
+            fill_attr(self, out.attr());
+            out.ttl(TTL);
 
-        req.reply(out)
+            req.reply(out)
+        })
     }
-}
 
-// ==== AsyncSession ====
+    fn read<'a>(&'a self, req: &'a Request, op: op::Read<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            match op.ino() {
+                HELLO_INO => (),
+                ROOT_INO => return req.reply_error(libc::EISDIR),
+                _ => return req.reply_error(libc::ENOENT),
+            }
 
-struct AsyncSession {
-    inner: AsyncFd<Session>,
-}
+            let mut data: &[u8] = &[];
 
-impl AsyncSession {
-    async fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
-        tokio::task::spawn_blocking(move || {
-            let session = Session::mount(mountpoint, config)?;
-            Ok(Self {
-                inner: AsyncFd::with_interest(session, Interest::READABLE)?,
-            })
+            let offset = op.offset() as usize;
+            if offset < HELLO_CONTENT.len() {
+                let size = op.size() as usize;
+                data = &HELLO_CONTENT[offset..];
+                data = &data[..std::cmp::min(data.len(), size)];
+            }
+
+            req.reply(data)
         })
-        .await
-        .expect("join error")
     }
 
-    async fn next_request(&self) -> io::Result<Option<Request>> {
-        use futures::{future::poll_fn, ready, task::Poll};
+    fn readdir<'a>(&'a self, req: &'a Request, op: op::Readdir<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            if op.ino() != ROOT_INO {
+                return req.reply_error(libc::ENOTDIR);
+            }
 
-        poll_fn(|cx| {
-            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
-            match self.inner.get_ref().next_request() {
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    guard.clear_ready();
-                    Poll::Pending
-                }
-                res => {
-                    guard.retain_ready();
-                    Poll::Ready(res)
+            let mut out = ReaddirOut::new(op.size() as usize);
+
+            for (i, entry) in self.dir_entries().skip(op.offset() as usize) {
+                let full = out.entry(
+                    entry.name.as_ref(), //
+                    entry.ino,
+                    entry.typ,
+                    i + 1,
+                );
+                if full {
+                    break;
                 }
             }
+
+            req.reply(out)
         })
-        .await
     }
 }